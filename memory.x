@@ -1,6 +1,8 @@
-/* Linker script for the STM32F103CxTx */
+/* Linker script for the STM32F103CBTx (128K flash, the C8 has 64K, which the
+   firmware does not fit into) */
+/* the last 17K of flash is reserved for FIDO2_STORAGE_OFFSET (see src/consts.rs) */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  FLASH : ORIGIN = 0x08000000, LENGTH = 111K
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
@@ -0,0 +1,261 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use embedded_hal::digital::v2::OutputPin;
+use stm32f1xx_hal::{
+    afio::MAPR,
+    gpio::{Alternate, Cr, Floating, Input, Pin, PullUp, PushPull},
+    pac,
+    prelude::*,
+    rcc::Clocks,
+    serial, usb,
+};
+#[cfg(any(
+    all(feature = "board-bluepill", feature = "board-unsafekey-v1"),
+    not(any(feature = "board-bluepill", feature = "board-unsafekey-v1"))
+))]
+compile_error!("select exactly one board feature: board-bluepill or board-unsafekey-v1");
+
+use crate::fido2_button::{FIDO2Button, FIDO2ButtonEvent, FIDO2PresenceInput};
+use crate::fido2_log::FIDO2LogSink;
+#[cfg(feature = "touch")]
+use crate::{consts::FIDO2_TOUCH_SAMPLE_INTERVAL_MS, fido2_touch::FIDO2TouchSensor};
+#[cfg(feature = "touch")]
+use stm32f1xx_hal::{gpio::Dynamic, timer::Timer};
+
+#[cfg(feature = "board-bluepill")]
+pub(crate) type CurrentBoard = crate::board_bluepill::FIDO2BluePill;
+#[cfg(feature = "board-unsafekey-v1")]
+pub(crate) type CurrentBoard = crate::board_unsafekey_v1::FIDO2UnsafeKeyV1;
+
+// Trait
+
+// everything that differs between PCBs: clock tree, LED, presence input,
+// debug UART and how the host is made to enumerate the device again
+pub(crate) trait FIDO2Board: Sized {
+    // USB product string
+    const NAME: &'static str;
+    type LedPin: OutputPin;
+    type Presence: FIDO2PresenceInput;
+    fn init(p: FIDO2BoardPeripherals, acr: &mut stm32f1xx_hal::flash::ACR) -> FIDO2BoardParts<Self>;
+}
+
+// peripherals handed to the board, the rest stays with main()
+pub(crate) struct FIDO2BoardPeripherals {
+    pub rcc: pac::RCC,
+    pub afio: pac::AFIO,
+    pub gpioa: pac::GPIOA,
+    pub gpiob: pac::GPIOB,
+    pub gpioc: pac::GPIOC,
+    pub usart1: pac::USART1,
+    pub usb: pac::USB,
+    pub tim3: pac::TIM3,
+}
+
+pub(crate) struct FIDO2BoardParts<B: FIDO2Board> {
+    pub clocks: Clocks,
+    pub led: FIDO2Led<B::LedPin>,
+    pub presence: B::Presence,
+    pub debug_tx: serial::Tx<pac::USART1>,
+    pub debug_rx: serial::Rx<pac::USART1>,
+    // ready for UsbBus::new, the host has already seen the disconnect
+    pub usb: usb::Peripheral,
+}
+
+// LED
+
+pub(crate) struct FIDO2Led<P: OutputPin> {
+    pin: P,
+    active_low: bool,
+}
+impl<P: OutputPin> FIDO2Led<P> {
+    pub fn new(pin: P, active_low: bool) -> FIDO2Led<P> {
+        let mut led = FIDO2Led { pin, active_low };
+        led.off();
+        led
+    }
+    pub fn on(&mut self) {
+        self.set(true);
+    }
+    pub fn off(&mut self) {
+        self.set(false);
+    }
+    fn set(&mut self, on: bool) {
+        if on == self.active_low {
+            self.pin.set_low().ok();
+        } else {
+            self.pin.set_high().ok();
+        }
+    }
+}
+
+// Debug UART
+
+// USART1 on PA9 (TX) and PA10 (RX), 115200 8N1
+pub(crate) fn debug_serial(
+    usart: pac::USART1,
+    tx: Pin<'A', 9>,
+    rx: Pin<'A', 10>,
+    cr: &mut Cr<'A', true>,
+    mapr: &mut MAPR,
+    clocks: &Clocks,
+) -> (serial::Tx<pac::USART1>, serial::Rx<pac::USART1>) {
+    let tx: Pin<'A', 9, Alternate<PushPull>> = tx.into_alternate_push_pull(cr);
+    serial::Serial::new(
+        usart,
+        (tx, rx),
+        mapr,
+        serial::Config::default().baudrate(115200.bps()),
+        clocks,
+    )
+    .split()
+}
+
+// the debug UART as the log sink, flush waits for the last stop bit
+impl FIDO2LogSink for serial::Tx<pac::USART1> {
+    fn flush(&mut self) {
+        nb::block!(embedded_hal::serial::Write::<u8>::flush(self)).ok();
+    }
+}
+
+// USB
+
+// D+ has a fixed pull-up to 3.3V, driving it low for `cycles` makes the host
+// drop the device so it enumerates again after a reset
+pub(crate) fn usb_pull_dp_low(
+    usb: pac::USB,
+    dm: Pin<'A', 11>,
+    dp: Pin<'A', 12>,
+    cr: &mut Cr<'A', true>,
+    cycles: u32,
+) -> usb::Peripheral {
+    let mut dp = dp.into_push_pull_output(cr);
+    dp.set_low();
+    cortex_m::asm::delay(cycles);
+    let dp: Pin<'A', 12, Input<Floating>> = dp.into_floating_input(cr);
+    usb::Peripheral {
+        usb,
+        pin_dm: dm,
+        pin_dp: dp,
+    }
+}
+
+// Button
+
+// between PA0 and ground
+pub(crate) type ButtonPin = Pin<'A', 0, Input<PullUp>>;
+pub(crate) const BUTTON_ACTIVE_LOW: bool = true;
+
+pub(crate) struct FIDO2BoardButton {
+    pin: ButtonPin,
+    button: FIDO2Button,
+}
+impl FIDO2BoardButton {
+    pub fn new(pin: Pin<'A', 0>, cr: &mut Cr<'A', false>) -> FIDO2BoardButton {
+        FIDO2BoardButton {
+            pin: pin.into_pull_up_input(cr),
+            button: FIDO2Button::new(),
+        }
+    }
+}
+impl FIDO2PresenceInput for FIDO2BoardButton {
+    fn poll(&mut self, now: u64) -> Option<FIDO2ButtonEvent> {
+        let pressed = self.pin.is_low() == BUTTON_ACTIVE_LOW;
+        self.button.update(pressed, now)
+    }
+    fn clear(&mut self) {
+        self.button.clear();
+    }
+}
+
+// Touch pad
+
+// copper pad on PA6 (TIM3_CH1) with 1M to 3.3V: the pad is discharged, then
+// TIM3 captures how long it takes the resistor to charge it to a logic high
+#[cfg(feature = "touch")]
+const TOUCH_MAX_CHARGE_TIME: u16 = 0x4000;
+
+#[cfg(feature = "touch")]
+pub(crate) struct FIDO2TouchPad {
+    // switches between discharging and sensing, so it keeps CRL of GPIOA
+    pad: Pin<'A', 6, Dynamic>,
+    crl: Cr<'A', false>,
+    tim: pac::TIM3,
+    sensor: FIDO2TouchSensor,
+    next_sample: u64,
+}
+#[cfg(feature = "touch")]
+impl FIDO2TouchPad {
+    // the HAL timer has enabled and reset TIM3 on APB1, the input capture is
+    // set up on the released registers
+    pub fn new(
+        pad: Pin<'A', 6>,
+        mut crl: Cr<'A', false>,
+        timer: Timer<pac::TIM3>,
+    ) -> FIDO2TouchPad {
+        let pad = pad.into_dynamic(&mut crl);
+        let tim = timer.release();
+        // free running at the timer clock, CH1 captures the rising edge of TI1
+        tim.psc.write(|w| w.psc().bits(0));
+        tim.arr.write(|w| w.arr().bits(0xffff));
+        tim.ccmr1_input().write(|w| w.cc1s().ti1());
+        tim.ccer.write(|w| w.cc1e().set_bit());
+        tim.cr1.write(|w| w.cen().set_bit());
+        FIDO2TouchPad {
+            pad,
+            crl,
+            tim,
+            sensor: FIDO2TouchSensor::new(),
+            next_sample: 0,
+        }
+    }
+    // charge time in timer ticks
+    fn measure(&mut self) -> u32 {
+        let (pad, crl, tim) = (&mut self.pad, &mut self.crl, &self.tim);
+        cortex_m::interrupt::free(|_| {
+            // discharge: push-pull output low
+            pad.make_push_pull_output(crl);
+            pad.set_low().ok();
+            cortex_m::asm::delay(72);
+            tim.sr.modify(|_, w| w.cc1if().clear_bit());
+            tim.cnt.write(|w| w.cnt().bits(0));
+            // charge: floating input, the resistor pulls it up
+            pad.make_floating_input(crl);
+            while tim.sr.read().cc1if().bit_is_clear() {
+                if tim.cnt.read().cnt().bits() >= TOUCH_MAX_CHARGE_TIME {
+                    return TOUCH_MAX_CHARGE_TIME as u32;
+                }
+            }
+            tim.ccr1().read().ccr().bits() as u32
+        })
+    }
+}
+#[cfg(feature = "touch")]
+impl FIDO2PresenceInput for FIDO2TouchPad {
+    fn poll(&mut self, now: u64) -> Option<FIDO2ButtonEvent> {
+        if now >= self.next_sample {
+            self.next_sample = now + FIDO2_TOUCH_SAMPLE_INTERVAL_MS;
+            let charge_time = self.measure();
+            self.sensor.sample(charge_time, now);
+        }
+        self.sensor.update(now)
+    }
+    fn clear(&mut self) {
+        self.sensor.clear();
+    }
+}
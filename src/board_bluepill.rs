@@ -0,0 +1,77 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use stm32f1xx_hal::{
+    flash::ACR,
+    gpio::{Output, Pin, PinState, PushPull},
+    prelude::*,
+};
+
+use crate::board::{
+    debug_serial, usb_pull_dp_low, FIDO2Board, FIDO2BoardButton, FIDO2BoardParts,
+    FIDO2BoardPeripherals, FIDO2Led,
+};
+
+// BluePill: 8 MHz crystal, LED on PC13 (active low), a button wired from PA0
+// to ground, 1.5k pull-up on D+ (R10), JTAG/SWD left enabled for debugging
+pub(crate) struct FIDO2BluePill;
+impl FIDO2Board for FIDO2BluePill {
+    const NAME: &'static str = "unsafe{key} BluePill";
+    type LedPin = Pin<'C', 13, Output<PushPull>>;
+    type Presence = FIDO2BoardButton;
+    fn init(p: FIDO2BoardPeripherals, acr: &mut ACR) -> FIDO2BoardParts<Self> {
+        let clocks = p
+            .rcc
+            .constrain()
+            .cfgr
+            .use_hse(8.MHz())
+            .sysclk(72.MHz())
+            .freeze(acr);
+        let mut gpioa = p.gpioa.split();
+        let mut gpioc = p.gpioc.split();
+        let mut afio = p.afio.constrain();
+        let (debug_tx, debug_rx) = debug_serial(
+            p.usart1,
+            gpioa.pa9,
+            gpioa.pa10,
+            &mut gpioa.crh,
+            &mut afio.mapr,
+            &clocks,
+        );
+        let led = gpioc
+            .pc13
+            .into_push_pull_output_with_state(&mut gpioc.crh, PinState::High);
+        let presence = FIDO2BoardButton::new(gpioa.pa0, &mut gpioa.crl);
+        // hosts need about 10 ms of SE0 to notice the disconnect
+        let usb = usb_pull_dp_low(
+            p.usb,
+            gpioa.pa11,
+            gpioa.pa12,
+            &mut gpioa.crh,
+            clocks.sysclk().raw() / 100,
+        );
+        FIDO2BoardParts {
+            clocks,
+            led: FIDO2Led::new(led, true),
+            presence,
+            debug_tx,
+            debug_rx,
+            usb,
+        }
+    }
+}
@@ -45,3 +45,83 @@ pub(crate) const FIDO2_COMMAND_WINK_RESPONSE_MAX_SIZE: usize = 0;
 
 pub(crate) const FIDO2_COMMAND_LOCK_REQUEST_MAX_SIZE: usize = 1;
 pub(crate) const FIDO2_COMMAND_LOCK_RESPONSE_MAX_SIZE: usize = 0;
+
+// transport
+pub(crate) const FIDO2_BROADCAST_CHANNEL_ID: u32 = 0xffffffff;
+// id range available: 0x00001000(4096) ~ 0x00001009(4105)
+pub(crate) const FIDO2_CHANNEL_ID_FIRST: u32 = 0x00001000;
+pub(crate) const FIDO2_CHANNEL_COUNT: usize = 10;
+// request/response buffers are limited by the 20K of RAM, not by CTAPHID
+pub(crate) const FIDO2_MESSAGE_BUFFER_SIZE: usize = 2048;
+
+// ctap2
+pub(crate) const FIDO2_AAGUID: [u8; 16] = [
+    0x75, 0x6e, 0x73, 0x61, 0x66, 0x65, 0x7b, 0x6b, 0x65, 0x79, 0x7d, 0x00, 0x00, 0x00, 0x00, 0x01,
+];
+pub(crate) const FIDO2_MAX_CREDENTIAL_COUNT_IN_LIST: usize = 8;
+pub(crate) const FIDO2_MAX_CREDENTIAL_ID_LENGTH: usize = 80;
+pub(crate) const FIDO2_MAX_RP_ID_LENGTH: usize = 64;
+pub(crate) const FIDO2_MAX_USER_ID_LENGTH: usize = 64;
+pub(crate) const FIDO2_MAX_USER_NAME_LENGTH: usize = 64;
+pub(crate) const FIDO2_MAX_CRED_BLOB_LENGTH: usize = 32;
+// authenticatorGetNextAssertion has to follow within this time
+pub(crate) const FIDO2_GET_NEXT_ASSERTION_TIMEOUT_MS: u64 = 30_000;
+// client pin
+pub(crate) const FIDO2_PIN_MAX_RETRIES: u8 = 8;
+// mismatches in a row before the device has to be power cycled
+pub(crate) const FIDO2_PIN_MAX_CONSECUTIVE_MISMATCHES: u8 = 3;
+pub(crate) const FIDO2_PIN_MIN_LENGTH: usize = 4;
+pub(crate) const FIDO2_PIN_MAX_LENGTH: usize = 63;
+// rps allowed to see the minimum PIN length through the minPinLength extension
+pub(crate) const FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS: usize = 4;
+// a pinUvAuthToken expires this long after it was handed out
+pub(crate) const FIDO2_PIN_TOKEN_USAGE_MS: u64 = 600_000;
+// authenticatorReset is only allowed right after power up
+pub(crate) const FIDO2_RESET_WINDOW_MS: u64 = 10_000;
+
+// storage (flash), offsets are relative to 0x08000000
+// the last 17K of the chip, see memory.x
+pub(crate) const FIDO2_STORAGE_OFFSET: u32 = 0x1BC00;
+pub(crate) const FIDO2_STORAGE_PAGE_SIZE: u32 = 1024;
+pub(crate) const FIDO2_STORAGE_PAGES: u32 = 17;
+// page 0 ~ 1: device state (A/B)
+pub(crate) const FIDO2_STORAGE_STATE_PAGE: u32 = 0;
+// page 2: signature counter log
+pub(crate) const FIDO2_STORAGE_COUNTER_PAGE: u32 = 2;
+// page 3: PIN retries log
+pub(crate) const FIDO2_STORAGE_PIN_RETRIES_PAGE: u32 = 3;
+// page 4 ~ 9: resident credentials, one per page
+pub(crate) const FIDO2_STORAGE_CREDENTIAL_PAGE: u32 = 4;
+pub(crate) const FIDO2_STORAGE_CREDENTIAL_PAGES: u32 = 6;
+// page 10 ~ 11: large-blob array (A/B), the device state points to the copy in use
+pub(crate) const FIDO2_STORAGE_LARGE_BLOB_PAGE: u32 = 10;
+pub(crate) const FIDO2_MAX_SERIALIZED_LARGE_BLOB_ARRAY: usize = FIDO2_STORAGE_PAGE_SIZE as usize;
+// page 12: DRBG seed log, kept by authenticatorReset like every page after it
+pub(crate) const FIDO2_STORAGE_SEED_PAGE: u32 = 12;
+// page 13 ~ 16: one write protection group of the chip (4 pages), write
+// protected once the attestation key is locked, so nothing else lives here
+// page 15: attestation key, certificate and AAGUID, written by the vendor provisioning command
+pub(crate) const FIDO2_STORAGE_ATTESTATION_PAGE: u32 = 15;
+pub(crate) const FIDO2_MAX_ATTESTATION_CERTIFICATE_LENGTH: usize = FIDO2_STORAGE_PAGE_SIZE as usize - 56;
+// user presence button
+pub(crate) const FIDO2_BUTTON_DEBOUNCE_MS: u64 = 20;
+pub(crate) const FIDO2_BUTTON_LONG_PRESS_MS: u64 = 1500;
+pub(crate) const FIDO2_BUTTON_MULTI_TAP_GAP_MS: u64 = 300;
+// default time the user has to confirm a request
+pub(crate) const FIDO2_USER_PRESENCE_TIMEOUT_MS: u64 = 30_000;
+// capacitive touch pad
+pub(crate) const FIDO2_TOUCH_SAMPLE_INTERVAL_MS: u64 = 5;
+pub(crate) const FIDO2_TOUCH_CALIBRATION_SAMPLES: u32 = 32;
+// a touch makes the charge time this much longer than the baseline
+pub(crate) const FIDO2_TOUCH_THRESHOLD_PERCENT: u32 = 20;
+// a touch that lasts longer than this is taken as the new baseline
+pub(crate) const FIDO2_TOUCH_RECALIBRATE_MS: u64 = 10_000;
+// HID reports queued for the USB interrupt
+pub(crate) const FIDO2_USB_TX_QUEUE_LENGTH: usize = 8;
+// LED patterns
+pub(crate) const FIDO2_LED_WINK_MS: u64 = 1000;
+pub(crate) const FIDO2_LED_BLINK_MS: u64 = 125;
+// CTAPHID_KEEPALIVE interval while a request is processed
+pub(crate) const FIDO2_KEEPALIVE_INTERVAL_MS: u64 = 100;
+// debug console (debug-console feature), longest command line
+pub(crate) const FIDO2_CONSOLE_LINE_LENGTH: usize = 32;
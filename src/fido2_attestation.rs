@@ -0,0 +1,176 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use byteorder::{ByteOrder, LittleEndian};
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    consts::{FIDO2_AAGUID, FIDO2_MAX_ATTESTATION_CERTIFICATE_LENGTH, FIDO2_STORAGE_ATTESTATION_PAGE},
+    fido2_authenticator::FIDO2Authenticator,
+    fido2_crypto::FIDO2CryptoProvider,
+    fido2_internal_error::FIDO2InternalError,
+    fido2_log::{log_error, FIDO2Secret},
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::{page_offset, FIDO2Storage},
+};
+
+// attestation page
+// locking it turns on readout protection (RDP level 1) and write protection
+// for its group of 4 pages through the option bytes, from the next reset on.
+// what is left:
+// - RDP level 1 can always be taken back to level 0 by a debugger, that mass
+//   erases the flash and the key with it, which is also the only way to
+//   provision the device again
+// - a debugger may still attach under level 1 and read the SRAM, where the
+//   key is kept from boot on
+// - the F1 has no level 2, and fault injection (voltage glitching during the
+//   option byte load at reset, SWD attacks on level 1) is known to get the
+//   flash out of it
+// - the firmware itself reads the key, so a code execution bug leaks it
+// [marker: 2] [lock: 2] [certificate length: 2] [reserved: 2] [aaguid: 16] [private key: 32] [certificate]
+// the marker is written last, the lock is a halfword cleared to zero once
+const ATTESTATION_MARKER: u16 = 0xa77e;
+const LOCK: u32 = 2;
+const CERTIFICATE_LENGTH: u32 = 4;
+const AAGUID: u32 = 8;
+const PRIVATE_KEY: u32 = 24;
+const CERTIFICATE: u32 = 56;
+
+#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum FIDO2ProvisionSubCommand {
+    // [aaguid: 16] [P-256 private key: 32] [DER certificate]
+    Provision = 0x01,
+    // no more provisioning, protects the flash until the chip is mass erased
+    Lock = 0x02,
+}
+
+// batch attestation, without a key MakeCredential falls back to self attestation
+#[derive(Debug)]
+pub(crate) struct FIDO2Attestation {
+    pub aaguid: [u8; 16],
+    pub private_key: Option<FIDO2Secret<[u8; 32]>>,
+    pub certificate_length: usize,
+    pub locked: bool,
+}
+impl FIDO2Attestation {
+    pub fn load(storage: &mut impl FIDO2Storage) -> Result<FIDO2Attestation, FIDO2InternalError> {
+        let mut raw = [0u8; CERTIFICATE as usize];
+        storage.read(page_offset(FIDO2_STORAGE_ATTESTATION_PAGE), &mut raw)?;
+        let locked = LittleEndian::read_u16(&raw[LOCK as usize..]) != 0xffff;
+        let certificate_length = LittleEndian::read_u16(&raw[CERTIFICATE_LENGTH as usize..]) as usize;
+        // keys locked before the protection existed, or a lock interrupted
+        // between the lock halfword and the option bytes
+        if locked {
+            if let Err(e) = storage.protect_page(FIDO2_STORAGE_ATTESTATION_PAGE) {
+                log_error!("attestation page not protected: {:?}", e);
+            }
+        }
+        if LittleEndian::read_u16(&raw) != ATTESTATION_MARKER
+            || certificate_length > FIDO2_MAX_ATTESTATION_CERTIFICATE_LENGTH
+        {
+            return Ok(FIDO2Attestation {
+                aaguid: FIDO2_AAGUID,
+                private_key: None,
+                certificate_length: 0,
+                locked,
+            });
+        }
+        Ok(FIDO2Attestation {
+            aaguid: raw[AAGUID as usize..AAGUID as usize + 16].try_into().unwrap(),
+            private_key: Some(FIDO2Secret(
+                raw[PRIVATE_KEY as usize..CERTIFICATE as usize].try_into().unwrap(),
+            )),
+            certificate_length,
+            locked,
+        })
+    }
+    // DER certificate of the attestation key
+    pub fn read_certificate<'a>(
+        &self,
+        storage: &mut impl FIDO2Storage,
+        buf: &'a mut [u8; FIDO2_MAX_ATTESTATION_CERTIFICATE_LENGTH],
+    ) -> Result<&'a [u8], FIDO2InternalError> {
+        let certificate = &mut buf[..self.certificate_length];
+        storage.read(page_offset(FIDO2_STORAGE_ATTESTATION_PAGE) + CERTIFICATE, certificate)?;
+        Ok(certificate)
+    }
+}
+
+impl<S: FIDO2Storage, C: FIDO2CryptoProvider> FIDO2Authenticator<S, C> {
+    fn write_attestation(
+        &mut self,
+        aaguid: &[u8; 16],
+        private_key: &[u8; 32],
+        certificate: &[u8],
+    ) -> Result<(), FIDO2InternalError> {
+        let base = page_offset(FIDO2_STORAGE_ATTESTATION_PAGE);
+        self.storage.erase_page(FIDO2_STORAGE_ATTESTATION_PAGE)?;
+        self.storage.write(base + CERTIFICATE_LENGTH, &(certificate.len() as u16).to_le_bytes())?;
+        self.storage.write(base + AAGUID, aaguid)?;
+        self.storage.write(base + PRIVATE_KEY, private_key)?;
+        // halfword writes, the odd byte is padded with erased flash
+        let even = certificate.len() & !1;
+        self.storage.write(base + CERTIFICATE, &certificate[..even])?;
+        if even < certificate.len() {
+            self.storage.write(base + CERTIFICATE + even as u32, &[certificate[even], 0xff])?;
+        }
+        self.storage.write(base, &ATTESTATION_MARKER.to_le_bytes())
+    }
+    // vendor command, [sub command: 1] [parameters] in, status code out
+    pub fn provision(&mut self, request: &[u8], response: &mut [u8]) -> u16 {
+        response[0] = match self.provision_inner(request) {
+            Ok(()) => FIDO2StatusCode::Ctap2Ok,
+            Err(code) => code,
+        } as u8;
+        1
+    }
+    fn provision_inner(&mut self, request: &[u8]) -> Result<(), FIDO2StatusCode> {
+        let (sub_command, params) = request
+            .split_first()
+            .ok_or(FIDO2StatusCode::Ctap1ErrInvalidLength)?;
+        let sub_command = FIDO2ProvisionSubCommand::try_from(*sub_command)
+            .map_err(|_| FIDO2StatusCode::Ctap2ErrInvalidSubcommand)?;
+        if self.attestation.locked {
+            return Err(FIDO2StatusCode::Ctap2ErrNotAllowed);
+        }
+        match sub_command {
+            FIDO2ProvisionSubCommand::Provision => {
+                if params.len() <= 48 || params.len() - 48 > FIDO2_MAX_ATTESTATION_CERTIFICATE_LENGTH {
+                    return Err(FIDO2StatusCode::Ctap1ErrInvalidLength);
+                }
+                let aaguid: [u8; 16] = params[..16].try_into().unwrap();
+                let private_key: [u8; 32] = params[16..48].try_into().unwrap();
+                let certificate = &params[48..];
+                if C::p256_public_key(&private_key).is_none() {
+                    return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+                }
+                self.write_attestation(&aaguid, &private_key, certificate)?;
+            }
+            FIDO2ProvisionSubCommand::Lock => {
+                self.storage.write(
+                    page_offset(FIDO2_STORAGE_ATTESTATION_PAGE) + LOCK,
+                    &[0, 0],
+                )?;
+                self.storage.protect_page(FIDO2_STORAGE_ATTESTATION_PAGE)?;
+            }
+        }
+        self.attestation = FIDO2Attestation::load(&mut self.storage)?;
+        Ok(())
+    }
+}
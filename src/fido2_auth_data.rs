@@ -0,0 +1,70 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    fido2_cbor::FIDO2CborWriter, fido2_commands::FIDO2PacketCommandResponse, fido2_cose::FIDO2CoseKey,
+};
+
+// authenticatorData flags
+pub(crate) const FIDO2_FLAG_UP: u8 = 0x01;
+pub(crate) const FIDO2_FLAG_UV: u8 = 0x04;
+pub(crate) const FIDO2_FLAG_AT: u8 = 0x40;
+pub(crate) const FIDO2_FLAG_ED: u8 = 0x80;
+
+pub(crate) const FIDO2_MAX_AUTH_DATA_LENGTH: usize = 384;
+
+// authenticatorData
+// [rpIdHash: 32] [flags: 1] [signCount: 4] [attestedCredentialData] [extensions]
+#[derive(Debug)]
+pub(crate) struct FIDO2AuthenticatorData<'a> {
+    pub rp_id_hash: &'a [u8; 32],
+    pub flags: u8,
+    pub sign_count: u32,
+    // aaguid, credential id and public key, MakeCredential only
+    pub attested_credential: Option<(&'a [u8; 16], &'a [u8], FIDO2CoseKey)>,
+    // encoded CBOR map
+    pub extensions: Option<&'a [u8]>,
+}
+impl<'a> FIDO2PacketCommandResponse for FIDO2AuthenticatorData<'a> {
+    fn apply(self, arr: &mut [u8]) -> Option<u16> {
+        let mut flags = self.flags;
+        if self.attested_credential.is_some() {
+            flags |= FIDO2_FLAG_AT;
+        }
+        if self.extensions.is_some() {
+            flags |= FIDO2_FLAG_ED;
+        }
+        if arr.len() < 37 {
+            return None;
+        }
+        arr[..32].copy_from_slice(self.rp_id_hash);
+        arr[32] = flags;
+        arr[33..37].copy_from_slice(&self.sign_count.to_be_bytes());
+        let mut w = FIDO2CborWriter::new(&mut arr[37..]);
+        if let Some((aaguid, credential_id, public_key)) = &self.attested_credential {
+            w.raw(*aaguid);
+            w.raw(&(credential_id.len() as u16).to_be_bytes());
+            w.raw(credential_id);
+            public_key.write(&mut w);
+        }
+        if let Some(extensions) = self.extensions {
+            w.raw(extensions);
+        }
+        Some(37 + w.finish()?)
+    }
+}
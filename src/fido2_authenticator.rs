@@ -0,0 +1,188 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    fido2_attestation::FIDO2Attestation,
+    fido2_client_pin::FIDO2ClientPinState,
+    fido2_counter::FIDO2SignatureCounter,
+    fido2_credential_management::FIDO2EnumerationState,
+    fido2_credential_store::FIDO2CredentialStore,
+    fido2_crypto::FIDO2CryptoProvider,
+    fido2_device_state::FIDO2DeviceState,
+    fido2_get_assertion::FIDO2AssertionState,
+    fido2_internal_error::FIDO2InternalError,
+    fido2_large_blobs::FIDO2LargeBlobWrite,
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::FIDO2Storage,
+};
+
+// Trait
+
+// things the authenticator needs from the device while processing a request
+pub(crate) trait FIDO2Platform {
+    // block until the user confirms the operation
+    fn user_presence(&mut self) -> Result<(), FIDO2StatusCode>;
+    // milliseconds since power up
+    fn uptime_ms(&mut self) -> u64;
+}
+
+#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum FIDO2CborCommand {
+    AuthenticatorMakeCredential = 0x01,
+    AuthenticatorGetAssertion = 0x02,
+    AuthenticatorGetInfo = 0x04,
+    AuthenticatorClientPIN = 0x06,
+    AuthenticatorReset = 0x07,
+    AuthenticatorCredentialManagement = 0x0A,
+    AuthenticatorSelection = 0x0B,
+    AuthenticatorLargeBlobs = 0x0C,
+    AuthenticatorConfig = 0x0D,
+    AuthenticatorGetNextAssertion = 0x08,
+}
+
+pub struct FIDO2Authenticator<S: FIDO2Storage, C: FIDO2CryptoProvider> {
+    pub storage: S,
+    pub crypto: C,
+    pub state: FIDO2DeviceState,
+    pub counter: FIDO2SignatureCounter,
+    pub attestation: FIDO2Attestation,
+    pub credentials: FIDO2CredentialStore,
+    pub pin: FIDO2ClientPinState,
+    // credentials left for authenticatorGetNextAssertion
+    pub assertion: Option<FIDO2AssertionState>,
+    // rps or credentials left for authenticatorCredentialManagement
+    pub enumeration: Option<FIDO2EnumerationState>,
+    // authenticatorLargeBlobs write waiting for more fragments
+    pub large_blob: Option<FIDO2LargeBlobWrite>,
+}
+impl<S: FIDO2Storage, C: FIDO2CryptoProvider> FIDO2Authenticator<S, C> {
+    pub fn new(mut storage: S, mut crypto: C) -> Result<FIDO2Authenticator<S, C>, FIDO2InternalError> {
+        let mut state = FIDO2DeviceState::load_or_init(&mut storage, &mut crypto)?;
+        // power was lost during authenticatorReset
+        if state.reset_pending {
+            FIDO2Authenticator::<S, C>::wipe(&mut storage, &mut state)?;
+        }
+        let counter = FIDO2SignatureCounter::load(&mut storage, &state)?;
+        let credentials = FIDO2CredentialStore::load(&mut storage)?;
+        let attestation = FIDO2Attestation::load(&mut storage)?;
+        let pin = FIDO2ClientPinState::new(&mut crypto);
+        Ok(FIDO2Authenticator {
+            storage,
+            crypto,
+            state,
+            counter,
+            attestation,
+            credentials,
+            pin,
+            assertion: None,
+            enumeration: None,
+            large_blob: None,
+        })
+    }
+    // pinUvAuthParam of MakeCredential and GetAssertion, returns true if the user is verified
+    pub fn check_pin_uv_auth_param(
+        &mut self,
+        platform: &mut impl FIDO2Platform,
+        param: Option<&[u8]>,
+        protocol: Option<u64>,
+        client_data_hash: &[u8],
+        permission: u8,
+        rp_id_hash: &[u8; 32],
+    ) -> Result<bool, FIDO2StatusCode> {
+        let param = match param {
+            Some(p) => p,
+            None => return Ok(false),
+        };
+        // zero length: the platform asks the user to pick an authenticator
+        if param.is_empty() {
+            platform.user_presence()?;
+            return Err(if self.state.pin_hash.is_some() {
+                FIDO2StatusCode::Ctap2ErrPinInvalid
+            } else {
+                FIDO2StatusCode::Ctap2ErrPinNotSet
+            });
+        }
+        self.verify_pin_uv_auth_token(
+            protocol,
+            param,
+            &[client_data_hash],
+            permission,
+            Some(rp_id_hash),
+        )?;
+        Ok(self.pin.user_verified)
+    }
+    // CTAPHID_CBOR request in, status code and CBOR response out
+    pub fn process(
+        &mut self,
+        platform: &mut impl FIDO2Platform,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> u16 {
+        if request.is_empty() {
+            response[0] = FIDO2StatusCode::Ctap1ErrInvalidLength as u8;
+            return 1;
+        }
+        let data = &request[1..];
+        let out = &mut response[1..];
+        let command = FIDO2CborCommand::try_from(request[0]);
+        if !matches!(command, Ok(FIDO2CborCommand::AuthenticatorGetNextAssertion)) {
+            self.assertion = None;
+        }
+        if !matches!(command, Ok(FIDO2CborCommand::AuthenticatorCredentialManagement)) {
+            self.enumeration = None;
+        }
+        self.pin.expire(&mut self.crypto, platform.uptime_ms());
+        let result = match command {
+            Ok(FIDO2CborCommand::AuthenticatorMakeCredential) => {
+                self.make_credential(platform, data, out)
+            }
+            Ok(FIDO2CborCommand::AuthenticatorGetAssertion) => {
+                self.get_assertion(platform, data, out)
+            }
+            Ok(FIDO2CborCommand::AuthenticatorGetInfo) => self.get_info(out),
+            Ok(FIDO2CborCommand::AuthenticatorClientPIN) => {
+                self.client_pin(platform, data, out)
+            }
+            Ok(FIDO2CborCommand::AuthenticatorReset) => self.reset(platform),
+            Ok(FIDO2CborCommand::AuthenticatorCredentialManagement) => {
+                self.credential_management(data, out)
+            }
+            // the platform asks every authenticator, the one that is touched wins
+            Ok(FIDO2CborCommand::AuthenticatorSelection) => platform.user_presence().map(|_| 0),
+            Ok(FIDO2CborCommand::AuthenticatorLargeBlobs) => self.large_blobs(data, out),
+            Ok(FIDO2CborCommand::AuthenticatorConfig) => self.config(data),
+            Ok(FIDO2CborCommand::AuthenticatorGetNextAssertion) => {
+                self.get_next_assertion(platform, out)
+            }
+            Err(_) => Err(FIDO2StatusCode::Ctap1ErrInvalidCommand),
+        };
+        match result {
+            Ok(length) => {
+                response[0] = FIDO2StatusCode::Ctap2Ok as u8;
+                length + 1
+            }
+            Err(code) => {
+                response[0] = code as u8;
+                1
+            }
+        }
+    }
+}
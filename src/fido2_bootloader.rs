@@ -0,0 +1,50 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use core::mem::MaybeUninit;
+
+// reboot into the STM32 system memory bootloader (USART1 / DFU), the request
+// survives the reset in RAM that cortex-m-rt leaves alone
+
+const FIDO2_BOOTLOADER_MAGIC: u32 = 0xB007_10AD;
+// system memory of the STM32F103, vector table first
+const FIDO2_SYSTEM_MEMORY: u32 = 0x1FFF_F000;
+
+#[link_section = ".uninit.FIDO2_BOOTLOADER_REQUEST"]
+static mut FIDO2_BOOTLOADER_REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+pub(crate) fn reboot_to_bootloader() -> ! {
+    unsafe {
+        core::ptr::write_volatile(
+            core::ptr::addr_of_mut!(FIDO2_BOOTLOADER_REQUEST).cast::<u32>(),
+            FIDO2_BOOTLOADER_MAGIC,
+        )
+    };
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+// runs before RAM is initialized, the clocks and peripherals are still in
+// their reset state, which is what the bootloader expects
+#[cortex_m_rt::pre_init]
+unsafe fn bootloader_check() {
+    let request = core::ptr::addr_of_mut!(FIDO2_BOOTLOADER_REQUEST).cast::<u32>();
+    if core::ptr::read_volatile(request) == FIDO2_BOOTLOADER_MAGIC {
+        core::ptr::write_volatile(request, 0);
+        cortex_m::asm::bootload(FIDO2_SYSTEM_MEMORY as *const u32);
+    }
+}
@@ -0,0 +1,277 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::consts::{
+    FIDO2_BUTTON_DEBOUNCE_MS, FIDO2_BUTTON_LONG_PRESS_MS, FIDO2_BUTTON_MULTI_TAP_GAP_MS,
+};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum FIDO2ButtonEventKind {
+    // released before the long press time, this is user presence
+    Press,
+    // still held after the long press time, sent once per press
+    LongPress,
+    // two or more presses, sent after the last one
+    MultiTap(u8),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct FIDO2ButtonEvent {
+    pub kind: FIDO2ButtonEventKind,
+    // milliseconds since power up
+    pub timestamp: u64,
+}
+
+// a button or a touch pad, the presence logic only sees the events
+pub(crate) trait FIDO2PresenceInput {
+    fn poll(&mut self, now: u64) -> Option<FIDO2ButtonEvent>;
+    // forget the taps seen so far
+    fn clear(&mut self);
+}
+
+// debounced button, fed with the raw pin level and the time so it runs on the host too
+#[derive(Debug)]
+pub(crate) struct FIDO2Button {
+    raw: bool,
+    raw_since: u64,
+    pressed: bool,
+    pressed_since: u64,
+    long_press_sent: bool,
+    taps: u8,
+    last_release: u64,
+}
+impl FIDO2Button {
+    pub fn new() -> FIDO2Button {
+        FIDO2Button {
+            raw: false,
+            raw_since: 0,
+            pressed: false,
+            pressed_since: 0,
+            long_press_sent: false,
+            taps: 0,
+            last_release: 0,
+        }
+    }
+    // forget the taps seen so far, a press that is held stays held
+    pub fn clear(&mut self) {
+        self.taps = 0;
+        self.long_press_sent = self.pressed;
+    }
+    pub fn update(&mut self, raw: bool, now: u64) -> Option<FIDO2ButtonEvent> {
+        if raw != self.raw {
+            self.raw = raw;
+            self.raw_since = now;
+        }
+        // the level has to be stable for the debounce time
+        if self.raw != self.pressed && now - self.raw_since >= FIDO2_BUTTON_DEBOUNCE_MS {
+            self.pressed = self.raw;
+            if self.pressed {
+                self.pressed_since = now;
+                self.long_press_sent = false;
+            } else {
+                self.last_release = now;
+                if !self.long_press_sent {
+                    self.taps = self.taps.saturating_add(1);
+                    return Some(FIDO2ButtonEvent {
+                        kind: FIDO2ButtonEventKind::Press,
+                        timestamp: now,
+                    });
+                }
+            }
+        }
+        if self.pressed {
+            if !self.long_press_sent && now - self.pressed_since >= FIDO2_BUTTON_LONG_PRESS_MS {
+                self.long_press_sent = true;
+                self.taps = 0;
+                return Some(FIDO2ButtonEvent {
+                    kind: FIDO2ButtonEventKind::LongPress,
+                    timestamp: now,
+                });
+            }
+        } else if self.taps > 0 && now - self.last_release >= FIDO2_BUTTON_MULTI_TAP_GAP_MS {
+            let taps = self.taps;
+            self.taps = 0;
+            if taps > 1 {
+                return Some(FIDO2ButtonEvent {
+                    kind: FIDO2ButtonEventKind::MultiTap(taps),
+                    timestamp: now,
+                });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // feeds the level every millisecond from `from` up to and including `to`
+    fn hold(button: &mut FIDO2Button, raw: bool, from: u64, to: u64) -> Vec<FIDO2ButtonEvent> {
+        (from..=to)
+            .filter_map(|now| button.update(raw, now))
+            .collect()
+    }
+
+    fn kinds(events: &[FIDO2ButtonEvent]) -> Vec<FIDO2ButtonEventKind> {
+        events.iter().map(|e| e.kind).collect()
+    }
+
+    #[test]
+    fn bounces_shorter_than_the_debounce_time_are_ignored() {
+        let mut button = FIDO2Button::new();
+        let mut events = hold(&mut button, true, 0, FIDO2_BUTTON_DEBOUNCE_MS - 2);
+        events.extend(hold(&mut button, false, FIDO2_BUTTON_DEBOUNCE_MS - 1, 1000));
+        assert!(events.is_empty());
+        assert!(!button.pressed);
+    }
+
+    #[test]
+    fn a_level_stable_for_the_debounce_time_is_taken() {
+        let mut button = FIDO2Button::new();
+        assert_eq!(button.update(true, 100), None);
+        assert_eq!(
+            button.update(true, 100 + FIDO2_BUTTON_DEBOUNCE_MS - 1),
+            None
+        );
+        assert!(!button.pressed);
+        assert_eq!(button.update(true, 100 + FIDO2_BUTTON_DEBOUNCE_MS), None);
+        assert!(button.pressed);
+        // the release is debounced the same way
+        assert_eq!(button.update(false, 200), None);
+        assert_eq!(
+            button.update(false, 200 + FIDO2_BUTTON_DEBOUNCE_MS - 1),
+            None
+        );
+        assert_eq!(
+            button.update(false, 200 + FIDO2_BUTTON_DEBOUNCE_MS),
+            Some(FIDO2ButtonEvent {
+                kind: FIDO2ButtonEventKind::Press,
+                timestamp: 200 + FIDO2_BUTTON_DEBOUNCE_MS,
+            })
+        );
+    }
+
+    #[test]
+    fn a_single_tap_is_a_press_only() {
+        let mut button = FIDO2Button::new();
+        let mut events = hold(&mut button, true, 0, 100);
+        events.extend(hold(&mut button, false, 101, 2000));
+        assert_eq!(kinds(&events), [FIDO2ButtonEventKind::Press]);
+    }
+
+    #[test]
+    fn long_press_is_sent_once_without_a_press() {
+        let mut button = FIDO2Button::new();
+        // pressed is taken at 20, the long press is due 1500 later
+        let events = hold(
+            &mut button,
+            true,
+            0,
+            FIDO2_BUTTON_DEBOUNCE_MS + FIDO2_BUTTON_LONG_PRESS_MS - 1,
+        );
+        assert!(events.is_empty());
+        let events = hold(
+            &mut button,
+            true,
+            FIDO2_BUTTON_DEBOUNCE_MS + FIDO2_BUTTON_LONG_PRESS_MS,
+            5000,
+        );
+        assert_eq!(
+            events,
+            [FIDO2ButtonEvent {
+                kind: FIDO2ButtonEventKind::LongPress,
+                timestamp: FIDO2_BUTTON_DEBOUNCE_MS + FIDO2_BUTTON_LONG_PRESS_MS,
+            }]
+        );
+        assert!(hold(&mut button, false, 5001, 7000).is_empty());
+    }
+
+    #[test]
+    fn taps_are_counted_until_the_gap() {
+        let mut button = FIDO2Button::new();
+        let mut events = Vec::new();
+        let mut now = 0;
+        for _ in 0..3 {
+            events.extend(hold(&mut button, true, now, now + 80));
+            events.extend(hold(&mut button, false, now + 81, now + 200));
+            now += 201;
+        }
+        assert_eq!(kinds(&events), [FIDO2ButtonEventKind::Press; 3]);
+        // the last release was taken at 402 + 81 + 20
+        let last_release = 2 * 201 + 81 + FIDO2_BUTTON_DEBOUNCE_MS;
+        let events = hold(
+            &mut button,
+            false,
+            now,
+            last_release + FIDO2_BUTTON_MULTI_TAP_GAP_MS - 1,
+        );
+        assert!(events.is_empty());
+        assert_eq!(
+            button.update(false, last_release + FIDO2_BUTTON_MULTI_TAP_GAP_MS),
+            Some(FIDO2ButtonEvent {
+                kind: FIDO2ButtonEventKind::MultiTap(3),
+                timestamp: last_release + FIDO2_BUTTON_MULTI_TAP_GAP_MS,
+            })
+        );
+        assert!(hold(
+            &mut button,
+            false,
+            last_release + FIDO2_BUTTON_MULTI_TAP_GAP_MS + 1,
+            5000
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn taps_further_apart_than_the_gap_are_single() {
+        let mut button = FIDO2Button::new();
+        let mut events = Vec::new();
+        for start in [0, 1000] {
+            events.extend(hold(&mut button, true, start, start + 80));
+            events.extend(hold(&mut button, false, start + 81, start + 900));
+        }
+        assert_eq!(kinds(&events), [FIDO2ButtonEventKind::Press; 2]);
+    }
+
+    #[test]
+    fn a_long_press_drops_earlier_taps() {
+        let mut button = FIDO2Button::new();
+        let mut events = hold(&mut button, true, 0, 80);
+        events.extend(hold(&mut button, false, 81, 200));
+        events.extend(hold(&mut button, true, 201, 2000));
+        events.extend(hold(&mut button, false, 2001, 4000));
+        assert_eq!(
+            kinds(&events),
+            [FIDO2ButtonEventKind::Press, FIDO2ButtonEventKind::LongPress]
+        );
+    }
+
+    #[test]
+    fn clear_forgets_the_taps_and_a_held_press() {
+        let mut button = FIDO2Button::new();
+        let mut events = hold(&mut button, true, 0, 80);
+        events.extend(hold(&mut button, false, 81, 200));
+        events.extend(hold(&mut button, true, 201, 300));
+        button.clear();
+        // the held press neither counts as a tap nor turns into a long press
+        events.extend(hold(&mut button, true, 301, 3000));
+        events.extend(hold(&mut button, false, 3001, 5000));
+        assert_eq!(kinds(&events), [FIDO2ButtonEventKind::Press]);
+    }
+}
@@ -0,0 +1,295 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::fido2_status_code::FIDO2StatusCode;
+
+// major types
+pub(crate) const CBOR_UNSIGNED: u8 = 0;
+pub(crate) const CBOR_NEGATIVE: u8 = 1;
+pub(crate) const CBOR_BYTES: u8 = 2;
+pub(crate) const CBOR_TEXT: u8 = 3;
+pub(crate) const CBOR_ARRAY: u8 = 4;
+pub(crate) const CBOR_MAP: u8 = 5;
+pub(crate) const CBOR_TAG: u8 = 6;
+pub(crate) const CBOR_SIMPLE: u8 = 7;
+
+// nesting limit for skipping unknown values (CTAP2 canonical CBOR is at most 4 levels deep)
+const CBOR_MAX_DEPTH: u8 = 8;
+
+// Writer
+
+// CTAP2 canonical CBOR encoder, the caller is responsible for the order of map keys
+#[derive(Debug)]
+pub(crate) struct FIDO2CborWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    overflow: bool,
+}
+impl<'a> FIDO2CborWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> FIDO2CborWriter<'a> {
+        FIDO2CborWriter {
+            buf,
+            pos: 0,
+            overflow: false,
+        }
+    }
+    fn put(&mut self, data: &[u8]) {
+        if self.overflow || self.pos + data.len() > self.buf.len() {
+            self.overflow = true;
+            return;
+        }
+        self.buf[self.pos..self.pos + data.len()].copy_from_slice(data);
+        self.pos += data.len();
+    }
+    fn head(&mut self, major: u8, val: u64) {
+        let m = major << 5;
+        if val < 24 {
+            self.put(&[m | val as u8]);
+        } else if val <= 0xff {
+            self.put(&[m | 24, val as u8]);
+        } else if val <= 0xffff {
+            self.put(&[m | 25]);
+            self.put(&(val as u16).to_be_bytes());
+        } else if val <= 0xffff_ffff {
+            self.put(&[m | 26]);
+            self.put(&(val as u32).to_be_bytes());
+        } else {
+            self.put(&[m | 27]);
+            self.put(&val.to_be_bytes());
+        }
+    }
+    pub fn unsigned(&mut self, val: u64) -> &mut Self {
+        self.head(CBOR_UNSIGNED, val);
+        self
+    }
+    pub fn int(&mut self, val: i64) -> &mut Self {
+        if val < 0 {
+            self.head(CBOR_NEGATIVE, (-1 - val) as u64);
+        } else {
+            self.head(CBOR_UNSIGNED, val as u64);
+        }
+        self
+    }
+    pub fn bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.head(CBOR_BYTES, data.len() as u64);
+        self.put(data);
+        self
+    }
+    pub fn text(&mut self, data: &str) -> &mut Self {
+        self.head(CBOR_TEXT, data.len() as u64);
+        self.put(data.as_bytes());
+        self
+    }
+    pub fn array(&mut self, len: usize) -> &mut Self {
+        self.head(CBOR_ARRAY, len as u64);
+        self
+    }
+    pub fn map(&mut self, len: usize) -> &mut Self {
+        self.head(CBOR_MAP, len as u64);
+        self
+    }
+    pub fn bool(&mut self, val: bool) -> &mut Self {
+        self.put(&[(CBOR_SIMPLE << 5) | if val { 21 } else { 20 }]);
+        self
+    }
+    // already encoded CBOR item
+    pub fn raw(&mut self, data: &[u8]) -> &mut Self {
+        self.put(data);
+        self
+    }
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+    pub fn finish(&self) -> Option<u16> {
+        if self.overflow {
+            return None;
+        }
+        Some(self.pos as u16)
+    }
+}
+
+// Reader
+
+// CTAP2 canonical CBOR decoder (definite lengths only)
+#[derive(Debug, Clone)]
+pub(crate) struct FIDO2CborReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+impl<'a> FIDO2CborReader<'a> {
+    pub fn new(data: &'a [u8]) -> FIDO2CborReader<'a> {
+        FIDO2CborReader { data, pos: 0 }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+    // the length comes from the message, so it must not wrap around
+    fn take(&mut self, len: u64) -> Result<&'a [u8], FIDO2StatusCode> {
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| self.pos.checked_add(len))
+            .filter(|end| *end <= self.data.len())
+            .ok_or(FIDO2StatusCode::Ctap2ErrInvalidCbor)?;
+        let r = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(r)
+    }
+    fn count(val: u64) -> Result<usize, FIDO2StatusCode> {
+        usize::try_from(val).map_err(|_| FIDO2StatusCode::Ctap2ErrInvalidCbor)
+    }
+    pub fn peek_major(&self) -> Result<u8, FIDO2StatusCode> {
+        if self.is_empty() {
+            return Err(FIDO2StatusCode::Ctap2ErrInvalidCbor);
+        }
+        Ok(self.data[self.pos] >> 5)
+    }
+    fn head(&mut self) -> Result<(u8, u64), FIDO2StatusCode> {
+        let first = self.take(1)?[0];
+        let major = first >> 5;
+        let info = first & 0b00011111;
+        let val = match info {
+            0..=23 => info as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            // indefinite lengths and reserved values are not canonical
+            _ => return Err(FIDO2StatusCode::Ctap2ErrInvalidCbor),
+        };
+        Ok((major, val))
+    }
+    fn expect(&mut self, major: u8) -> Result<u64, FIDO2StatusCode> {
+        if self.peek_major()? != major {
+            return Err(FIDO2StatusCode::Ctap2ErrCborUnexpectedType);
+        }
+        Ok(self.head()?.1)
+    }
+    pub fn unsigned(&mut self) -> Result<u64, FIDO2StatusCode> {
+        self.expect(CBOR_UNSIGNED)
+    }
+    pub fn int(&mut self) -> Result<i64, FIDO2StatusCode> {
+        match self.peek_major()? {
+            CBOR_UNSIGNED => {
+                let v = self.head()?.1;
+                if v > i64::MAX as u64 {
+                    return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+                }
+                Ok(v as i64)
+            }
+            CBOR_NEGATIVE => {
+                let v = self.head()?.1;
+                if v > i64::MAX as u64 {
+                    return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+                }
+                Ok(-1 - v as i64)
+            }
+            _ => Err(FIDO2StatusCode::Ctap2ErrCborUnexpectedType),
+        }
+    }
+    pub fn bytes(&mut self) -> Result<&'a [u8], FIDO2StatusCode> {
+        let len = self.expect(CBOR_BYTES)?;
+        self.take(len)
+    }
+    pub fn text(&mut self) -> Result<&'a str, FIDO2StatusCode> {
+        let len = self.expect(CBOR_TEXT)?;
+        let raw = self.take(len)?;
+        core::str::from_utf8(raw).map_err(|_| FIDO2StatusCode::Ctap2ErrInvalidCbor)
+    }
+    pub fn array(&mut self) -> Result<usize, FIDO2StatusCode> {
+        Self::count(self.expect(CBOR_ARRAY)?)
+    }
+    pub fn map(&mut self) -> Result<usize, FIDO2StatusCode> {
+        Self::count(self.expect(CBOR_MAP)?)
+    }
+    pub fn bool(&mut self) -> Result<bool, FIDO2StatusCode> {
+        match self.expect(CBOR_SIMPLE)? {
+            20 => Ok(false),
+            21 => Ok(true),
+            _ => Err(FIDO2StatusCode::Ctap2ErrCborUnexpectedType),
+        }
+    }
+    fn skip_depth(&mut self, depth: u8) -> Result<(), FIDO2StatusCode> {
+        if depth > CBOR_MAX_DEPTH {
+            return Err(FIDO2StatusCode::Ctap2ErrInvalidCbor);
+        }
+        let (major, val) = self.head()?;
+        match major {
+            CBOR_BYTES | CBOR_TEXT => {
+                self.take(val)?;
+            }
+            CBOR_ARRAY => {
+                for _ in 0..val {
+                    self.skip_depth(depth + 1)?;
+                }
+            }
+            CBOR_MAP => {
+                let items = val
+                    .checked_mul(2)
+                    .ok_or(FIDO2StatusCode::Ctap2ErrInvalidCbor)?;
+                for _ in 0..items {
+                    self.skip_depth(depth + 1)?;
+                }
+            }
+            CBOR_TAG => self.skip_depth(depth + 1)?,
+            _ => {}
+        }
+        Ok(())
+    }
+    pub fn skip(&mut self) -> Result<(), FIDO2StatusCode> {
+        self.skip_depth(0)
+    }
+    // the encoded bytes of the next item, for parsing it later
+    pub fn raw(&mut self) -> Result<&'a [u8], FIDO2StatusCode> {
+        let start = self.pos;
+        self.skip()?;
+        Ok(&self.data[start..self.pos])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // heads with 8 byte lengths close to u64::MAX must not wrap the position
+    #[test]
+    fn huge_lengths_are_invalid() {
+        let huge = [0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+        assert_eq!(
+            FIDO2CborReader::new(&huge).bytes(),
+            Err(FIDO2StatusCode::Ctap2ErrInvalidCbor)
+        );
+        let text = [0x7b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0x61];
+        assert_eq!(
+            FIDO2CborReader::new(&text).skip(),
+            Err(FIDO2StatusCode::Ctap2ErrInvalidCbor)
+        );
+        let map = [0xbb, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01];
+        assert_eq!(
+            FIDO2CborReader::new(&map).skip(),
+            Err(FIDO2StatusCode::Ctap2ErrInvalidCbor)
+        );
+        let short = [0x43, 0x01, 0x02];
+        assert_eq!(
+            FIDO2CborReader::new(&short).bytes(),
+            Err(FIDO2StatusCode::Ctap2ErrInvalidCbor)
+        );
+        let mut ok = FIDO2CborReader::new(&[0x42, 0x01, 0x02]);
+        assert_eq!(ok.bytes(), Ok(&[1u8, 2][..]));
+        assert!(ok.is_empty());
+    }
+}
@@ -16,7 +16,7 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::utils::set_bit_u128;
+use crate::{consts::FIDO2_MAX_CHUNK_PACKET_DATA_SIZE, utils::set_bit_u128};
 
 #[derive(Debug)]
 pub(crate) struct FIDO2ChunkMerger {
@@ -24,6 +24,7 @@ pub(crate) struct FIDO2ChunkMerger {
     pub first_packet_received: bool,
     pub seq_received: u128,
     pub data_length: u16,
+    pub chunks_num: u8,
 }
 impl FIDO2ChunkMerger {
     pub fn new(data_length: u16, first_packet_length: u8) -> FIDO2ChunkMerger {
@@ -34,6 +35,7 @@ impl FIDO2ChunkMerger {
             seq_received: 0,
             data_length,
             first_packet_length,
+            chunks_num: chunks_num(data_length, first_packet_length),
         }
     }
     fn mark_first_packet_received(&mut self) {
@@ -43,7 +45,12 @@ impl FIDO2ChunkMerger {
         set_bit_u128(&mut self.seq_received, index);
     }
     pub fn is_done(&self) -> bool {
-        self.first_packet_received && self.seq_received == (1u128 << self.data_length as usize) - 1
+        let all = if self.chunks_num >= 128 {
+            u128::MAX
+        } else {
+            (1u128 << self.chunks_num as usize) - 1
+        };
+        self.first_packet_received && self.seq_received == all
     }
     pub fn apply(&mut self, buffer: &mut [u8], data: &[u8], seq_id: u8) {
         // seq_id > 127: first packet
@@ -54,10 +61,12 @@ impl FIDO2ChunkMerger {
             self.mark_first_packet_received();
         } else {
             // chunk packet
-            let chunk_len = core::cmp::min(59, data.len());
-            let _ = &mut buffer[seq_id as usize * 59 + self.first_packet_length as usize
-                ..seq_id as usize * 59 + self.first_packet_length as usize + chunk_len]
-                .copy_from_slice(&data[..chunk_len]);
+            if seq_id >= self.chunks_num {
+                return;
+            }
+            let (start, end) = chunk_range(self.data_length, self.first_packet_length, seq_id);
+            let chunk_len = core::cmp::min(end - start, data.len());
+            let _ = &mut buffer[start..start + chunk_len].copy_from_slice(&data[..chunk_len]);
             self.mark_chunk_packet_received(seq_id);
         }
     }
@@ -71,15 +80,10 @@ pub(crate) struct FIDO2ChunkSpliter {
 }
 impl FIDO2ChunkSpliter {
     pub fn new(data_length: u16, first_packet_length: u8) -> FIDO2ChunkSpliter {
-        let chunks_num = if data_length <= first_packet_length as u16 {
-            0
-        } else {
-            ((data_length - first_packet_length as u16) / 59 + 1) as u8
-        };
         FIDO2ChunkSpliter {
             data_length,
             first_packet_length,
-            chunks_num,
+            chunks_num: chunks_num(data_length, first_packet_length),
         }
     }
     pub fn apply(&self, buffer: &[u8], data: &mut [u8], seq_id: u8) {
@@ -90,14 +94,31 @@ impl FIDO2ChunkSpliter {
             let _ = &mut data[..first_len].copy_from_slice(&buffer[..first_len]);
         } else {
             // chunk packet
-            let chunk_len = core::cmp::min(59, data.len());
-            let _ = &mut data[..chunk_len].copy_from_slice(
-                &buffer[seq_id as usize * 59 + self.first_packet_length as usize
-                    ..seq_id as usize * 59 + self.first_packet_length as usize + chunk_len],
-            );
+            let (start, end) = chunk_range(self.data_length, self.first_packet_length, seq_id);
+            let chunk_len = core::cmp::min(end - start, data.len());
+            let _ = &mut data[..chunk_len].copy_from_slice(&buffer[start..start + chunk_len]);
         }
     }
     pub fn chunks(&self) -> u8 {
         self.chunks_num
     }
 }
+
+fn chunks_num(data_length: u16, first_packet_length: u8) -> u8 {
+    if data_length <= first_packet_length as u16 {
+        0
+    } else {
+        let rest = (data_length - first_packet_length as u16) as usize;
+        rest.div_ceil(FIDO2_MAX_CHUNK_PACKET_DATA_SIZE) as u8
+    }
+}
+
+// byte range of a chunk packet inside the whole message
+fn chunk_range(data_length: u16, first_packet_length: u8, seq_id: u8) -> (usize, usize) {
+    let start = seq_id as usize * FIDO2_MAX_CHUNK_PACKET_DATA_SIZE + first_packet_length as usize;
+    let end = core::cmp::min(
+        start + FIDO2_MAX_CHUNK_PACKET_DATA_SIZE,
+        data_length as usize,
+    );
+    (start, core::cmp::max(start, end))
+}
@@ -0,0 +1,542 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    consts::{
+        FIDO2_PIN_MAX_CONSECUTIVE_MISMATCHES, FIDO2_PIN_MAX_LENGTH, FIDO2_PIN_MAX_RETRIES,
+        FIDO2_PIN_TOKEN_USAGE_MS,
+    },
+    fido2_authenticator::{FIDO2Authenticator, FIDO2Platform},
+    fido2_cbor::{FIDO2CborReader, FIDO2CborWriter},
+    fido2_commands::FIDO2PacketCommandResponse,
+    fido2_cose::FIDO2CoseKey,
+    fido2_crypto::{ct_eq, FIDO2CryptoProvider},
+    fido2_log::FIDO2Secret,
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::FIDO2Storage,
+};
+
+#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum FIDO2ClientPinSubCommand {
+    GetPinRetries = 0x01,
+    GetKeyAgreement = 0x02,
+    SetPin = 0x03,
+    ChangePin = 0x04,
+    GetPinToken = 0x05,
+    GetPinUvAuthTokenUsingPinWithPermissions = 0x09,
+}
+
+// pinUvAuthToken permissions
+pub(crate) const FIDO2_PERMISSION_MC: u8 = 0x01;
+pub(crate) const FIDO2_PERMISSION_GA: u8 = 0x02;
+pub(crate) const FIDO2_PERMISSION_CM: u8 = 0x04;
+pub(crate) const FIDO2_PERMISSION_BE: u8 = 0x08;
+pub(crate) const FIDO2_PERMISSION_LBW: u8 = 0x10;
+pub(crate) const FIDO2_PERMISSION_ACFG: u8 = 0x20;
+
+// PIN/UV auth protocol
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum FIDO2PinProtocol {
+    One,
+    Two,
+}
+
+// protocol 1 uses the same key for both
+#[derive(Debug)]
+pub(crate) struct FIDO2SharedSecret {
+    pub hmac_key: FIDO2Secret<[u8; 32]>,
+    pub aes_key: FIDO2Secret<[u8; 32]>,
+}
+
+impl FIDO2PinProtocol {
+    pub fn from_u64(val: u64) -> Result<FIDO2PinProtocol, FIDO2StatusCode> {
+        match val {
+            1 => Ok(FIDO2PinProtocol::One),
+            2 => Ok(FIDO2PinProtocol::Two),
+            _ => Err(FIDO2StatusCode::Ctap1ErrInvalidParameter),
+        }
+    }
+    // shared secret from the x coordinate of the ECDH point
+    pub fn kdf<C: FIDO2CryptoProvider>(&self, z: &[u8; 32]) -> FIDO2SharedSecret {
+        match self {
+            FIDO2PinProtocol::One => {
+                let key = C::sha256(&[z]);
+                FIDO2SharedSecret {
+                    hmac_key: FIDO2Secret(key),
+                    aes_key: FIDO2Secret(key),
+                }
+            }
+            FIDO2PinProtocol::Two => FIDO2SharedSecret {
+                hmac_key: FIDO2Secret(C::hkdf_sha256(&[0u8; 32], z, b"CTAP2 HMAC key")),
+                aes_key: FIDO2Secret(C::hkdf_sha256(&[0u8; 32], z, b"CTAP2 AES key")),
+            },
+        }
+    }
+    // protocol 1 encrypts with a zero IV, protocol 2 prepends a random IV
+    pub fn encrypt<C: FIDO2CryptoProvider>(
+        &self,
+        crypto: &mut C,
+        key: &FIDO2SharedSecret,
+        data: &[u8],
+        out: &mut [u8],
+    ) -> Option<usize> {
+        let mut iv = [0u8; 16];
+        let offset = match self {
+            FIDO2PinProtocol::One => 0,
+            FIDO2PinProtocol::Two => {
+                crypto.fill_bytes(&mut iv);
+                16
+            }
+        };
+        let out = out.get_mut(..offset + data.len())?;
+        out[..offset].copy_from_slice(&iv[..offset]);
+        out[offset..].copy_from_slice(data);
+        C::aes256_cbc_encrypt(&key.aes_key, &iv, &mut out[offset..])?;
+        Some(out.len())
+    }
+    // returns the plaintext length
+    pub fn decrypt<C: FIDO2CryptoProvider>(
+        &self,
+        key: &FIDO2SharedSecret,
+        data: &[u8],
+        out: &mut [u8],
+    ) -> Option<usize> {
+        let (iv, data) = match self {
+            FIDO2PinProtocol::One => ([0u8; 16], data),
+            FIDO2PinProtocol::Two => (data.get(..16)?.try_into().unwrap(), &data[16..]),
+        };
+        let out = out.get_mut(..data.len())?;
+        out.copy_from_slice(data);
+        C::aes256_cbc_decrypt(&key.aes_key, &iv, out)?;
+        Some(out.len())
+    }
+    // protocol 1 signatures are truncated to 16 bytes
+    pub fn verify<C: FIDO2CryptoProvider>(&self, key: &[u8], parts: &[&[u8]], signature: &[u8]) -> bool {
+        let mac = C::hmac_sha256(key, parts);
+        match self {
+            FIDO2PinProtocol::One => ct_eq(&mac[..16], signature),
+            FIDO2PinProtocol::Two => ct_eq(&mac, signature),
+        }
+    }
+}
+
+// volatile state, everything is regenerated at power up
+#[derive(Debug)]
+pub(crate) struct FIDO2ClientPinState {
+    // private key of the key agreement
+    pub key_agreement: FIDO2Secret<[u8; 32]>,
+    pub pin_token: FIDO2Secret<[u8; 32]>,
+    // protocol the token was handed out with, None if there is no token in use
+    pub pin_token_protocol: Option<FIDO2PinProtocol>,
+    pub permissions: u8,
+    // rpIdHash the token is bound to
+    pub permissions_rp_id: Option<[u8; 32]>,
+    // the PIN was entered for the token, cleared once MakeCredential or
+    // GetAssertion used it
+    pub user_verified: bool,
+    // uptime when the token was handed out, for the usage timer
+    pub pin_token_issued_ms: u64,
+    pub consecutive_mismatches: u8,
+}
+impl FIDO2ClientPinState {
+    pub fn new(crypto: &mut impl FIDO2CryptoProvider) -> FIDO2ClientPinState {
+        let mut pin_token = [0u8; 32];
+        crypto.fill_bytes(&mut pin_token);
+        FIDO2ClientPinState {
+            key_agreement: FIDO2Secret(crypto.p256_generate()),
+            pin_token: FIDO2Secret(pin_token),
+            pin_token_protocol: None,
+            permissions: 0,
+            permissions_rp_id: None,
+            user_verified: false,
+            pin_token_issued_ms: 0,
+            consecutive_mismatches: 0,
+        }
+    }
+    pub fn regenerate(&mut self, crypto: &mut impl FIDO2CryptoProvider) {
+        *self.key_agreement = crypto.p256_generate();
+    }
+    // invalidates the current token
+    pub fn reset_pin_token(&mut self, crypto: &mut impl FIDO2CryptoProvider) {
+        crypto.fill_bytes(&mut *self.pin_token);
+        self.pin_token_protocol = None;
+        self.permissions = 0;
+        self.permissions_rp_id = None;
+        self.user_verified = false;
+    }
+    // MakeCredential and GetAssertion leave only largeBlobWrite to the token
+    pub fn clear_after_use(&mut self) {
+        self.permissions &= FIDO2_PERMISSION_LBW;
+        self.user_verified = false;
+    }
+    // pinUvAuthTokenUsageTimer, checked before every request
+    pub fn expire(&mut self, crypto: &mut impl FIDO2CryptoProvider, now_ms: u64) {
+        if self.pin_token_protocol.is_some()
+            && now_ms.saturating_sub(self.pin_token_issued_ms) > FIDO2_PIN_TOKEN_USAGE_MS
+        {
+            self.reset_pin_token(crypto);
+        }
+    }
+}
+
+// ClientPIN
+
+#[derive(Debug)]
+pub(crate) struct FIDO2ClientPinRequest<'a> {
+    pub pin_protocol: Option<u64>,
+    pub sub_command: u64,
+    pub key_agreement: Option<FIDO2CoseKey>,
+    pub pin_auth: Option<&'a [u8]>,
+    pub new_pin_enc: Option<&'a [u8]>,
+    pub pin_hash_enc: Option<&'a [u8]>,
+    pub permissions: Option<u64>,
+    pub rp_id: Option<&'a str>,
+}
+impl<'a> FIDO2ClientPinRequest<'a> {
+    pub fn unpack(packet: &'a [u8]) -> Result<FIDO2ClientPinRequest<'a>, FIDO2StatusCode> {
+        let mut r = FIDO2CborReader::new(packet);
+        let mut pin_protocol: Option<u64> = None;
+        let mut sub_command: Option<u64> = None;
+        let mut key_agreement: Option<FIDO2CoseKey> = None;
+        let mut pin_auth: Option<&[u8]> = None;
+        let mut new_pin_enc: Option<&[u8]> = None;
+        let mut pin_hash_enc: Option<&[u8]> = None;
+        let mut permissions: Option<u64> = None;
+        let mut rp_id: Option<&str> = None;
+        for _ in 0..r.map()? {
+            match r.unsigned()? {
+                0x01 => pin_protocol = Some(r.unsigned()?),
+                0x02 => sub_command = Some(r.unsigned()?),
+                0x03 => key_agreement = Some(FIDO2CoseKey::read_ecdh(&mut r)?),
+                0x04 => pin_auth = Some(r.bytes()?),
+                0x05 => new_pin_enc = Some(r.bytes()?),
+                0x06 => pin_hash_enc = Some(r.bytes()?),
+                0x09 => permissions = Some(r.unsigned()?),
+                0x0A => rp_id = Some(r.text()?),
+                _ => r.skip()?,
+            }
+        }
+        Ok(FIDO2ClientPinRequest {
+            pin_protocol,
+            sub_command: sub_command.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?,
+            key_agreement,
+            pin_auth,
+            new_pin_enc,
+            pin_hash_enc,
+            permissions,
+            rp_id,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct FIDO2ClientPinResponse<'a> {
+    pub key_agreement: Option<FIDO2CoseKey>,
+    // encrypted with the shared secret
+    pub pin_token: Option<&'a [u8]>,
+    pub retries: Option<u8>,
+}
+impl<'a> FIDO2PacketCommandResponse for FIDO2ClientPinResponse<'a> {
+    fn apply(self, arr: &mut [u8]) -> Option<u16> {
+        let mut w = FIDO2CborWriter::new(arr);
+        w.map(
+            self.key_agreement.is_some() as usize
+                + self.pin_token.is_some() as usize
+                + self.retries.is_some() as usize,
+        );
+        if let Some(key) = self.key_agreement {
+            w.unsigned(0x01);
+            key.write(&mut w);
+        }
+        if let Some(token) = self.pin_token {
+            w.unsigned(0x02).bytes(token);
+        }
+        if let Some(retries) = self.retries {
+            w.unsigned(0x03).unsigned(retries as u64);
+        }
+        w.finish()
+    }
+}
+
+// number of utf-8 code points, without decoding
+fn code_points(data: &[u8]) -> usize {
+    data.iter().filter(|b| (**b & 0xc0) != 0x80).count()
+}
+
+impl<S: FIDO2Storage, C: FIDO2CryptoProvider> FIDO2Authenticator<S, C> {
+    pub fn pin_shared_secret(
+        &mut self,
+        protocol: FIDO2PinProtocol,
+        key_agreement: &FIDO2CoseKey,
+    ) -> Result<FIDO2SharedSecret, FIDO2StatusCode> {
+        let z = match key_agreement {
+            FIDO2CoseKey::Ecdh(x, y) => C::p256_ecdh(&self.pin.key_agreement, x, y),
+            _ => None,
+        };
+        let z = z.ok_or(FIDO2StatusCode::Ctap1ErrInvalidParameter)?;
+        Ok(protocol.kdf::<C>(&z))
+    }
+    fn check_pin_blocked(&self) -> Result<(), FIDO2StatusCode> {
+        if self.state.pin_retries == 0 {
+            return Err(FIDO2StatusCode::Ctap2ErrPinBlocked);
+        }
+        if self.pin.consecutive_mismatches >= FIDO2_PIN_MAX_CONSECUTIVE_MISMATCHES {
+            return Err(FIDO2StatusCode::Ctap2ErrPinAuthBlocked);
+        }
+        Ok(())
+    }
+    // pinHashEnc against the stored PIN, the retry counter is decremented first
+    // so pulling the plug after a wrong guess doesn't give the attempt back
+    fn verify_pin_hash_enc(
+        &mut self,
+        protocol: FIDO2PinProtocol,
+        shared_secret: &FIDO2SharedSecret,
+        pin_hash_enc: &[u8],
+    ) -> Result<(), FIDO2StatusCode> {
+        let pin_hash = self.state.pin_hash.clone().ok_or(FIDO2StatusCode::Ctap2ErrPinNotSet)?;
+        self.state.pin_retries -= 1;
+        self.state.save_pin_retries(&mut self.storage)?;
+        let mut hash = [0u8; 16];
+        if protocol.decrypt::<C>(shared_secret, pin_hash_enc, &mut hash) != Some(16) {
+            return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+        }
+        if !ct_eq(&hash, &*pin_hash) {
+            self.pin.regenerate(&mut self.crypto);
+            self.pin.consecutive_mismatches += 1;
+            if self.state.pin_retries == 0 {
+                return Err(FIDO2StatusCode::Ctap2ErrPinBlocked);
+            }
+            if self.pin.consecutive_mismatches >= FIDO2_PIN_MAX_CONSECUTIVE_MISMATCHES {
+                return Err(FIDO2StatusCode::Ctap2ErrPinAuthBlocked);
+            }
+            return Err(FIDO2StatusCode::Ctap2ErrPinInvalid);
+        }
+        self.pin.consecutive_mismatches = 0;
+        self.state.pin_retries = FIDO2_PIN_MAX_RETRIES;
+        self.state.save_pin_retries(&mut self.storage)?;
+        Ok(())
+    }
+    // newPinEnc is the PIN padded with zeros to 64 bytes
+    fn set_new_pin(
+        &mut self,
+        protocol: FIDO2PinProtocol,
+        shared_secret: &FIDO2SharedSecret,
+        new_pin_enc: &[u8],
+    ) -> Result<(), FIDO2StatusCode> {
+        let mut padded = [0u8; 64];
+        if protocol.decrypt::<C>(shared_secret, new_pin_enc, &mut padded) != Some(64) {
+            return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+        }
+        let len = padded.iter().position(|b| *b == 0).unwrap_or(padded.len());
+        let pin = &padded[..len];
+        let length = code_points(pin);
+        if len > FIDO2_PIN_MAX_LENGTH || length < self.state.min_pin_length as usize {
+            return Err(FIDO2StatusCode::Ctap2ErrPinPolicyViolation);
+        }
+        let hash = C::sha256(&[pin]);
+        // a forced change has to pick a different PIN
+        if self.state.force_pin_change
+            && matches!(&self.state.pin_hash, Some(old) if ct_eq(&**old, &hash[..16]))
+        {
+            return Err(FIDO2StatusCode::Ctap2ErrPinPolicyViolation);
+        }
+        self.state.pin_hash = Some(FIDO2Secret(hash[..16].try_into().unwrap()));
+        self.state.pin_length = length as u8;
+        self.state.pin_retries = FIDO2_PIN_MAX_RETRIES;
+        self.state.force_pin_change = false;
+        self.state.save(&mut self.storage)?;
+        Ok(())
+    }
+    // pinUvAuthParam of any command that consumes the token, the token is bound
+    // to the first rpIdHash it is used with if it wasn't bound to one yet
+    pub fn verify_pin_uv_auth_token(
+        &mut self,
+        protocol: Option<u64>,
+        param: &[u8],
+        message: &[&[u8]],
+        permission: u8,
+        rp_id_hash: Option<&[u8; 32]>,
+    ) -> Result<(), FIDO2StatusCode> {
+        let protocol =
+            FIDO2PinProtocol::from_u64(protocol.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?)?;
+        if self.state.pin_hash.is_none() {
+            return Err(FIDO2StatusCode::Ctap2ErrPinNotSet);
+        }
+        if self.pin.pin_token_protocol != Some(protocol)
+            || !protocol.verify::<C>(&*self.pin.pin_token, message, param)
+        {
+            return Err(FIDO2StatusCode::Ctap2ErrPinAuthInvalid);
+        }
+        if self.pin.permissions & permission == 0 {
+            return Err(FIDO2StatusCode::Ctap2ErrPinAuthInvalid);
+        }
+        match (self.pin.permissions_rp_id, rp_id_hash) {
+            (Some(bound), Some(rp_id_hash)) if bound != *rp_id_hash => {
+                return Err(FIDO2StatusCode::Ctap2ErrPinAuthInvalid);
+            }
+            (None, Some(rp_id_hash)) => self.pin.permissions_rp_id = Some(*rp_id_hash),
+            _ => {}
+        }
+        Ok(())
+    }
+    pub fn client_pin(
+        &mut self,
+        platform: &mut impl FIDO2Platform,
+        data: &[u8],
+        out: &mut [u8],
+    ) -> Result<u16, FIDO2StatusCode> {
+        let req = FIDO2ClientPinRequest::unpack(data)?;
+        let sub_command = u8::try_from(req.sub_command)
+            .ok()
+            .and_then(|c| FIDO2ClientPinSubCommand::try_from(c).ok())
+            .ok_or(FIDO2StatusCode::Ctap2ErrInvalidSubcommand)?;
+        // every subcommand but getPINRetries needs the protocol
+        let protocol = req
+            .pin_protocol
+            .ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)
+            .and_then(FIDO2PinProtocol::from_u64);
+        match sub_command {
+            FIDO2ClientPinSubCommand::GetPinRetries => FIDO2ClientPinResponse {
+                retries: Some(self.state.pin_retries),
+                ..Default::default()
+            }
+            .apply(out)
+            .ok_or(FIDO2StatusCode::Ctap1ErrOther),
+            FIDO2ClientPinSubCommand::GetKeyAgreement => {
+                protocol?;
+                let (x, y) = C::p256_public_key(&self.pin.key_agreement)
+                    .ok_or(FIDO2StatusCode::Ctap1ErrOther)?;
+                FIDO2ClientPinResponse {
+                    key_agreement: Some(FIDO2CoseKey::Ecdh(x, y)),
+                    ..Default::default()
+                }
+                .apply(out)
+                .ok_or(FIDO2StatusCode::Ctap1ErrOther)
+            }
+            FIDO2ClientPinSubCommand::SetPin => {
+                let protocol = protocol?;
+                let (key_agreement, pin_auth, new_pin_enc) =
+                    match (&req.key_agreement, req.pin_auth, req.new_pin_enc) {
+                        (Some(k), Some(a), Some(n)) => (k, a, n),
+                        _ => return Err(FIDO2StatusCode::Ctap2ErrMissingParameter),
+                    };
+                if self.state.pin_hash.is_some() {
+                    return Err(FIDO2StatusCode::Ctap2ErrNotAllowed);
+                }
+                let shared_secret = self.pin_shared_secret(protocol, key_agreement)?;
+                if !protocol.verify::<C>(&*shared_secret.hmac_key, &[new_pin_enc], pin_auth) {
+                    return Err(FIDO2StatusCode::Ctap2ErrPinAuthInvalid);
+                }
+                self.set_new_pin(protocol, &shared_secret, new_pin_enc)?;
+                Ok(0)
+            }
+            FIDO2ClientPinSubCommand::ChangePin => {
+                let protocol = protocol?;
+                let (key_agreement, pin_auth, new_pin_enc, pin_hash_enc) = match (
+                    &req.key_agreement,
+                    req.pin_auth,
+                    req.new_pin_enc,
+                    req.pin_hash_enc,
+                ) {
+                    (Some(k), Some(a), Some(n), Some(h)) => (k, a, n, h),
+                    _ => return Err(FIDO2StatusCode::Ctap2ErrMissingParameter),
+                };
+                if self.state.pin_hash.is_none() {
+                    return Err(FIDO2StatusCode::Ctap2ErrPinNotSet);
+                }
+                self.check_pin_blocked()?;
+                let shared_secret = self.pin_shared_secret(protocol, key_agreement)?;
+                if !protocol.verify::<C>(
+                    &*shared_secret.hmac_key,
+                    &[new_pin_enc, pin_hash_enc],
+                    pin_auth,
+                ) {
+                    return Err(FIDO2StatusCode::Ctap2ErrPinAuthInvalid);
+                }
+                self.verify_pin_hash_enc(protocol, &shared_secret, pin_hash_enc)?;
+                self.set_new_pin(protocol, &shared_secret, new_pin_enc)?;
+                self.pin.reset_pin_token(&mut self.crypto);
+                Ok(0)
+            }
+            FIDO2ClientPinSubCommand::GetPinToken
+            | FIDO2ClientPinSubCommand::GetPinUvAuthTokenUsingPinWithPermissions => {
+                let protocol = protocol?;
+                let (key_agreement, pin_hash_enc) = match (&req.key_agreement, req.pin_hash_enc) {
+                    (Some(k), Some(h)) => (k, h),
+                    _ => return Err(FIDO2StatusCode::Ctap2ErrMissingParameter),
+                };
+                // getPinToken is the CTAP 2.0 way, it can only make and get credentials
+                let (permissions, rp_id) =
+                    if sub_command == FIDO2ClientPinSubCommand::GetPinToken {
+                        if req.permissions.is_some() || req.rp_id.is_some() {
+                            return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+                        }
+                        (FIDO2_PERMISSION_MC | FIDO2_PERMISSION_GA, None)
+                    } else {
+                        let permissions = req
+                            .permissions
+                            .ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?;
+                        if permissions == 0 || permissions > 0xff {
+                            return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+                        }
+                        let permissions = permissions as u8;
+                        if permissions & (FIDO2_PERMISSION_MC | FIDO2_PERMISSION_GA) != 0
+                            && req.rp_id.is_none()
+                        {
+                            return Err(FIDO2StatusCode::Ctap2ErrMissingParameter);
+                        }
+                        // there is no biometric sensor
+                        if permissions & FIDO2_PERMISSION_BE != 0 {
+                            return Err(FIDO2StatusCode::Ctap2ErrUnauthorizedPermission);
+                        }
+                        (permissions, req.rp_id)
+                    };
+                if self.state.pin_hash.is_none() {
+                    return Err(FIDO2StatusCode::Ctap2ErrPinNotSet);
+                }
+                self.check_pin_blocked()?;
+                let shared_secret = self.pin_shared_secret(protocol, key_agreement)?;
+                self.verify_pin_hash_enc(protocol, &shared_secret, pin_hash_enc)?;
+                // setMinPINLength asked for a new PIN first
+                if self.state.force_pin_change {
+                    return Err(FIDO2StatusCode::Ctap2ErrPinPolicyViolation);
+                }
+                self.pin.reset_pin_token(&mut self.crypto);
+                self.pin.pin_token_protocol = Some(protocol);
+                self.pin.permissions = permissions;
+                self.pin.permissions_rp_id = rp_id.map(|id| C::sha256(&[id.as_bytes()]));
+                self.pin.user_verified = true;
+                self.pin.pin_token_issued_ms = platform.uptime_ms();
+                let mut pin_token = [0u8; 48];
+                let len = protocol
+                    .encrypt(&mut self.crypto, &shared_secret, &*self.pin.pin_token, &mut pin_token)
+                    .ok_or(FIDO2StatusCode::Ctap1ErrOther)?;
+                FIDO2ClientPinResponse {
+                    pin_token: Some(&pin_token[..len]),
+                    ..Default::default()
+                }
+                .apply(out)
+                .ok_or(FIDO2StatusCode::Ctap1ErrOther)
+            }
+        }
+    }
+}
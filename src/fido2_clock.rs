@@ -0,0 +1,89 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use fugit::{TimerDurationU64, TimerInstantU64};
+
+// milliseconds since power up, fugit compares instants with wrapping arithmetic
+// so ordering stays correct across an overflow of the tick counter
+pub(crate) type FIDO2Instant = TimerInstantU64<1000>;
+pub(crate) type FIDO2Duration = TimerDurationU64<1000>;
+
+pub(crate) trait FIDO2Clock {
+    fn now(&mut self) -> FIDO2Instant;
+    fn now_ms(&mut self) -> u64 {
+        self.now().duration_since_epoch().to_millis()
+    }
+    // true once now is at or after the deadline
+    fn expired(&mut self, deadline: FIDO2Instant) -> bool {
+        self.now() >= deadline
+    }
+}
+
+// simulator clock, time only moves when the test says so
+#[cfg(not(target_os = "none"))]
+#[derive(Debug)]
+pub(crate) struct FIDO2ManualClock {
+    now: FIDO2Instant,
+}
+#[cfg(not(target_os = "none"))]
+impl FIDO2ManualClock {
+    pub fn new(start: FIDO2Instant) -> FIDO2ManualClock {
+        FIDO2ManualClock { now: start }
+    }
+    pub fn advance(&mut self, duration: FIDO2Duration) {
+        self.now = FIDO2Instant::from_ticks(self.now.ticks().wrapping_add(duration.ticks()));
+    }
+}
+#[cfg(not(target_os = "none"))]
+impl FIDO2Clock for FIDO2ManualClock {
+    fn now(&mut self) -> FIDO2Instant {
+        self.now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::ExtU64;
+
+    #[test]
+    fn manual_clock_expires_at_the_deadline() {
+        let mut clock = FIDO2ManualClock::new(FIDO2Instant::from_ticks(0));
+        let deadline = clock.now() + 30_000u64.millis();
+        clock.advance(29_999u64.millis());
+        assert!(!clock.expired(deadline));
+        assert_eq!(clock.now_ms(), 29_999);
+        clock.advance(1u64.millis());
+        assert!(clock.expired(deadline));
+        clock.advance(5u64.secs());
+        assert!(clock.expired(deadline));
+        assert_eq!(clock.now_ms(), 35_000);
+    }
+
+    #[test]
+    fn manual_clock_wraps_around() {
+        let mut clock = FIDO2ManualClock::new(FIDO2Instant::from_ticks(u64::MAX - 10));
+        let deadline = clock.now() + 100u64.millis();
+        assert_eq!(deadline.ticks(), 89);
+        clock.advance(50u64.millis());
+        assert_eq!(clock.now().ticks(), 39);
+        assert!(!clock.expired(deadline));
+        clock.advance(50u64.millis());
+        assert!(clock.expired(deadline));
+    }
+}
@@ -45,7 +45,7 @@ pub(crate) struct FIDO2PacketCommandPingResponse<'a> {
     pub data: &'a [u8],
 }
 impl<'a> FIDO2PacketCommandPingResponse<'a> {
-    fn new(data: &'a [u8]) -> FIDO2PacketCommandPingResponse<'a> {
+    pub fn new(data: &'a [u8]) -> FIDO2PacketCommandPingResponse<'a> {
         FIDO2PacketCommandPingResponse { data }
     }
 }
@@ -102,7 +102,7 @@ impl FIDO2PacketCommandResponse for FIDO2PacketCommandErrorResponse {
         if arr.len() < required_size {
             return None;
         }
-        arr[1] = self.code as u8;
+        arr[0] = self.code as u8;
         return Some(required_size as u16);
     }
 }
@@ -130,7 +130,7 @@ impl FIDO2PacketCommandResponse for FIDO2PacketCommandKeepAliveResponse {
         if arr.len() < required_size {
             return None;
         }
-        arr[1] = self.code as u8;
+        arr[0] = self.code as u8;
         return Some(required_size as u16);
     }
 }
@@ -178,6 +178,7 @@ impl FIDO2PacketCommandInitResponse {
             minor_version: MINOR_VERSION,
             build_version: BUILD_VERSION,
             capabilities_flag: (FIDO2Capabilities::CapabilityWink as u8
+                | FIDO2Capabilities::CapabilityCbor as u8
                 | FIDO2Capabilities::CapabilityNmsg as u8),
         }
     }
@@ -264,6 +265,37 @@ impl FIDO2PacketCommandResponse for FIDO2PacketCommandLockResponse {
     }
 }
 
+// Firmware info (vendor)
+
+#[derive(Debug)]
+pub(crate) struct FIDO2PacketCommandFirmwareInfoResponse<'a> {
+    pub device_id: [u8; 16],
+    pub build_time: &'a str,
+}
+impl<'a> FIDO2PacketCommandFirmwareInfoResponse<'a> {
+    pub fn new(device_id: [u8; 16], build_time: &'a str) -> FIDO2PacketCommandFirmwareInfoResponse<'a> {
+        FIDO2PacketCommandFirmwareInfoResponse {
+            device_id,
+            build_time,
+        }
+    }
+}
+impl<'a> FIDO2PacketCommandResponse for FIDO2PacketCommandFirmwareInfoResponse<'a> {
+    // [major: 1] [minor: 1] [build: 1] [device id: 16] [build time: ascii]
+    fn apply(self, arr: &mut [u8]) -> Option<u16> {
+        let required_size = 19 + self.build_time.len();
+        if arr.len() < required_size {
+            return None;
+        }
+        arr[0] = MAJOR_VERSION;
+        arr[1] = MINOR_VERSION;
+        arr[2] = BUILD_VERSION;
+        arr[3..19].copy_from_slice(&self.device_id);
+        arr[19..required_size].copy_from_slice(self.build_time.as_bytes());
+        return Some(required_size as u16);
+    }
+}
+
 // Msg (u2f)
 
 #[derive(Debug)]
@@ -0,0 +1,169 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    consts::{FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS, FIDO2_PIN_MAX_LENGTH},
+    fido2_authenticator::FIDO2Authenticator,
+    fido2_cbor::FIDO2CborReader,
+    fido2_client_pin::FIDO2_PERMISSION_ACFG,
+    fido2_crypto::FIDO2CryptoProvider,
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::FIDO2Storage,
+};
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum FIDO2ConfigSubCommand {
+    EnableEnterpriseAttestation = 0x01,
+    ToggleAlwaysUv = 0x02,
+    SetMinPinLength = 0x03,
+}
+
+// Config
+
+#[derive(Debug, Default)]
+pub(crate) struct FIDO2SetMinPinLengthParams {
+    pub new_min_pin_length: Option<u64>,
+    // rpIdHashes
+    pub min_pin_length_rp_ids: Option<([[u8; 32]; FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS], usize)>,
+    pub force_change_pin: bool,
+}
+impl FIDO2SetMinPinLengthParams {
+    pub fn unpack<C: FIDO2CryptoProvider>(
+        packet: &[u8],
+    ) -> Result<FIDO2SetMinPinLengthParams, FIDO2StatusCode> {
+        let mut r = FIDO2CborReader::new(packet);
+        let mut params = FIDO2SetMinPinLengthParams::default();
+        for _ in 0..r.map()? {
+            match r.unsigned()? {
+                0x01 => params.new_min_pin_length = Some(r.unsigned()?),
+                0x02 => {
+                    let mut rp_ids = [[0u8; 32]; FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS];
+                    let count = r.array()?;
+                    if count > FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS {
+                        return Err(FIDO2StatusCode::Ctap2ErrKeyStoreFull);
+                    }
+                    for v in rp_ids[..count].iter_mut() {
+                        *v = C::sha256(&[r.text()?.as_bytes()]);
+                    }
+                    params.min_pin_length_rp_ids = Some((rp_ids, count));
+                }
+                0x03 => params.force_change_pin = r.bool()?,
+                _ => r.skip()?,
+            }
+        }
+        Ok(params)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FIDO2ConfigRequest<'a> {
+    pub sub_command: u64,
+    // raw, the pinUvAuthParam is computed over the encoded map
+    pub sub_command_params: Option<&'a [u8]>,
+    pub pin_uv_auth_protocol: Option<u64>,
+    pub pin_uv_auth_param: Option<&'a [u8]>,
+}
+impl<'a> FIDO2ConfigRequest<'a> {
+    pub fn unpack(packet: &'a [u8]) -> Result<FIDO2ConfigRequest<'a>, FIDO2StatusCode> {
+        let mut r = FIDO2CborReader::new(packet);
+        let mut sub_command: Option<u64> = None;
+        let mut sub_command_params: Option<&[u8]> = None;
+        let mut pin_uv_auth_protocol: Option<u64> = None;
+        let mut pin_uv_auth_param: Option<&[u8]> = None;
+        for _ in 0..r.map()? {
+            match r.unsigned()? {
+                0x01 => sub_command = Some(r.unsigned()?),
+                0x02 => sub_command_params = Some(r.raw()?),
+                0x03 => pin_uv_auth_protocol = Some(r.unsigned()?),
+                0x04 => pin_uv_auth_param = Some(r.bytes()?),
+                _ => r.skip()?,
+            }
+        }
+        Ok(FIDO2ConfigRequest {
+            sub_command: sub_command.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?,
+            sub_command_params,
+            pin_uv_auth_protocol,
+            pin_uv_auth_param,
+        })
+    }
+}
+
+impl<S: FIDO2Storage, C: FIDO2CryptoProvider> FIDO2Authenticator<S, C> {
+    pub fn config(&mut self, data: &[u8]) -> Result<u16, FIDO2StatusCode> {
+        let req = FIDO2ConfigRequest::unpack(data)?;
+        let sub_command = u8::try_from(req.sub_command)
+            .ok()
+            .and_then(|c| FIDO2ConfigSubCommand::try_from(c).ok())
+            .ok_or(FIDO2StatusCode::Ctap2ErrInvalidSubcommand)?;
+        // anyone may configure a key without a PIN, unless alwaysUv is on
+        if self.state.pin_hash.is_some() || self.state.always_uv {
+            let param = req
+                .pin_uv_auth_param
+                .ok_or(FIDO2StatusCode::Ctap2ErrPuatRequired)?;
+            self.verify_pin_uv_auth_token(
+                req.pin_uv_auth_protocol,
+                param,
+                &[
+                    &[0xff; 32],
+                    &[0x0d, sub_command as u8],
+                    req.sub_command_params.unwrap_or(&[]),
+                ],
+                FIDO2_PERMISSION_ACFG,
+                None,
+            )?;
+        }
+        match sub_command {
+            FIDO2ConfigSubCommand::EnableEnterpriseAttestation => {
+                self.state.enterprise_attestation = true;
+            }
+            FIDO2ConfigSubCommand::ToggleAlwaysUv => {
+                self.state.always_uv = !self.state.always_uv;
+            }
+            FIDO2ConfigSubCommand::SetMinPinLength => {
+                let params = match req.sub_command_params {
+                    Some(p) => FIDO2SetMinPinLengthParams::unpack::<C>(p)?,
+                    None => FIDO2SetMinPinLengthParams::default(),
+                };
+                let current = self.state.min_pin_length as u64;
+                let length = params.new_min_pin_length.unwrap_or(current);
+                // the minimum can only go up, until the next authenticatorReset
+                if length < current || length > FIDO2_PIN_MAX_LENGTH as u64 {
+                    return Err(FIDO2StatusCode::Ctap2ErrPinPolicyViolation);
+                }
+                if params.force_change_pin && self.state.pin_hash.is_none() {
+                    return Err(FIDO2StatusCode::Ctap2ErrPinNotSet);
+                }
+                self.state.min_pin_length = length as u8;
+                if let Some((rp_ids, count)) = params.min_pin_length_rp_ids {
+                    self.state.min_pin_length_rp_ids = rp_ids;
+                    self.state.min_pin_length_rp_id_count = count;
+                }
+                if params.force_change_pin
+                    || (self.state.pin_hash.is_some() && self.state.pin_length < length as u8)
+                {
+                    self.state.force_pin_change = true;
+                }
+            }
+        }
+        self.state.save(&mut self.storage)?;
+        Ok(0)
+    }
+}
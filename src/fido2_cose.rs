@@ -0,0 +1,104 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    fido2_cbor::{FIDO2CborReader, FIDO2CborWriter},
+    fido2_status_code::FIDO2StatusCode,
+};
+
+// COSE algorithm identifiers
+pub(crate) const COSE_ALG_ES256: i32 = -7;
+pub(crate) const COSE_ALG_EDDSA: i32 = -8;
+pub(crate) const COSE_ALG_ECDH_ES_HKDF_256: i32 = -25;
+// credential algorithms, in the order of preference of GetInfo
+pub(crate) const FIDO2_SUPPORTED_ALGORITHMS: [i32; 2] = [COSE_ALG_ES256, COSE_ALG_EDDSA];
+
+// COSE_Key labels
+const COSE_KEY_KTY: i64 = 1;
+const COSE_KEY_ALG: i64 = 3;
+const COSE_KEY_CRV: i64 = -1;
+const COSE_KEY_X: i64 = -2;
+const COSE_KEY_Y: i64 = -3;
+
+const COSE_KTY_OKP: i64 = 1;
+const COSE_KTY_EC2: i64 = 2;
+const COSE_CRV_P256: i64 = 1;
+const COSE_CRV_ED25519: i64 = 6;
+
+#[derive(Debug)]
+pub(crate) enum FIDO2CoseKey {
+    // EC2 P-256 public key (x, y)
+    ES256([u8; 32], [u8; 32]),
+    // OKP Ed25519 public key (x)
+    EdDSA([u8; 32]),
+    // EC2 P-256 key agreement key (x, y) of the client pin
+    Ecdh([u8; 32], [u8; 32]),
+}
+impl FIDO2CoseKey {
+    pub fn write(&self, w: &mut FIDO2CborWriter) {
+        match self {
+            FIDO2CoseKey::ES256(x, y) => {
+                w.map(5);
+                w.int(COSE_KEY_KTY).int(COSE_KTY_EC2);
+                w.int(COSE_KEY_ALG).int(COSE_ALG_ES256 as i64);
+                w.int(COSE_KEY_CRV).int(COSE_CRV_P256);
+                w.int(COSE_KEY_X).bytes(x);
+                w.int(COSE_KEY_Y).bytes(y);
+            }
+            FIDO2CoseKey::EdDSA(x) => {
+                w.map(4);
+                w.int(COSE_KEY_KTY).int(COSE_KTY_OKP);
+                w.int(COSE_KEY_ALG).int(COSE_ALG_EDDSA as i64);
+                w.int(COSE_KEY_CRV).int(COSE_CRV_ED25519);
+                w.int(COSE_KEY_X).bytes(x);
+            }
+            FIDO2CoseKey::Ecdh(x, y) => {
+                w.map(5);
+                w.int(COSE_KEY_KTY).int(COSE_KTY_EC2);
+                w.int(COSE_KEY_ALG).int(COSE_ALG_ECDH_ES_HKDF_256 as i64);
+                w.int(COSE_KEY_CRV).int(COSE_CRV_P256);
+                w.int(COSE_KEY_X).bytes(x);
+                w.int(COSE_KEY_Y).bytes(y);
+            }
+        }
+    }
+    // key agreement key of the platform, the algorithm is ignored
+    pub fn read_ecdh(r: &mut FIDO2CborReader) -> Result<FIDO2CoseKey, FIDO2StatusCode> {
+        let mut kty: Option<i64> = None;
+        let mut crv: Option<i64> = None;
+        let mut x: Option<&[u8]> = None;
+        let mut y: Option<&[u8]> = None;
+        for _ in 0..r.map()? {
+            match r.int()? {
+                COSE_KEY_KTY => kty = Some(r.int()?),
+                COSE_KEY_CRV => crv = Some(r.int()?),
+                COSE_KEY_X => x = Some(r.bytes()?),
+                COSE_KEY_Y => y = Some(r.bytes()?),
+                _ => r.skip()?,
+            }
+        }
+        let (x, y) = match (x, y) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return Err(FIDO2StatusCode::Ctap2ErrMissingParameter),
+        };
+        if kty != Some(COSE_KTY_EC2) || crv != Some(COSE_CRV_P256) || x.len() != 32 || y.len() != 32 {
+            return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+        }
+        Ok(FIDO2CoseKey::Ecdh(x.try_into().unwrap(), y.try_into().unwrap()))
+    }
+}
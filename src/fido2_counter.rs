@@ -0,0 +1,79 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{
+    consts::{FIDO2_STORAGE_COUNTER_PAGE, FIDO2_STORAGE_PAGE_SIZE},
+    fido2_device_state::FIDO2DeviceState,
+    fido2_internal_error::FIDO2InternalError,
+    fido2_storage::{page_offset, FIDO2Storage},
+};
+
+// every increment appends the new value to the counter page, so the page is
+// only erased once per 256 signatures. before erasing, the value is saved in
+// the device state so it never goes backwards
+const COUNTER_SLOTS: u32 = FIDO2_STORAGE_PAGE_SIZE / 4;
+
+#[derive(Debug)]
+pub(crate) struct FIDO2SignatureCounter {
+    pub value: u32,
+    pub next_slot: u32,
+}
+impl FIDO2SignatureCounter {
+    pub fn load(
+        storage: &mut impl FIDO2Storage,
+        state: &FIDO2DeviceState,
+    ) -> Result<FIDO2SignatureCounter, FIDO2InternalError> {
+        let mut value = state.counter_base;
+        let mut next_slot = 0;
+        while next_slot < COUNTER_SLOTS {
+            let mut raw = [0u8; 4];
+            storage.read(page_offset(FIDO2_STORAGE_COUNTER_PAGE) + next_slot * 4, &mut raw)?;
+            let v = LittleEndian::read_u32(&raw);
+            if v == 0xffffffff {
+                break;
+            }
+            value = core::cmp::max(value, v);
+            next_slot += 1;
+        }
+        Ok(FIDO2SignatureCounter { value, next_slot })
+    }
+    pub fn increment(
+        &mut self,
+        storage: &mut impl FIDO2Storage,
+        state: &mut FIDO2DeviceState,
+    ) -> Result<u32, FIDO2InternalError> {
+        let value = self.value + 1;
+        if self.next_slot >= COUNTER_SLOTS {
+            state.counter_base = value;
+            state.save(storage)?;
+            storage.erase_page(FIDO2_STORAGE_COUNTER_PAGE)?;
+            self.next_slot = 0;
+        }
+        let mut raw = [0u8; 4];
+        LittleEndian::write_u32(&mut raw, value);
+        storage.write(
+            page_offset(FIDO2_STORAGE_COUNTER_PAGE) + self.next_slot * 4,
+            &raw,
+        )?;
+        self.next_slot += 1;
+        self.value = value;
+        Ok(value)
+    }
+}
@@ -0,0 +1,387 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use num_enum::TryFromPrimitive;
+
+use crate::{
+    fido2_authenticator::FIDO2Authenticator,
+    fido2_cbor::{FIDO2CborReader, FIDO2CborWriter},
+    fido2_client_pin::FIDO2_PERMISSION_CM,
+    fido2_commands::FIDO2PacketCommandResponse,
+    fido2_cose::FIDO2CoseKey,
+    fido2_credential_store::{FIDO2ResidentCredential, FIDO2_MAX_RESIDENT_CREDENTIALS},
+    fido2_crypto::FIDO2CryptoProvider,
+    fido2_key_wrap::unwrap,
+    fido2_make_credential::read_credential_descriptor,
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::FIDO2Storage,
+    utils::FIDO2Bytes,
+};
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, TryFromPrimitive)]
+#[repr(u8)]
+pub(crate) enum FIDO2CredentialManagementSubCommand {
+    GetCredsMetadata = 0x01,
+    EnumerateRPsBegin = 0x02,
+    EnumerateRPsGetNextRP = 0x03,
+    EnumerateCredentialsBegin = 0x04,
+    EnumerateCredentialsGetNextCredential = 0x05,
+    DeleteCredential = 0x06,
+    UpdateUserInformation = 0x07,
+}
+
+// CredentialManagement
+
+#[derive(Debug, Default)]
+pub(crate) struct FIDO2CredentialManagementParams<'a> {
+    pub rp_id_hash: Option<&'a [u8]>,
+    pub credential_id: Option<&'a [u8]>,
+    // id, name, displayName
+    pub user: Option<(&'a [u8], &'a str, &'a str)>,
+}
+impl<'a> FIDO2CredentialManagementParams<'a> {
+    pub fn unpack(packet: &'a [u8]) -> Result<FIDO2CredentialManagementParams<'a>, FIDO2StatusCode> {
+        let mut r = FIDO2CborReader::new(packet);
+        let mut params = FIDO2CredentialManagementParams::default();
+        for _ in 0..r.map()? {
+            match r.unsigned()? {
+                0x01 => params.rp_id_hash = Some(r.bytes()?),
+                0x02 => params.credential_id = read_credential_descriptor(&mut r)?,
+                0x03 => {
+                    let mut id: Option<&[u8]> = None;
+                    let mut name = "";
+                    let mut display_name = "";
+                    for _ in 0..r.map()? {
+                        match r.text()? {
+                            "id" => id = Some(r.bytes()?),
+                            "name" => name = r.text()?,
+                            "displayName" => display_name = r.text()?,
+                            _ => r.skip()?,
+                        }
+                    }
+                    let id = id.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?;
+                    params.user = Some((id, name, display_name));
+                }
+                _ => r.skip()?,
+            }
+        }
+        Ok(params)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FIDO2CredentialManagementRequest<'a> {
+    pub sub_command: u64,
+    // raw, the pinUvAuthParam is computed over the encoded map
+    pub sub_command_params: Option<&'a [u8]>,
+    pub pin_uv_auth_protocol: Option<u64>,
+    pub pin_uv_auth_param: Option<&'a [u8]>,
+}
+impl<'a> FIDO2CredentialManagementRequest<'a> {
+    pub fn unpack(packet: &'a [u8]) -> Result<FIDO2CredentialManagementRequest<'a>, FIDO2StatusCode> {
+        let mut r = FIDO2CborReader::new(packet);
+        let mut sub_command: Option<u64> = None;
+        let mut sub_command_params: Option<&[u8]> = None;
+        let mut pin_uv_auth_protocol: Option<u64> = None;
+        let mut pin_uv_auth_param: Option<&[u8]> = None;
+        for _ in 0..r.map()? {
+            match r.unsigned()? {
+                0x01 => sub_command = Some(r.unsigned()?),
+                0x02 => sub_command_params = Some(r.raw()?),
+                0x03 => pin_uv_auth_protocol = Some(r.unsigned()?),
+                0x04 => pin_uv_auth_param = Some(r.bytes()?),
+                _ => r.skip()?,
+            }
+        }
+        Ok(FIDO2CredentialManagementRequest {
+            sub_command: sub_command.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?,
+            sub_command_params,
+            pin_uv_auth_protocol,
+            pin_uv_auth_param,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct FIDO2CredentialManagementResponse<'a> {
+    // existing and remaining resident credentials
+    pub metadata: Option<(usize, usize)>,
+    // the rp and rpIdHash are taken from one of its credentials
+    pub rp: Option<&'a FIDO2ResidentCredential>,
+    pub total_rps: Option<usize>,
+    pub credential: Option<(&'a FIDO2ResidentCredential, FIDO2CoseKey)>,
+    pub total_credentials: Option<usize>,
+}
+impl<'a> FIDO2PacketCommandResponse for FIDO2CredentialManagementResponse<'a> {
+    fn apply(self, arr: &mut [u8]) -> Option<u16> {
+        let cred_protect = match &self.credential {
+            Some((cred, _)) => cred.cred_protect != 0,
+            None => false,
+        };
+        let mut w = FIDO2CborWriter::new(arr);
+        w.map(
+            self.metadata.is_some() as usize * 2
+                + self.rp.is_some() as usize * 2
+                + self.total_rps.is_some() as usize
+                + self.credential.is_some() as usize * 3
+                + self.total_credentials.is_some() as usize
+                + cred_protect as usize,
+        );
+        if let Some((existing, remaining)) = self.metadata {
+            w.unsigned(0x01).unsigned(existing as u64);
+            w.unsigned(0x02).unsigned(remaining as u64);
+        }
+        if let Some(rp) = self.rp {
+            w.unsigned(0x03).map(1);
+            w.text("id").text(rp.rp_id.as_str());
+            w.unsigned(0x04).bytes(&rp.rp_id_hash);
+        }
+        if let Some(n) = self.total_rps {
+            w.unsigned(0x05).unsigned(n as u64);
+        }
+        if let Some((cred, key)) = &self.credential {
+            w.unsigned(0x06);
+            cred.write_user(&mut w, true);
+            w.unsigned(0x07).map(2);
+            w.text("id").bytes(cred.credential_id.as_slice());
+            w.text("type").text("public-key");
+            w.unsigned(0x08);
+            key.write(&mut w);
+        }
+        if let Some(n) = self.total_credentials {
+            w.unsigned(0x09).unsigned(n as u64);
+        }
+        if let Some((cred, _)) = &self.credential {
+            if cred_protect {
+                w.unsigned(0x0A).unsigned(cred.cred_protect as u64);
+            }
+        }
+        w.finish()
+    }
+}
+
+// enumerateRPsGetNextRP / enumerateCredentialsGetNextCredential
+
+#[derive(Debug)]
+pub(crate) struct FIDO2EnumerationState {
+    pub rps: bool,
+    // a credential slot per rp, or the credential slots of one rp
+    pub slots: [u8; FIDO2_MAX_RESIDENT_CREDENTIALS],
+    pub count: usize,
+    pub next: usize,
+}
+
+impl<S: FIDO2Storage, C: FIDO2CryptoProvider> FIDO2Authenticator<S, C> {
+    fn credential_public_key(
+        &self,
+        cred: &FIDO2ResidentCredential,
+    ) -> Result<FIDO2CoseKey, FIDO2StatusCode> {
+        let key = unwrap::<C>(
+            &self.state.master_secret,
+            cred.credential_id.as_slice(),
+            &cred.rp_id_hash,
+        )
+        .ok_or(FIDO2StatusCode::Ctap2ErrInvalidCredential)?;
+        key.public_key::<C>().ok_or(FIDO2StatusCode::Ctap1ErrOther)
+    }
+    // next rp or credential of the enumeration, the state is kept until it runs out
+    fn enumeration_next(
+        &mut self,
+        mut state: FIDO2EnumerationState,
+        out: &mut [u8],
+    ) -> Result<u16, FIDO2StatusCode> {
+        let first = state.next == 0;
+        let slot = state.slots[state.next] as usize;
+        state.next += 1;
+        let cred = self
+            .credentials
+            .read(&mut self.storage, slot)?
+            .ok_or(FIDO2StatusCode::Ctap2ErrNoCredentials)?;
+        let resp = if state.rps {
+            FIDO2CredentialManagementResponse {
+                rp: Some(&cred),
+                total_rps: if first { Some(state.count) } else { None },
+                ..Default::default()
+            }
+        } else {
+            FIDO2CredentialManagementResponse {
+                credential: Some((&cred, self.credential_public_key(&cred)?)),
+                total_credentials: if first { Some(state.count) } else { None },
+                ..Default::default()
+            }
+        };
+        let length = resp.apply(out).ok_or(FIDO2StatusCode::Ctap1ErrOther)?;
+        if state.next < state.count {
+            self.enumeration = Some(state);
+        }
+        Ok(length)
+    }
+    // the credential a subcommand refers to, with rpId binding of the token
+    fn managed_credential(
+        &mut self,
+        credential_id: Option<&[u8]>,
+    ) -> Result<(usize, FIDO2ResidentCredential), FIDO2StatusCode> {
+        let credential_id = credential_id.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?;
+        let slot = self
+            .credentials
+            .find_credential_id(&mut self.storage, credential_id)?
+            .ok_or(FIDO2StatusCode::Ctap2ErrNoCredentials)?;
+        let cred = self
+            .credentials
+            .read(&mut self.storage, slot)?
+            .ok_or(FIDO2StatusCode::Ctap2ErrNoCredentials)?;
+        if let Some(rp_id_hash) = self.pin.permissions_rp_id {
+            if rp_id_hash != cred.rp_id_hash {
+                return Err(FIDO2StatusCode::Ctap2ErrPinAuthInvalid);
+            }
+        }
+        Ok((slot, cred))
+    }
+    pub fn credential_management(
+        &mut self,
+        data: &[u8],
+        out: &mut [u8],
+    ) -> Result<u16, FIDO2StatusCode> {
+        let req = FIDO2CredentialManagementRequest::unpack(data)?;
+        let enumeration = self.enumeration.take();
+        let sub_command = u8::try_from(req.sub_command)
+            .ok()
+            .and_then(|c| FIDO2CredentialManagementSubCommand::try_from(c).ok())
+            .ok_or(FIDO2StatusCode::Ctap2ErrInvalidSubcommand)?;
+        let params = match req.sub_command_params {
+            Some(raw) => FIDO2CredentialManagementParams::unpack(raw)?,
+            None => FIDO2CredentialManagementParams::default(),
+        };
+        // the GetNext subcommands continue the previous Begin, everything else
+        // needs a token with the cm permission
+        match sub_command {
+            FIDO2CredentialManagementSubCommand::EnumerateRPsGetNextRP
+            | FIDO2CredentialManagementSubCommand::EnumerateCredentialsGetNextCredential => {
+                let rps = sub_command == FIDO2CredentialManagementSubCommand::EnumerateRPsGetNextRP;
+                return match enumeration {
+                    Some(state) if state.rps == rps => self.enumeration_next(state, out),
+                    _ => Err(FIDO2StatusCode::Ctap2ErrNotAllowed),
+                };
+            }
+            _ => {
+                let param = req
+                    .pin_uv_auth_param
+                    .ok_or(FIDO2StatusCode::Ctap2ErrPuatRequired)?;
+                let message: &[&[u8]] = &[
+                    &[sub_command as u8],
+                    req.sub_command_params.unwrap_or(&[]),
+                ];
+                self.verify_pin_uv_auth_token(
+                    req.pin_uv_auth_protocol,
+                    param,
+                    message,
+                    FIDO2_PERMISSION_CM,
+                    None,
+                )?;
+            }
+        }
+        match sub_command {
+            FIDO2CredentialManagementSubCommand::GetCredsMetadata
+            | FIDO2CredentialManagementSubCommand::EnumerateRPsBegin
+                if self.pin.permissions_rp_id.is_some() =>
+            {
+                Err(FIDO2StatusCode::Ctap2ErrPinAuthInvalid)
+            }
+            FIDO2CredentialManagementSubCommand::GetCredsMetadata => {
+                FIDO2CredentialManagementResponse {
+                    metadata: Some((
+                        self.credentials.count(&mut self.storage)?,
+                        self.credentials.remaining(&mut self.storage)?,
+                    )),
+                    ..Default::default()
+                }
+                .apply(out)
+                .ok_or(FIDO2StatusCode::Ctap1ErrOther)
+            }
+            FIDO2CredentialManagementSubCommand::EnumerateRPsBegin => {
+                // one credential of every rp
+                let mut rp_id_hashes = [[0u8; 32]; FIDO2_MAX_RESIDENT_CREDENTIALS];
+                let mut state = FIDO2EnumerationState {
+                    rps: true,
+                    slots: [0u8; FIDO2_MAX_RESIDENT_CREDENTIALS],
+                    count: 0,
+                    next: 0,
+                };
+                for slot in 0..FIDO2_MAX_RESIDENT_CREDENTIALS {
+                    if let Some(cred) = self.credentials.read(&mut self.storage, slot)? {
+                        if !rp_id_hashes[..state.count].contains(&cred.rp_id_hash) {
+                            rp_id_hashes[state.count] = cred.rp_id_hash;
+                            state.slots[state.count] = slot as u8;
+                            state.count += 1;
+                        }
+                    }
+                }
+                if state.count == 0 {
+                    return Err(FIDO2StatusCode::Ctap2ErrNoCredentials);
+                }
+                self.enumeration_next(state, out)
+            }
+            FIDO2CredentialManagementSubCommand::EnumerateCredentialsBegin => {
+                let rp_id_hash: [u8; 32] = params
+                    .rp_id_hash
+                    .ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?
+                    .try_into()
+                    .map_err(|_| FIDO2StatusCode::Ctap1ErrInvalidParameter)?;
+                if let Some(bound) = self.pin.permissions_rp_id {
+                    if bound != rp_id_hash {
+                        return Err(FIDO2StatusCode::Ctap2ErrPinAuthInvalid);
+                    }
+                }
+                let mut state = FIDO2EnumerationState {
+                    rps: false,
+                    slots: [0u8; FIDO2_MAX_RESIDENT_CREDENTIALS],
+                    count: 0,
+                    next: 0,
+                };
+                state.count = self
+                    .credentials
+                    .find(&mut self.storage, &rp_id_hash, &mut state.slots)?;
+                if state.count == 0 {
+                    return Err(FIDO2StatusCode::Ctap2ErrNoCredentials);
+                }
+                self.enumeration_next(state, out)
+            }
+            FIDO2CredentialManagementSubCommand::DeleteCredential => {
+                let (slot, _) = self.managed_credential(params.credential_id)?;
+                self.credentials.delete(&mut self.storage, slot)?;
+                Ok(0)
+            }
+            FIDO2CredentialManagementSubCommand::UpdateUserInformation => {
+                let (_, mut cred) = self.managed_credential(params.credential_id)?;
+                let (user_id, user_name, display_name) =
+                    params.user.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?;
+                if user_id != cred.user_id.as_slice() {
+                    return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+                }
+                cred.user_name = FIDO2Bytes::from_str(user_name);
+                cred.display_name = FIDO2Bytes::from_str(display_name);
+                self.credentials.store(&mut self.storage, &mut cred)?;
+                Ok(0)
+            }
+            FIDO2CredentialManagementSubCommand::EnumerateRPsGetNextRP
+            | FIDO2CredentialManagementSubCommand::EnumerateCredentialsGetNextCredential => {
+                Err(FIDO2StatusCode::Ctap2ErrNotAllowed)
+            }
+        }
+    }
+}
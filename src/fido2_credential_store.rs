@@ -0,0 +1,328 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{
+    consts::{
+        FIDO2_MAX_CREDENTIAL_ID_LENGTH, FIDO2_MAX_CRED_BLOB_LENGTH, FIDO2_MAX_RP_ID_LENGTH, FIDO2_MAX_USER_ID_LENGTH,
+        FIDO2_MAX_USER_NAME_LENGTH, FIDO2_STORAGE_CREDENTIAL_PAGE, FIDO2_STORAGE_CREDENTIAL_PAGES,
+        FIDO2_STORAGE_PAGE_SIZE,
+    },
+    fido2_cbor::FIDO2CborWriter,
+    fido2_internal_error::FIDO2InternalError,
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::{is_erased, page_offset, FIDO2Storage},
+    utils::FIDO2Bytes,
+};
+
+// one credential per flash page, so deleting a credential is a single page
+// erase and never touches another credential. a page is written body first
+// and marker last, a page without the marker is garbage and erased before reuse
+// [marker: 2] [version: 2] [creation order: 4] [rpIdHash: 32] [credProtect: 1]
+// [lengths: 6] [reserved: 1] [credential id: 80] [rp id: 64] [user id: 64]
+// [user name: 64] [display name: 64] [credBlob: 32]
+const RECORD_SIZE: usize = 512;
+const RECORD_MARKER: u16 = 0x5AA5;
+const RECORD_VERSION: u16 = 1;
+const CREATION_ORDER: usize = 4;
+const RP_ID_HASH: usize = 8;
+const CRED_PROTECT: usize = 40;
+const LENGTHS: usize = 41;
+const CREDENTIAL_ID: usize = 48;
+const RP_ID: usize = CREDENTIAL_ID + FIDO2_MAX_CREDENTIAL_ID_LENGTH;
+const USER_ID: usize = RP_ID + FIDO2_MAX_RP_ID_LENGTH;
+const USER_NAME: usize = USER_ID + FIDO2_MAX_USER_ID_LENGTH;
+const DISPLAY_NAME: usize = USER_NAME + FIDO2_MAX_USER_NAME_LENGTH;
+const CRED_BLOB: usize = DISPLAY_NAME + FIDO2_MAX_USER_NAME_LENGTH;
+// records written before credBlob have 0xff as its length
+const CRED_BLOB_LENGTH: usize = LENGTHS + 5;
+
+pub(crate) const FIDO2_MAX_RESIDENT_CREDENTIALS: usize = FIDO2_STORAGE_CREDENTIAL_PAGES as usize;
+
+#[derive(Debug, Clone)]
+pub(crate) struct FIDO2ResidentCredential {
+    pub creation_order: u32,
+    pub rp_id_hash: [u8; 32],
+    pub cred_protect: u8,
+    // wrapped private key, see fido2_key_wrap
+    pub credential_id: FIDO2Bytes<FIDO2_MAX_CREDENTIAL_ID_LENGTH>,
+    pub rp_id: FIDO2Bytes<FIDO2_MAX_RP_ID_LENGTH>,
+    pub user_id: FIDO2Bytes<FIDO2_MAX_USER_ID_LENGTH>,
+    pub user_name: FIDO2Bytes<FIDO2_MAX_USER_NAME_LENGTH>,
+    pub display_name: FIDO2Bytes<FIDO2_MAX_USER_NAME_LENGTH>,
+    pub cred_blob: FIDO2Bytes<FIDO2_MAX_CRED_BLOB_LENGTH>,
+}
+impl FIDO2ResidentCredential {
+    fn unpack(raw: &[u8; RECORD_SIZE]) -> Option<FIDO2ResidentCredential> {
+        if LittleEndian::read_u16(&raw[0..2]) != RECORD_MARKER
+            || LittleEndian::read_u16(&raw[2..4]) != RECORD_VERSION
+        {
+            return None;
+        }
+        let field = |offset: usize, len_index: usize, max: usize| -> &[u8] {
+            let len = core::cmp::min(raw[LENGTHS + len_index] as usize, max);
+            &raw[offset..offset + len]
+        };
+        Some(FIDO2ResidentCredential {
+            creation_order: LittleEndian::read_u32(&raw[CREATION_ORDER..CREATION_ORDER + 4]),
+            rp_id_hash: raw[RP_ID_HASH..RP_ID_HASH + 32].try_into().unwrap(),
+            cred_protect: raw[CRED_PROTECT],
+            credential_id: FIDO2Bytes::from_slice(field(
+                CREDENTIAL_ID,
+                0,
+                FIDO2_MAX_CREDENTIAL_ID_LENGTH,
+            )),
+            rp_id: FIDO2Bytes::from_slice(field(RP_ID, 1, FIDO2_MAX_RP_ID_LENGTH)),
+            user_id: FIDO2Bytes::from_slice(field(USER_ID, 2, FIDO2_MAX_USER_ID_LENGTH)),
+            user_name: FIDO2Bytes::from_slice(field(USER_NAME, 3, FIDO2_MAX_USER_NAME_LENGTH)),
+            display_name: FIDO2Bytes::from_slice(field(
+                DISPLAY_NAME,
+                4,
+                FIDO2_MAX_USER_NAME_LENGTH,
+            )),
+            cred_blob: if raw[CRED_BLOB_LENGTH] == 0xff {
+                FIDO2Bytes::new()
+            } else {
+                FIDO2Bytes::from_slice(field(CRED_BLOB, 5, FIDO2_MAX_CRED_BLOB_LENGTH))
+            },
+        })
+    }
+    fn pack(&self) -> [u8; RECORD_SIZE] {
+        let mut raw = [0xffu8; RECORD_SIZE];
+        LittleEndian::write_u16(&mut raw[0..2], RECORD_MARKER);
+        LittleEndian::write_u16(&mut raw[2..4], RECORD_VERSION);
+        LittleEndian::write_u32(&mut raw[CREATION_ORDER..CREATION_ORDER + 4], self.creation_order);
+        raw[RP_ID_HASH..RP_ID_HASH + 32].copy_from_slice(&self.rp_id_hash);
+        raw[CRED_PROTECT] = self.cred_protect;
+        let fields: [(usize, &[u8]); 6] = [
+            (CREDENTIAL_ID, self.credential_id.as_slice()),
+            (RP_ID, self.rp_id.as_slice()),
+            (USER_ID, self.user_id.as_slice()),
+            (USER_NAME, self.user_name.as_slice()),
+            (DISPLAY_NAME, self.display_name.as_slice()),
+            (CRED_BLOB, self.cred_blob.as_slice()),
+        ];
+        for (k, (offset, v)) in fields.iter().enumerate() {
+            raw[LENGTHS + k] = v.len() as u8;
+            raw[*offset..*offset + v.len()].copy_from_slice(v);
+        }
+        raw
+    }
+    // PublicKeyCredentialUserEntity, the names are left out unless details is set
+    pub fn write_user(&self, w: &mut FIDO2CborWriter, details: bool) {
+        let name = details && !self.user_name.is_empty();
+        let display_name = details && !self.display_name.is_empty();
+        w.map(1 + name as usize + display_name as usize);
+        w.text("id").bytes(self.user_id.as_slice());
+        if name {
+            w.text("name").text(self.user_name.as_str());
+        }
+        if display_name {
+            w.text("displayName").text(self.display_name.as_str());
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum FIDO2CredentialSlot {
+    Free,
+    Valid,
+    // partially written or partially erased
+    Garbage,
+}
+
+#[derive(Debug)]
+pub(crate) struct FIDO2CredentialStore {
+    pub next_creation_order: u32,
+}
+impl FIDO2CredentialStore {
+    fn slot_offset(slot: usize) -> u32 {
+        page_offset(FIDO2_STORAGE_CREDENTIAL_PAGE + slot as u32)
+    }
+    pub fn load(storage: &mut impl FIDO2Storage) -> Result<FIDO2CredentialStore, FIDO2InternalError> {
+        let mut store = FIDO2CredentialStore {
+            next_creation_order: 0,
+        };
+        for slot in 0..FIDO2_MAX_RESIDENT_CREDENTIALS {
+            if let Some(cred) = store.read(storage, slot)? {
+                store.next_creation_order =
+                    core::cmp::max(store.next_creation_order, cred.creation_order + 1);
+            }
+        }
+        // a power loss while replacing a credential leaves both copies, keep the newer one
+        for slot in 0..FIDO2_MAX_RESIDENT_CREDENTIALS {
+            if let Some(cred) = store.read(storage, slot)? {
+                if let Some(newer) = store.find_user(storage, &cred.rp_id_hash, cred.user_id.as_slice())? {
+                    if newer != slot {
+                        store.delete(storage, slot)?;
+                    }
+                }
+            }
+        }
+        Ok(store)
+    }
+    pub fn slot(
+        &self,
+        storage: &mut impl FIDO2Storage,
+        slot: usize,
+    ) -> Result<FIDO2CredentialSlot, FIDO2InternalError> {
+        let mut marker = [0u8; 2];
+        storage.read(Self::slot_offset(slot), &mut marker)?;
+        if LittleEndian::read_u16(&marker) == RECORD_MARKER {
+            return Ok(FIDO2CredentialSlot::Valid);
+        }
+        if is_erased(storage, Self::slot_offset(slot), FIDO2_STORAGE_PAGE_SIZE)? {
+            return Ok(FIDO2CredentialSlot::Free);
+        }
+        Ok(FIDO2CredentialSlot::Garbage)
+    }
+    pub fn read(
+        &self,
+        storage: &mut impl FIDO2Storage,
+        slot: usize,
+    ) -> Result<Option<FIDO2ResidentCredential>, FIDO2InternalError> {
+        if slot >= FIDO2_MAX_RESIDENT_CREDENTIALS {
+            return Ok(None);
+        }
+        let mut raw = [0u8; RECORD_SIZE];
+        storage.read(Self::slot_offset(slot), &mut raw)?;
+        Ok(FIDO2ResidentCredential::unpack(&raw))
+    }
+    pub fn count(&self, storage: &mut impl FIDO2Storage) -> Result<usize, FIDO2InternalError> {
+        let mut n = 0;
+        for slot in 0..FIDO2_MAX_RESIDENT_CREDENTIALS {
+            if self.slot(storage, slot)? == FIDO2CredentialSlot::Valid {
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+    pub fn remaining(&self, storage: &mut impl FIDO2Storage) -> Result<usize, FIDO2InternalError> {
+        Ok(FIDO2_MAX_RESIDENT_CREDENTIALS - self.count(storage)?)
+    }
+    // slots of all credentials of an rp, newest first
+    pub fn find(
+        &self,
+        storage: &mut impl FIDO2Storage,
+        rp_id_hash: &[u8; 32],
+        out: &mut [u8; FIDO2_MAX_RESIDENT_CREDENTIALS],
+    ) -> Result<usize, FIDO2InternalError> {
+        let mut orders = [0u32; FIDO2_MAX_RESIDENT_CREDENTIALS];
+        let mut n = 0;
+        for slot in 0..FIDO2_MAX_RESIDENT_CREDENTIALS {
+            if let Some(cred) = self.read(storage, slot)? {
+                if &cred.rp_id_hash != rp_id_hash {
+                    continue;
+                }
+                // insertion sort by creation order, descending
+                let mut i = n;
+                while i > 0 && orders[i - 1] < cred.creation_order {
+                    orders[i] = orders[i - 1];
+                    out[i] = out[i - 1];
+                    i -= 1;
+                }
+                orders[i] = cred.creation_order;
+                out[i] = slot as u8;
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+    // the newest credential of a user account
+    pub fn find_user(
+        &self,
+        storage: &mut impl FIDO2Storage,
+        rp_id_hash: &[u8; 32],
+        user_id: &[u8],
+    ) -> Result<Option<usize>, FIDO2InternalError> {
+        let mut found: Option<(usize, u32)> = None;
+        for slot in 0..FIDO2_MAX_RESIDENT_CREDENTIALS {
+            if let Some(cred) = self.read(storage, slot)? {
+                if &cred.rp_id_hash == rp_id_hash
+                    && cred.user_id.as_slice() == user_id
+                    && found.is_none_or(|(_, order)| cred.creation_order > order)
+                {
+                    found = Some((slot, cred.creation_order));
+                }
+            }
+        }
+        Ok(found.map(|(slot, _)| slot))
+    }
+    pub fn find_credential_id(
+        &self,
+        storage: &mut impl FIDO2Storage,
+        credential_id: &[u8],
+    ) -> Result<Option<usize>, FIDO2InternalError> {
+        for slot in 0..FIDO2_MAX_RESIDENT_CREDENTIALS {
+            if let Some(cred) = self.read(storage, slot)? {
+                if cred.credential_id.as_slice() == credential_id {
+                    return Ok(Some(slot));
+                }
+            }
+        }
+        Ok(None)
+    }
+    // a credential of the same user account on the same rp is replaced
+    pub fn store(
+        &mut self,
+        storage: &mut impl FIDO2Storage,
+        cred: &mut FIDO2ResidentCredential,
+    ) -> Result<usize, FIDO2StatusCode> {
+        let old = self.find_user(storage, &cred.rp_id_hash, cred.user_id.as_slice())?;
+        let mut target: Option<usize> = None;
+        for slot in 0..FIDO2_MAX_RESIDENT_CREDENTIALS {
+            match self.slot(storage, slot)? {
+                FIDO2CredentialSlot::Free => {
+                    target = Some(slot);
+                    break;
+                }
+                FIDO2CredentialSlot::Garbage if target.is_none() => target = Some(slot),
+                _ => {}
+            }
+        }
+        // full, the only place left is the credential that is being replaced
+        let target = match (target, old) {
+            (Some(slot), _) => slot,
+            (None, Some(slot)) => slot,
+            (None, None) => return Err(FIDO2StatusCode::Ctap2ErrKeyStoreFull),
+        };
+        cred.creation_order = self.next_creation_order;
+        let raw = cred.pack();
+        if self.slot(storage, target)? != FIDO2CredentialSlot::Free {
+            storage.erase_page(FIDO2_STORAGE_CREDENTIAL_PAGE + target as u32)?;
+        }
+        // marker last
+        storage.write(Self::slot_offset(target) + 2, &raw[2..])?;
+        storage.write(Self::slot_offset(target), &raw[..2])?;
+        self.next_creation_order += 1;
+        if let Some(slot) = old {
+            if slot != target {
+                self.delete(storage, slot)?;
+            }
+        }
+        Ok(target)
+    }
+    pub fn delete(
+        &mut self,
+        storage: &mut impl FIDO2Storage,
+        slot: usize,
+    ) -> Result<(), FIDO2InternalError> {
+        storage.erase_page(FIDO2_STORAGE_CREDENTIAL_PAGE + slot as u32)
+    }
+}
@@ -0,0 +1,259 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use aes::cipher::{block_padding::NoPadding, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use ed25519_dalek::{Signer, Verifier};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use p256::{
+    ecdh::diffie_hellman,
+    ecdsa::{
+        signature::{DigestSigner, DigestVerifier},
+        Signature, SigningKey, VerifyingKey,
+    },
+    elliptic_curve::sec1::FromEncodedPoint,
+    EncodedPoint, PublicKey, SecretKey,
+};
+use rand_core::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+// SHA-256 and HMAC on their own, for the DRBG: it is the RNG inside the
+// provider, so it cannot name the provider type
+pub(crate) trait FIDO2HashProvider {
+    fn sha256(parts: &[&[u8]]) -> [u8; 32];
+    fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32];
+}
+
+// cryptographic backend of the authenticator
+// the provider is the RNG itself, all other primitives are stateless
+// messages are passed as parts that are hashed or signed as one concatenation
+pub(crate) trait FIDO2CryptoProvider: FIDO2HashProvider + RngCore + CryptoRng {
+    // 32 byte output
+    fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; 32];
+    // without padding, buf length must be a multiple of 16
+    fn aes256_cbc_encrypt(key: &[u8; 32], iv: &[u8; 16], buf: &mut [u8]) -> Option<()>;
+    fn aes256_cbc_decrypt(key: &[u8; 32], iv: &[u8; 16], buf: &mut [u8]) -> Option<()>;
+
+    // ECDSA P-256 (ES256), private keys are 32 byte scalars
+    fn p256_generate(&mut self) -> [u8; 32];
+    // uncompressed public key (x, y)
+    fn p256_public_key(secret: &[u8; 32]) -> Option<([u8; 32], [u8; 32])>;
+    // DER encoded signature over SHA-256, returns the signature length
+    fn p256_sign(secret: &[u8; 32], parts: &[&[u8]], out: &mut [u8]) -> Option<usize>;
+    fn p256_verify(x: &[u8; 32], y: &[u8; 32], parts: &[&[u8]], signature: &[u8]) -> bool;
+    // returns the x coordinate of the shared point
+    fn p256_ecdh(secret: &[u8; 32], x: &[u8; 32], y: &[u8; 32]) -> Option<[u8; 32]>;
+
+    // Ed25519 (EdDSA), private keys are 32 byte seeds
+    fn ed25519_generate(&mut self) -> [u8; 32];
+    fn ed25519_public_key(secret: &[u8; 32]) -> [u8; 32];
+    // pure EdDSA, returns the signature length
+    fn ed25519_sign(secret: &[u8; 32], parts: &[&[u8]], out: &mut [u8]) -> Option<usize>;
+    fn ed25519_verify(public: &[u8; 32], parts: &[&[u8]], signature: &[u8]) -> bool;
+}
+
+// constant time comparison, for tags and PIN hashes
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// RustCrypto backend (constant time, pure no_std), runs on the device and on the host
+pub struct FIDO2SoftwareCrypto<R: RngCore + CryptoRng>(pub R);
+
+impl<R: RngCore + CryptoRng> RngCore for FIDO2SoftwareCrypto<R> {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.0.try_fill_bytes(dest)
+    }
+}
+impl<R: RngCore + CryptoRng> CryptoRng for FIDO2SoftwareCrypto<R> {}
+
+fn sha256_digest(parts: &[&[u8]]) -> Sha256 {
+    let mut h = Sha256::new();
+    for p in parts {
+        h.update(p);
+    }
+    h
+}
+
+// Ed25519 signs the message itself, which is at most authenticatorData and clientDataHash
+const ED25519_MAX_MESSAGE_LENGTH: usize = 512;
+
+fn concat<'a>(parts: &[&[u8]], buf: &'a mut [u8; ED25519_MAX_MESSAGE_LENGTH]) -> Option<&'a [u8]> {
+    let mut len = 0;
+    for p in parts {
+        buf.get_mut(len..len + p.len())?.copy_from_slice(p);
+        len += p.len();
+    }
+    Some(&buf[..len])
+}
+
+// the hashes of FIDO2SoftwareCrypto, without an RNG
+#[derive(Debug)]
+pub struct FIDO2SoftwareHash;
+
+impl FIDO2HashProvider for FIDO2SoftwareHash {
+    fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+        sha256_digest(parts).finalize().into()
+    }
+    fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+        for p in parts {
+            mac.update(p);
+        }
+        mac.finalize().into_bytes().into()
+    }
+}
+
+impl<R: RngCore + CryptoRng> FIDO2HashProvider for FIDO2SoftwareCrypto<R> {
+    fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+        FIDO2SoftwareHash::sha256(parts)
+    }
+    fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+        FIDO2SoftwareHash::hmac_sha256(key, parts)
+    }
+}
+
+impl<R: RngCore + CryptoRng> FIDO2CryptoProvider for FIDO2SoftwareCrypto<R> {
+    fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; 32] {
+        let mut okm = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(salt), ikm)
+            .expand(info, &mut okm)
+            .unwrap();
+        okm
+    }
+    fn aes256_cbc_encrypt(key: &[u8; 32], iv: &[u8; 16], buf: &mut [u8]) -> Option<()> {
+        let len = buf.len();
+        cbc::Encryptor::<aes::Aes256>::new(key.into(), iv.into())
+            .encrypt_padded_mut::<NoPadding>(buf, len)
+            .ok()?;
+        Some(())
+    }
+    fn aes256_cbc_decrypt(key: &[u8; 32], iv: &[u8; 16], buf: &mut [u8]) -> Option<()> {
+        cbc::Decryptor::<aes::Aes256>::new(key.into(), iv.into())
+            .decrypt_padded_mut::<NoPadding>(buf)
+            .ok()?;
+        Some(())
+    }
+
+    fn p256_generate(&mut self) -> [u8; 32] {
+        SigningKey::random(&mut self.0).to_bytes().into()
+    }
+    fn p256_public_key(secret: &[u8; 32]) -> Option<([u8; 32], [u8; 32])> {
+        let key = SigningKey::from_bytes(secret.into()).ok()?;
+        let point = key.verifying_key().to_encoded_point(false);
+        Some((
+            (*point.x()?).into(),
+            (*point.y()?).into(),
+        ))
+    }
+    fn p256_sign(secret: &[u8; 32], parts: &[&[u8]], out: &mut [u8]) -> Option<usize> {
+        let key = SigningKey::from_bytes(secret.into()).ok()?;
+        let signature: Signature = key.sign_digest(sha256_digest(parts));
+        let der = signature.to_der();
+        let der = der.as_bytes();
+        if out.len() < der.len() {
+            return None;
+        }
+        out[..der.len()].copy_from_slice(der);
+        Some(der.len())
+    }
+    fn p256_verify(x: &[u8; 32], y: &[u8; 32], parts: &[&[u8]], signature: &[u8]) -> bool {
+        let point = EncodedPoint::from_affine_coordinates(x.into(), y.into(), false);
+        let (Ok(key), Ok(signature)) = (
+            VerifyingKey::from_encoded_point(&point),
+            Signature::from_der(signature),
+        ) else {
+            return false;
+        };
+        key.verify_digest(sha256_digest(parts), &signature).is_ok()
+    }
+    fn p256_ecdh(secret: &[u8; 32], x: &[u8; 32], y: &[u8; 32]) -> Option<[u8; 32]> {
+        let secret = SecretKey::from_bytes(secret.into()).ok()?;
+        let point = EncodedPoint::from_affine_coordinates(x.into(), y.into(), false);
+        let public: Option<PublicKey> = PublicKey::from_encoded_point(&point).into();
+        let shared = diffie_hellman(secret.to_nonzero_scalar(), public?.as_affine());
+        Some((*shared.raw_secret_bytes()).into())
+    }
+
+    fn ed25519_generate(&mut self) -> [u8; 32] {
+        let mut seed = [0u8; 32];
+        self.0.fill_bytes(&mut seed);
+        seed
+    }
+    fn ed25519_public_key(secret: &[u8; 32]) -> [u8; 32] {
+        ed25519_dalek::SigningKey::from_bytes(secret)
+            .verifying_key()
+            .to_bytes()
+    }
+    fn ed25519_sign(secret: &[u8; 32], parts: &[&[u8]], out: &mut [u8]) -> Option<usize> {
+        let mut buf = [0u8; ED25519_MAX_MESSAGE_LENGTH];
+        let signature = ed25519_dalek::SigningKey::from_bytes(secret)
+            .sign(concat(parts, &mut buf)?)
+            .to_bytes();
+        out.get_mut(..signature.len())?.copy_from_slice(&signature);
+        Some(signature.len())
+    }
+    fn ed25519_verify(public: &[u8; 32], parts: &[&[u8]], signature: &[u8]) -> bool {
+        let mut buf = [0u8; ED25519_MAX_MESSAGE_LENGTH];
+        let (Ok(key), Ok(signature), Some(message)) = (
+            ed25519_dalek::VerifyingKey::from_bytes(public),
+            ed25519_dalek::Signature::from_slice(signature),
+            concat(parts, &mut buf),
+        ) else {
+            return false;
+        };
+        key.verify(message, &signature).is_ok()
+    }
+}
+
+// deterministic RNG for the host tests (splitmix64), never for keys
+#[cfg(test)]
+pub(crate) struct FIDO2TestRng(pub u64);
+#[cfg(test)]
+impl RngCore for FIDO2TestRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+#[cfg(test)]
+impl CryptoRng for FIDO2TestRng {}
+#[cfg(test)]
+pub(crate) type FIDO2TestCrypto = FIDO2SoftwareCrypto<FIDO2TestRng>;
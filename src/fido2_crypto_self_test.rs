@@ -0,0 +1,436 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::fido2_crypto::{ct_eq, FIDO2CryptoProvider};
+
+// known answer tests of the crypto provider, run once at power up. only what
+// the firmware uses is tested here, signature verification (which would link
+// two more curve implementations) is left to the host tests
+// vectors: FIPS 180-2, RFC 4231, RFC 5869, SP 800-38A, RFC 6979, NIST CAVS KAS, RFC 8032
+
+pub(crate) const fn hex<const N: usize>(s: &str) -> [u8; N] {
+    const fn nibble(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            _ => panic!("bad hex"),
+        }
+    }
+    let s = s.as_bytes();
+    assert!(s.len() == N * 2);
+    let mut out = [0u8; N];
+    let mut i = 0;
+    while i < N {
+        out[i] = nibble(s[i * 2]) << 4 | nibble(s[i * 2 + 1]);
+        i += 1;
+    }
+    out
+}
+
+fn sha256<C: FIDO2CryptoProvider>() -> bool {
+    let expected: [u8; 32] = hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    C::sha256(&[b"a", b"bc"]) == expected
+}
+
+fn hmac_sha256<C: FIDO2CryptoProvider>() -> bool {
+    let expected: [u8; 32] = hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    C::hmac_sha256(b"Jefe", &[b"what do ya want ", b"for nothing?"]) == expected
+}
+
+fn hkdf_sha256<C: FIDO2CryptoProvider>() -> bool {
+    let salt: [u8; 13] = hex("000102030405060708090a0b0c");
+    let info: [u8; 10] = hex("f0f1f2f3f4f5f6f7f8f9");
+    let expected: [u8; 32] = hex("3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf");
+    C::hkdf_sha256(&salt, &[0x0b; 22], &info) == expected
+}
+
+fn aes256_cbc<C: FIDO2CryptoProvider>() -> bool {
+    let key: [u8; 32] = hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4");
+    let iv: [u8; 16] = hex("000102030405060708090a0b0c0d0e0f");
+    let plaintext: [u8; 32] = hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+    let ciphertext: [u8; 32] = hex("f58c4c04d6e5f1ba779eabfb5f7bfbd69cfc4e967edb808d679f777bc6702c7d");
+    let mut buf = plaintext;
+    if C::aes256_cbc_encrypt(&key, &iv, &mut buf).is_none() || buf != ciphertext {
+        return false;
+    }
+    C::aes256_cbc_decrypt(&key, &iv, &mut buf).is_some() && buf == plaintext
+}
+
+fn p256_ecdsa<C: FIDO2CryptoProvider>() -> bool {
+    let secret: [u8; 32] = hex("c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721");
+    let x: [u8; 32] = hex("60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6");
+    let y: [u8; 32] = hex("7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299");
+    // deterministic k, DER(r, s)
+    let expected: [u8; 72] = hex(concat!(
+        "3046022100efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716",
+        "022100f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8",
+    ));
+    if C::p256_public_key(&secret) != Some((x, y)) {
+        return false;
+    }
+    let mut signature = [0u8; 72];
+    C::p256_sign(&secret, &[b"sam", b"ple"], &mut signature) == Some(72) && signature == expected
+}
+
+fn p256_ecdh<C: FIDO2CryptoProvider>() -> bool {
+    let secret: [u8; 32] = hex("7d7dc5f71eb29ddaf80d6214632eeae03d9058af1fb6d22ed80badb62bc1a534");
+    let x: [u8; 32] = hex("700c48f77f56584c5cc632ca65640db91b6bacce3a4df6b42ce7cc838833d287");
+    let y: [u8; 32] = hex("db71e509e3fd9b060ddb20ba5c51dcc5948d46fbf640dfe0441782cab85fa4ac");
+    let expected: [u8; 32] = hex("46fc62106420ff012e54a434fbdd2d25ccc5852060561e68040dd7778997bd7b");
+    // a point that is not on the curve must be rejected
+    let mut invalid = y;
+    invalid[31] ^= 1;
+    C::p256_ecdh(&secret, &x, &y).is_some_and(|z| ct_eq(&z, &expected))
+        && C::p256_ecdh(&secret, &x, &invalid).is_none()
+}
+
+fn ed25519<C: FIDO2CryptoProvider>() -> bool {
+    let secret: [u8; 32] = hex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+    let public: [u8; 32] = hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+    let expected: [u8; 64] = hex(concat!(
+        "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155",
+        "5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+    ));
+    if C::ed25519_public_key(&secret) != public {
+        return false;
+    }
+    let mut signature = [0u8; 64];
+    C::ed25519_sign(&secret, &[], &mut signature) == Some(64) && signature == expected
+}
+
+// false if any primitive gives a wrong answer
+pub(crate) fn self_test<C: FIDO2CryptoProvider>() -> bool {
+    sha256::<C>()
+        && hmac_sha256::<C>()
+        && hkdf_sha256::<C>()
+        && aes256_cbc::<C>()
+        && p256_ecdsa::<C>()
+        && p256_ecdh::<C>()
+        && ed25519::<C>()
+}
+
+// the same provider on the host, with more vectors than fit into the firmware:
+// FIPS 180-2 examples, RFC 4231, RFC 6979, RFC 8032, NIST CAVS (CBC MMT, KAS
+// ECC CDH) and the Wycheproof ecdsa_secp256r1_sha256 and hkdf_sha256 groups.
+// the vector files in src/test_vectors are the ones the RustCrypto crates
+// test with, in their "blobby" format
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fido2_crypto::{FIDO2HashProvider, FIDO2TestCrypto as C};
+
+    // P-256 field prime and group order
+    const P: &str = "ffffffff00000001000000000000000000000000ffffffffffffffffffffffff";
+    const N: &str = "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551";
+
+    // splits a blobby file into rows of `width` blobs
+    fn blobs(data: &[u8], width: usize) -> Vec<Vec<&[u8]>> {
+        fn vlq(data: &[u8], pos: &mut usize) -> usize {
+            let mut value = 0;
+            loop {
+                let b = data[*pos];
+                *pos += 1;
+                value += (b & 0x7f) as usize;
+                if b & 0x80 == 0 {
+                    return value;
+                }
+                value = (value + 1) << 7;
+            }
+        }
+        let mut pos = 0;
+        let mut dedup = Vec::new();
+        for _ in 0..vlq(data, &mut pos) {
+            let len = vlq(data, &mut pos);
+            dedup.push(&data[pos..pos + len]);
+            pos += len;
+        }
+        let mut all = Vec::new();
+        while pos < data.len() {
+            let v = vlq(data, &mut pos);
+            if v & 1 == 1 {
+                all.push(dedup[v >> 1]);
+            } else {
+                all.push(&data[pos..pos + (v >> 1)]);
+                pos += v >> 1;
+            }
+        }
+        assert_eq!(all.len() % width, 0);
+        all.chunks(width).map(|row| row.to_vec()).collect()
+    }
+
+    // big endian integer of up to 32 significant bytes, left padded
+    fn coordinate(v: &[u8]) -> [u8; 32] {
+        let v = &v[v.iter().take_while(|b| **b == 0).count()..];
+        let mut out = [0u8; 32];
+        out[32 - v.len()..].copy_from_slice(v);
+        out
+    }
+
+    #[test]
+    fn power_up_self_test() {
+        assert!(self_test::<C>());
+    }
+
+    #[test]
+    fn sha256_nist() {
+        let empty: [u8; 32] = hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(C::sha256(&[]), empty);
+        let two_blocks: [u8; 32] = hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        assert_eq!(
+            C::sha256(&[b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"]),
+            two_blocks
+        );
+        let million: [u8; 32] = hex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+        let a = [b'a'; 1000];
+        let parts = [&a[..]; 1000];
+        assert_eq!(C::sha256(&parts), million);
+    }
+
+    #[test]
+    fn hmac_sha256_rfc4231() {
+        // test case 1
+        let expected: [u8; 32] = hex("b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        assert_eq!(C::hmac_sha256(&[0x0b; 20], &[b"Hi There"]), expected);
+        // test case 6, key longer than a block
+        let expected: [u8; 32] = hex("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+        assert_eq!(
+            C::hmac_sha256(&[0xaa; 131], &[b"Test Using Larger Than Block-Size Key - Hash Key First"]),
+            expected
+        );
+        // test case 7, key and data longer than a block
+        let expected: [u8; 32] = hex("9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2");
+        assert_eq!(
+            C::hmac_sha256(
+                &[0xaa; 131],
+                &[
+                    b"This is a test using a larger than block-size key and a larger than block-",
+                    b"size data. The key needs to be hashed before being used by the HMAC algorithm.",
+                ]
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_edge_keys() {
+        // empty key and message
+        let expected: [u8; 32] = hex("b613679a0814d9ec772f95d778c35fc5ff1697c493715653c6c712144292c5ad");
+        assert_eq!(C::hmac_sha256(&[], &[]), expected);
+        // a key of exactly one block is used as is, one byte more is hashed first
+        let block = [0x5c; 64];
+        let hashed = C::sha256(&[&[0x5c; 65]]);
+        assert_eq!(C::hmac_sha256(&[0x5c; 65], &[b"m"]), C::hmac_sha256(&hashed, &[b"m"]));
+        assert_ne!(C::hmac_sha256(&block, &[b"m"]), C::hmac_sha256(&block[..63], &[b"m"]));
+        // a flipped tag bit does not compare equal
+        let tag = C::hmac_sha256(&block, &[b"m"]);
+        let mut flipped = tag;
+        flipped[31] ^= 0x80;
+        assert!(ct_eq(&tag, &tag) && !ct_eq(&tag, &flipped) && !ct_eq(&tag, &tag[..16]));
+    }
+
+    #[test]
+    fn hkdf_sha256_wycheproof() {
+        // key, salt, info, okm. only 32 bytes are ever derived, which are the
+        // start of any longer okm
+        let rows = blobs(include_bytes!("test_vectors/hkdf_sha256.blb"), 4);
+        assert_eq!(rows.len(), 102);
+        for (i, row) in rows.iter().enumerate() {
+            let (ikm, salt, info, okm) = (row[0], row[1], row[2], row[3]);
+            let len = okm.len().min(32);
+            assert_eq!(&C::hkdf_sha256(salt, ikm, info)[..len], &okm[..len], "case {}", i);
+        }
+    }
+
+    #[test]
+    fn aes256_cbc_nist_mmt() {
+        // key, iv, plaintext, ciphertext
+        let rows = blobs(include_bytes!("test_vectors/aes256_cbc_mmt.blb"), 4);
+        assert_eq!(rows.len(), 20);
+        for (i, row) in rows.iter().enumerate() {
+            let (key, iv) = (row[0].try_into().unwrap(), row[1].try_into().unwrap());
+            let mut buf = row[2].to_vec();
+            assert!(C::aes256_cbc_encrypt(key, iv, &mut buf).is_some());
+            assert_eq!(buf, row[3], "case {}", i);
+            assert!(C::aes256_cbc_decrypt(key, iv, &mut buf).is_some());
+            assert_eq!(buf, row[2], "case {}", i);
+        }
+        // only whole blocks, there is no padding
+        let mut odd = [0u8; 17];
+        assert!(C::aes256_cbc_encrypt(&[0; 32], &[0; 16], &mut odd).is_none());
+        assert!(C::aes256_cbc_decrypt(&[0; 32], &[0; 16], &mut odd).is_none());
+    }
+
+    #[test]
+    fn ecdsa_rfc6979() {
+        // A.2.5, SHA-256, message "test"
+        let secret: [u8; 32] = hex("c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721");
+        let expected: [u8; 71] = hex(concat!(
+            "3045022100f1abb023518351cd71d881567b1ea663ed3efcf6c5132b354f28d3b0b7d38367",
+            "0220019f4113742a2b14bd25926b49c649155f267e60d3814b4c0cc84250e46f0083",
+        ));
+        let mut signature = [0u8; 72];
+        assert_eq!(C::p256_sign(&secret, &[b"te", b"st"], &mut signature), Some(71));
+        assert_eq!(signature[..71], expected);
+        let (x, y) = C::p256_public_key(&secret).unwrap();
+        assert!(C::p256_verify(&x, &y, &[b"test"], &expected));
+        assert!(!C::p256_verify(&x, &y, &[b"tesT"], &expected));
+    }
+
+    #[test]
+    fn ecdsa_secp256r1_sha256_wycheproof() {
+        // wx, wy, msg, sig, result. the invalid cases cover the DER encoding
+        // of the signature, r and s out of range and modified signatures
+        let rows = blobs(include_bytes!("test_vectors/ecdsa_secp256r1_sha256.blb"), 5);
+        assert_eq!(rows.len(), 386);
+        let invalid = rows.iter().filter(|row| row[4] == [0]).count();
+        assert!(invalid > 200);
+        for (i, row) in rows.iter().enumerate() {
+            let (x, y) = (coordinate(row[0]), coordinate(row[1]));
+            let valid = row[4] == [1];
+            assert_eq!(C::p256_verify(&x, &y, &[row[2]], row[3]), valid, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn ecdh_nist_cavs() {
+        // KAS ECC CDH primitive, P-256, COUNT = 0, 1, 2: d, Qx, Qy, Z
+        for (d, x, y, z) in [
+            (
+                "7d7dc5f71eb29ddaf80d6214632eeae03d9058af1fb6d22ed80badb62bc1a534",
+                "700c48f77f56584c5cc632ca65640db91b6bacce3a4df6b42ce7cc838833d287",
+                "db71e509e3fd9b060ddb20ba5c51dcc5948d46fbf640dfe0441782cab85fa4ac",
+                "46fc62106420ff012e54a434fbdd2d25ccc5852060561e68040dd7778997bd7b",
+            ),
+            (
+                "38f65d6dce47676044d58ce5139582d568f64bb16098d179dbab07741dd5caf5",
+                "809f04289c64348c01515eb03d5ce7ac1a8cb9498f5caa50197e58d43a86a7ae",
+                "b29d84e811197f25eba8f5194092cb6ff440e26d4421011372461f579271cda3",
+                "057d636096cb80b67a8c038c890e887d1adfa4195e9b3ce241c8a778c59cda67",
+            ),
+            (
+                "1accfaf1b97712b85a6f54b148985a1bdc4c9bec0bd258cad4b3d603f49f32c8",
+                "a2339c12d4a03c33546de533268b4ad667debf458b464d77443636440ee7fec3",
+                "ef48a3ab26e20220bcda2c1851076839dae88eae962869a497bf73cb66faf536",
+                "2d457b78b4614132477618a5b077965ec90730a8c81a1c75d6d4ec68005d67ec",
+            ),
+        ] {
+            assert_eq!(C::p256_ecdh(&hex(d), &hex(x), &hex(y)), Some(hex(z)));
+        }
+    }
+
+    #[test]
+    fn ecdh_invalid_public_keys() {
+        let secret: [u8; 32] = hex("7d7dc5f71eb29ddaf80d6214632eeae03d9058af1fb6d22ed80badb62bc1a534");
+        let x: [u8; 32] = hex("700c48f77f56584c5cc632ca65640db91b6bacce3a4df6b42ce7cc838833d287");
+        let y: [u8; 32] = hex("db71e509e3fd9b060ddb20ba5c51dcc5948d46fbf640dfe0441782cab85fa4ac");
+        assert!(C::p256_ecdh(&secret, &x, &y).is_some());
+        // not on the curve
+        let mut off = y;
+        off[0] ^= 1;
+        assert!(C::p256_ecdh(&secret, &x, &off).is_none());
+        // (0, 0) stands for the point at infinity in some encodings
+        assert!(C::p256_ecdh(&secret, &[0; 32], &[0; 32]).is_none());
+        // coordinates that are not reduced modulo p
+        assert!(C::p256_ecdh(&secret, &hex(P), &y).is_none());
+        assert!(C::p256_ecdh(&secret, &x, &hex(P)).is_none());
+        // the private key has to be in [1, n - 1]
+        assert!(C::p256_ecdh(&[0; 32], &x, &y).is_none());
+        assert!(C::p256_ecdh(&hex(N), &x, &y).is_none());
+    }
+
+    #[test]
+    fn ed25519_rfc8032() {
+        // 7.1, TEST 1, 2, 3 and SHA(abc): secret, public, message, signature
+        let abc = hex::<64>(concat!(
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a",
+            "2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+        ));
+        for (secret, public, message, expected) in [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                &[][..],
+                concat!(
+                    "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155",
+                    "5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+                ),
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                &[0x72][..],
+                concat!(
+                    "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da",
+                    "085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+                ),
+            ),
+            (
+                "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+                "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+                &[0xaf, 0x82][..],
+                concat!(
+                    "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac",
+                    "18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+                ),
+            ),
+            (
+                "833fe62409237b9d62ec77587520911e9a759cec1d19755b7da901b96dca3d42",
+                "ec172b93ad5e563bf4932c70e1245034c35467ef2efd4d64ebf819683467e2bf",
+                &abc[..],
+                concat!(
+                    "dc2a4459e7369633a52b1bf277839a00201009a3efbf3ecb69bea2186c26b589",
+                    "09351fc9ac90b3ecfdfbc7c66431e0303dca179c138ac17ad9bef1177331a704",
+                ),
+            ),
+        ] {
+            let (secret, public, expected) = (hex::<32>(secret), hex::<32>(public), hex::<64>(expected));
+            assert_eq!(C::ed25519_public_key(&secret), public);
+            let mut signature = [0u8; 64];
+            assert_eq!(C::ed25519_sign(&secret, &[message], &mut signature), Some(64));
+            assert_eq!(signature, expected);
+            assert!(C::ed25519_verify(&public, &[message], &signature));
+            // a flipped bit in R or S
+            for k in [0, 32] {
+                let mut flipped = signature;
+                flipped[k] ^= 1;
+                assert!(!C::ed25519_verify(&public, &[message], &flipped));
+            }
+            assert!(!C::ed25519_verify(&public, &[message, b"x"], &signature));
+            assert!(!C::ed25519_verify(&public, &[message], &signature[..63]));
+        }
+    }
+
+    #[test]
+    fn ed25519_non_canonical_s() {
+        // RFC 8032 5.1.7: S has to be below the group order L, S + L is rejected
+        const L: [u8; 32] = hex("edd3f55c1a631258d69cf7a2def9de1400000000000000000000000000000010");
+        let secret: [u8; 32] = hex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+        let public = C::ed25519_public_key(&secret);
+        let mut signature = [0u8; 64];
+        C::ed25519_sign(&secret, &[b"m"], &mut signature).unwrap();
+        assert!(C::ed25519_verify(&public, &[b"m"], &signature));
+        // S is little endian
+        let mut carry = 0u16;
+        for k in 0..32 {
+            let sum = signature[32 + k] as u16 + L[k] as u16 + carry;
+            signature[32 + k] = sum as u8;
+            carry = sum >> 8;
+        }
+        assert!(!C::ed25519_verify(&public, &[b"m"], &signature));
+    }
+}
@@ -0,0 +1,225 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use core::fmt::{self, Write};
+
+use crate::{
+    consts::{
+        FIDO2_CONSOLE_LINE_LENGTH, FIDO2_STORAGE_CREDENTIAL_PAGES, FIDO2_STORAGE_SEED_PAGE,
+        FIDO2_STORAGE_PAGES,
+    },
+    fido2_authenticator::FIDO2Authenticator,
+    fido2_crypto::FIDO2CryptoProvider,
+    fido2_log,
+    fido2_storage::FIDO2Storage,
+    fido2_transport::FIDO2Transport,
+    global_buffer::GlobalBuffer,
+};
+
+// line based shell on the debug serial port, the USART1 task that reads it
+// only exists with the "debug-console" feature
+
+pub(crate) const FIDO2_CONSOLE_HELP: &str =
+    "commands: help, channels, buffers, flash, counters, bootloader, trace on|off";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum FIDO2ConsoleCommand {
+    Help,
+    Channels,
+    Buffers,
+    Flash,
+    Counters,
+    // reboot into the system memory bootloader
+    Bootloader,
+    Trace(bool),
+    Unknown,
+}
+impl FIDO2ConsoleCommand {
+    pub fn parse(line: &[u8]) -> FIDO2ConsoleCommand {
+        let line = core::str::from_utf8(line).unwrap_or("");
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("help"), None, None) => FIDO2ConsoleCommand::Help,
+            (Some("channels"), None, None) => FIDO2ConsoleCommand::Channels,
+            (Some("buffers"), None, None) => FIDO2ConsoleCommand::Buffers,
+            (Some("flash"), None, None) => FIDO2ConsoleCommand::Flash,
+            (Some("counters"), None, None) => FIDO2ConsoleCommand::Counters,
+            (Some("bootloader"), None, None) => FIDO2ConsoleCommand::Bootloader,
+            (Some("trace"), Some("on"), None) => FIDO2ConsoleCommand::Trace(true),
+            (Some("trace"), Some("off"), None) => FIDO2ConsoleCommand::Trace(false),
+            _ => FIDO2ConsoleCommand::Unknown,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FIDO2ConsoleLine {
+    data: [u8; FIDO2_CONSOLE_LINE_LENGTH],
+    length: usize,
+    // the line got too long, it is dropped at the end
+    overflow: bool,
+}
+impl FIDO2ConsoleLine {
+    pub const fn new() -> FIDO2ConsoleLine {
+        FIDO2ConsoleLine {
+            data: [0u8; FIDO2_CONSOLE_LINE_LENGTH],
+            length: 0,
+            overflow: false,
+        }
+    }
+    // feed a received byte, a command comes out at the end of a line
+    pub fn push(&mut self, byte: u8) -> Option<FIDO2ConsoleCommand> {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = &self.data[..self.length];
+                let command = match (self.overflow, line.is_empty()) {
+                    (true, _) => Some(FIDO2ConsoleCommand::Unknown),
+                    // empty lines, also the \n of \r\n
+                    (false, true) => None,
+                    (false, false) => Some(FIDO2ConsoleCommand::parse(line)),
+                };
+                self.length = 0;
+                self.overflow = false;
+                command
+            }
+            // backspace, delete
+            0x08 | 0x7f => {
+                self.length = self.length.saturating_sub(1);
+                None
+            }
+            _ => {
+                if self.length == self.data.len() {
+                    self.overflow = true;
+                } else {
+                    self.data[self.length] = byte;
+                    self.length += 1;
+                }
+                None
+            }
+        }
+    }
+}
+
+// allocated channels and the request being received
+pub(crate) fn dump_channels(w: &mut impl Write, transport: &FIDO2Transport) -> fmt::Result {
+    write!(w, "channels:")?;
+    let mut count = 0;
+    for channel_id in transport.channel_ids() {
+        write!(w, " {:08x}", channel_id)?;
+        count += 1;
+    }
+    if count == 0 {
+        write!(w, " none")?;
+    }
+    writeln!(w)?;
+    match transport.pending_request() {
+        Some((channel_id, command)) => {
+            writeln!(w, "receiving: {:?} on {:08x}", command, channel_id)
+        }
+        None => writeln!(w, "receiving: none"),
+    }
+}
+
+// the message buffer, None while the CTAP task has it
+pub(crate) fn dump_buffers(
+    w: &mut impl Write,
+    buffer: Option<&GlobalBuffer>,
+    tx_queued: usize,
+    tx_capacity: usize,
+) -> fmt::Result {
+    match buffer {
+        Some(b) => {
+            let size = b.request_buffer.len();
+            writeln!(
+                w,
+                "request: {}/{} bytes{}",
+                b.request_buffer_data_len,
+                size,
+                if b.request_buffer_done { ", done" } else { "" }
+            )?;
+            writeln!(
+                w,
+                "response: {}/{} bytes{}",
+                b.response_buffer_data_len,
+                size,
+                if b.response_buffer_done { ", done" } else { "" }
+            )?;
+        }
+        None => writeln!(w, "request/response: in use by the CTAP task")?,
+    }
+    writeln!(w, "usb tx: {}/{} reports", tx_queued, tx_capacity)
+}
+
+pub(crate) fn dump_flash<S: FIDO2Storage, C: FIDO2CryptoProvider>(
+    w: &mut impl Write,
+    authenticator: &mut FIDO2Authenticator<S, C>,
+) -> fmt::Result {
+    writeln!(w, "pages: {}", FIDO2_STORAGE_PAGES)?;
+    let state = &authenticator.state;
+    writeln!(w, "state: page {}, seq {}", state.page, state.seq)?;
+    writeln!(w, "counter log: slot {}", authenticator.counter.next_slot)?;
+    let credentials = &authenticator.credentials;
+    match (
+        credentials.count(&mut authenticator.storage),
+        credentials.remaining(&mut authenticator.storage),
+    ) {
+        (Ok(used), Ok(remaining)) => writeln!(
+            w,
+            "credentials: {} used, {} free of {}",
+            used, remaining, FIDO2_STORAGE_CREDENTIAL_PAGES
+        )?,
+        (Err(e), _) | (_, Err(e)) => writeln!(w, "credentials: {:?}", e)?,
+    }
+    writeln!(
+        w,
+        "large blob: page {}, {} bytes",
+        state.large_blob_page, state.large_blob_length
+    )?;
+    writeln!(w, "seed: page {}", FIDO2_STORAGE_SEED_PAGE)?;
+    let attestation = &authenticator.attestation;
+    writeln!(
+        w,
+        "attestation: {}, certificate {} bytes{}",
+        if attestation.private_key.is_some() {
+            "provisioned"
+        } else {
+            "self"
+        },
+        attestation.certificate_length,
+        if attestation.locked { ", locked" } else { "" }
+    )
+}
+
+pub(crate) fn dump_counters<S: FIDO2Storage, C: FIDO2CryptoProvider>(
+    w: &mut impl Write,
+    authenticator: &FIDO2Authenticator<S, C>,
+) -> fmt::Result {
+    writeln!(w, "signature counter: {}", authenticator.counter.value)?;
+    writeln!(
+        w,
+        "next creation order: {}",
+        authenticator.credentials.next_creation_order
+    )?;
+    writeln!(w, "pin retries: {}", authenticator.state.pin_retries)?;
+    writeln!(
+        w,
+        "pin mismatches in a row: {}",
+        authenticator.pin.consecutive_mismatches
+    )?;
+    writeln!(w, "log records dropped: {}", fido2_log::dropped())
+}
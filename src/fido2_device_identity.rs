@@ -0,0 +1,52 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::fido2_crypto::FIDO2CryptoProvider;
+
+// everything that tells two keys apart is derived from the 96-bit unique id of the chip,
+// the raw id never leaves the device
+#[derive(Debug)]
+pub(crate) struct FIDO2DeviceIdentity {
+    uid: [u8; 12],
+}
+impl FIDO2DeviceIdentity {
+    pub fn new(uid: [u8; 12]) -> FIDO2DeviceIdentity {
+        FIDO2DeviceIdentity { uid }
+    }
+    // stable across reset and provisioning, unlike the AAGUID
+    pub fn device_id<C: FIDO2CryptoProvider>(&self) -> [u8; 16] {
+        let hash = C::sha256(&[b"unsafe{key} device id", &self.uid]);
+        let mut id = [0u8; 16];
+        id.copy_from_slice(&hash[..16]);
+        id
+    }
+    // USB iSerial, the device id in upper case hex
+    pub fn serial_number<C: FIDO2CryptoProvider>(&self) -> [u8; 32] {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+        let mut serial = [0u8; 32];
+        for (k, v) in self.device_id::<C>().iter().enumerate() {
+            serial[k * 2] = HEX[(v >> 4) as usize];
+            serial[k * 2 + 1] = HEX[(v & 0x0f) as usize];
+        }
+        serial
+    }
+    // DRBG nonce, keeps two keys with the same noise apart
+    pub fn seed_salt<C: FIDO2CryptoProvider>(&self) -> [u8; 32] {
+        C::sha256(&[b"unsafe{key} seed salt", &self.uid])
+    }
+}
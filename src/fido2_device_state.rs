@@ -0,0 +1,305 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use byteorder::{ByteOrder, LittleEndian};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    consts::{
+        FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS, FIDO2_PIN_MAX_RETRIES, FIDO2_PIN_MIN_LENGTH, FIDO2_STORAGE_LARGE_BLOB_PAGE,
+        FIDO2_STORAGE_PAGE_SIZE, FIDO2_STORAGE_PIN_RETRIES_PAGE, FIDO2_STORAGE_STATE_PAGE,
+    },
+    fido2_crypto::FIDO2CryptoProvider,
+    fido2_internal_error::FIDO2InternalError,
+    fido2_log::FIDO2Secret,
+    fido2_storage::{is_erased, page_offset, FIDO2Storage},
+};
+
+// the state is written to two pages in turn (A/B), the copy with the highest
+// sequence number and a valid checksum wins, so a power loss while saving
+// leaves the previous state intact
+// [marker: 2] [version: 2] [seq: 4] [checksum: 8] [body]
+const STATE_SIZE: usize = 512;
+const STATE_MARKER: u16 = 0x5AA5;
+const STATE_VERSION: u16 = 1;
+const STATE_HEADER_SIZE: usize = 16;
+// body
+const MASTER_SECRET: usize = STATE_HEADER_SIZE;
+const COUNTER_BASE: usize = MASTER_SECRET + 32;
+// LEFT(SHA-256(pin), 16)
+const PIN_HASH: usize = COUNTER_BASE + 4;
+// in code points, 0xff if no pin is set
+const PIN_LENGTH: usize = PIN_HASH + 16;
+const PIN_RETRIES: usize = PIN_LENGTH + 1;
+// 0x01 while authenticatorReset is erasing the other pages
+const RESET_PENDING: usize = PIN_RETRIES + 1;
+// copy of the large-blob array in use, 0xffff if it was never written
+const LARGE_BLOB_PAGE: usize = RESET_PENDING + 1;
+const LARGE_BLOB_LENGTH: usize = LARGE_BLOB_PAGE + 1;
+// authenticatorConfig, flags are 0x01 when set
+const ENTERPRISE_ATTESTATION: usize = LARGE_BLOB_LENGTH + 2;
+const ALWAYS_UV: usize = ENTERPRISE_ATTESTATION + 1;
+const FORCE_PIN_CHANGE: usize = ALWAYS_UV + 1;
+// 0xff for the default
+const MIN_PIN_LENGTH: usize = FORCE_PIN_CHANGE + 1;
+// [count: 1] [rpIdHash: 32] * FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS, 0xff for none
+const MIN_PIN_LENGTH_RP_IDS: usize = MIN_PIN_LENGTH + 1;
+
+// every PIN attempt appends the new retries to the retries log instead of
+// saving the state. an entry only counts for the copy of the state with the
+// same sequence number, so saving the state drops the whole log. once the log
+// is full the state is saved and the page erased
+// [seq: 4] [retries: 1] [!retries: 1] [0x0000]
+const RETRIES_ENTRY_SIZE: u32 = 8;
+const RETRIES_SLOTS: u32 = FIDO2_STORAGE_PAGE_SIZE / RETRIES_ENTRY_SIZE;
+
+fn retries_entry_offset(slot: u32) -> u32 {
+    page_offset(FIDO2_STORAGE_PIN_RETRIES_PAGE) + slot * RETRIES_ENTRY_SIZE
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FIDO2DeviceState {
+    pub seq: u32,
+    pub page: u32,
+    // key wrapping secret of all credentials
+    pub master_secret: FIDO2Secret<[u8; 32]>,
+    // signature counter value when the counter log was last erased
+    pub counter_base: u32,
+    pub pin_hash: Option<FIDO2Secret<[u8; 16]>>,
+    pub pin_length: u8,
+    pub pin_retries: u8,
+    pub reset_pending: bool,
+    // serialized large-blob array, 0 for the initial empty array
+    pub large_blob_page: u32,
+    pub large_blob_length: u16,
+    pub enterprise_attestation: bool,
+    pub always_uv: bool,
+    pub force_pin_change: bool,
+    pub min_pin_length: u8,
+    pub min_pin_length_rp_ids: [[u8; 32]; FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS],
+    pub min_pin_length_rp_id_count: usize,
+}
+// detects torn or stale copies, it does not depend on the crypto provider
+fn checksum(raw: &[u8; STATE_SIZE]) -> [u8; 32] {
+    Sha256::digest(&raw[STATE_HEADER_SIZE..]).into()
+}
+
+impl FIDO2DeviceState {
+    fn new(crypto: &mut impl FIDO2CryptoProvider) -> FIDO2DeviceState {
+        let mut master_secret = [0u8; 32];
+        crypto.fill_bytes(&mut master_secret);
+        FIDO2DeviceState {
+            seq: 0,
+            // the first save goes to page A
+            page: FIDO2_STORAGE_STATE_PAGE + 1,
+            master_secret: FIDO2Secret(master_secret),
+            counter_base: 0,
+            pin_hash: None,
+            pin_length: 0,
+            pin_retries: FIDO2_PIN_MAX_RETRIES,
+            reset_pending: false,
+            large_blob_page: FIDO2_STORAGE_LARGE_BLOB_PAGE,
+            large_blob_length: 0,
+            enterprise_attestation: false,
+            always_uv: false,
+            force_pin_change: false,
+            min_pin_length: FIDO2_PIN_MIN_LENGTH as u8,
+            min_pin_length_rp_ids: [[0u8; 32]; FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS],
+            min_pin_length_rp_id_count: 0,
+        }
+    }
+    fn unpack(raw: &[u8; STATE_SIZE], page: u32) -> Option<FIDO2DeviceState> {
+        if LittleEndian::read_u16(&raw[0..2]) != STATE_MARKER
+            || LittleEndian::read_u16(&raw[2..4]) != STATE_VERSION
+        {
+            return None;
+        }
+        if checksum(raw)[..8] != raw[8..16] {
+            return None;
+        }
+        let pin_set = raw[PIN_LENGTH] != 0xff;
+        let large_blob_length = LittleEndian::read_u16(&raw[LARGE_BLOB_LENGTH..LARGE_BLOB_LENGTH + 2]);
+        let large_blob_set = large_blob_length != 0xffff;
+        let mut min_pin_length_rp_ids = [[0u8; 32]; FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS];
+        let min_pin_length_rp_id_count = match raw[MIN_PIN_LENGTH_RP_IDS] as usize {
+            n if n <= FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS => n,
+            _ => 0,
+        };
+        for (k, v) in min_pin_length_rp_ids[..min_pin_length_rp_id_count]
+            .iter_mut()
+            .enumerate()
+        {
+            let offset = MIN_PIN_LENGTH_RP_IDS + 1 + k * 32;
+            v.copy_from_slice(&raw[offset..offset + 32]);
+        }
+        Some(FIDO2DeviceState {
+            seq: LittleEndian::read_u32(&raw[4..8]),
+            page,
+            master_secret: FIDO2Secret(raw[MASTER_SECRET..MASTER_SECRET + 32].try_into().unwrap()),
+            counter_base: LittleEndian::read_u32(&raw[COUNTER_BASE..COUNTER_BASE + 4]),
+            pin_hash: if pin_set {
+                Some(FIDO2Secret(raw[PIN_HASH..PIN_HASH + 16].try_into().unwrap()))
+            } else {
+                None
+            },
+            pin_length: if pin_set { raw[PIN_LENGTH] } else { 0 },
+            pin_retries: raw[PIN_RETRIES].min(FIDO2_PIN_MAX_RETRIES),
+            reset_pending: raw[RESET_PENDING] == 0x01,
+            large_blob_page: FIDO2_STORAGE_LARGE_BLOB_PAGE
+                + if large_blob_set { raw[LARGE_BLOB_PAGE] as u32 & 1 } else { 0 },
+            large_blob_length: if large_blob_set { large_blob_length } else { 0 },
+            enterprise_attestation: raw[ENTERPRISE_ATTESTATION] == 0x01,
+            always_uv: raw[ALWAYS_UV] == 0x01,
+            force_pin_change: raw[FORCE_PIN_CHANGE] == 0x01,
+            min_pin_length: if raw[MIN_PIN_LENGTH] != 0xff {
+                raw[MIN_PIN_LENGTH]
+            } else {
+                FIDO2_PIN_MIN_LENGTH as u8
+            },
+            min_pin_length_rp_ids,
+            min_pin_length_rp_id_count,
+        })
+    }
+    fn pack(&self) -> [u8; STATE_SIZE] {
+        let mut raw = [0xffu8; STATE_SIZE];
+        LittleEndian::write_u16(&mut raw[0..2], STATE_MARKER);
+        LittleEndian::write_u16(&mut raw[2..4], STATE_VERSION);
+        LittleEndian::write_u32(&mut raw[4..8], self.seq);
+        raw[MASTER_SECRET..MASTER_SECRET + 32].copy_from_slice(&*self.master_secret);
+        LittleEndian::write_u32(&mut raw[COUNTER_BASE..COUNTER_BASE + 4], self.counter_base);
+        if let Some(pin_hash) = &self.pin_hash {
+            raw[PIN_HASH..PIN_HASH + 16].copy_from_slice(&**pin_hash);
+            raw[PIN_LENGTH] = self.pin_length;
+        }
+        raw[PIN_RETRIES] = self.pin_retries;
+        if self.reset_pending {
+            raw[RESET_PENDING] = 0x01;
+        }
+        if self.large_blob_length != 0 {
+            raw[LARGE_BLOB_PAGE] = (self.large_blob_page - FIDO2_STORAGE_LARGE_BLOB_PAGE) as u8;
+            LittleEndian::write_u16(
+                &mut raw[LARGE_BLOB_LENGTH..LARGE_BLOB_LENGTH + 2],
+                self.large_blob_length,
+            );
+        }
+        if self.enterprise_attestation {
+            raw[ENTERPRISE_ATTESTATION] = 0x01;
+        }
+        if self.always_uv {
+            raw[ALWAYS_UV] = 0x01;
+        }
+        if self.force_pin_change {
+            raw[FORCE_PIN_CHANGE] = 0x01;
+        }
+        raw[MIN_PIN_LENGTH] = self.min_pin_length;
+        raw[MIN_PIN_LENGTH_RP_IDS] = self.min_pin_length_rp_id_count as u8;
+        for (k, v) in self.min_pin_length_rp_ids[..self.min_pin_length_rp_id_count]
+            .iter()
+            .enumerate()
+        {
+            let offset = MIN_PIN_LENGTH_RP_IDS + 1 + k * 32;
+            raw[offset..offset + 32].copy_from_slice(v);
+        }
+        let checksum = checksum(&raw);
+        raw[8..16].copy_from_slice(&checksum[..8]);
+        raw
+    }
+    pub fn load(storage: &mut impl FIDO2Storage) -> Result<Option<FIDO2DeviceState>, FIDO2InternalError> {
+        let mut found: Option<FIDO2DeviceState> = None;
+        for page in FIDO2_STORAGE_STATE_PAGE..FIDO2_STORAGE_STATE_PAGE + 2 {
+            let mut raw = [0u8; STATE_SIZE];
+            storage.read(page_offset(page), &mut raw)?;
+            if let Some(state) = FIDO2DeviceState::unpack(&raw, page) {
+                if found.as_ref().is_none_or(|f| state.seq > f.seq) {
+                    found = Some(state);
+                }
+            }
+        }
+        let Some(mut state) = found else {
+            return Ok(None);
+        };
+        // the last entry written after this copy of the state wins, torn
+        // entries are skipped
+        for slot in 0..RETRIES_SLOTS {
+            let mut raw = [0u8; RETRIES_ENTRY_SIZE as usize];
+            storage.read(retries_entry_offset(slot), &mut raw)?;
+            if raw == [0xff; RETRIES_ENTRY_SIZE as usize] {
+                break;
+            }
+            if LittleEndian::read_u32(&raw[0..4]) == state.seq && raw[4] == !raw[5] && raw[6..8] == [0, 0] {
+                state.pin_retries = raw[4].min(FIDO2_PIN_MAX_RETRIES);
+            }
+        }
+        Ok(Some(state))
+    }
+    // factory state with a fresh master secret, saving it replaces the current
+    // state in one step, the pages it used to protect still have to be erased
+    pub fn reset(&self, crypto: &mut impl FIDO2CryptoProvider) -> FIDO2DeviceState {
+        let mut state = FIDO2DeviceState::new(crypto);
+        state.seq = self.seq;
+        state.page = self.page;
+        state.reset_pending = true;
+        state
+    }
+    // a new device gets a fresh master secret
+    pub fn load_or_init(
+        storage: &mut impl FIDO2Storage,
+        crypto: &mut impl FIDO2CryptoProvider,
+    ) -> Result<FIDO2DeviceState, FIDO2InternalError> {
+        if let Some(state) = FIDO2DeviceState::load(storage)? {
+            return Ok(state);
+        }
+        let mut state = FIDO2DeviceState::new(crypto);
+        state.save(storage)?;
+        Ok(state)
+    }
+    pub fn save(&mut self, storage: &mut impl FIDO2Storage) -> Result<(), FIDO2InternalError> {
+        // always overwrite the older copy
+        let page = if self.page == FIDO2_STORAGE_STATE_PAGE {
+            FIDO2_STORAGE_STATE_PAGE + 1
+        } else {
+            FIDO2_STORAGE_STATE_PAGE
+        };
+        self.seq = self.seq.wrapping_add(1);
+        let raw = self.pack();
+        storage.erase_page(page)?;
+        // marker last
+        storage.write(page_offset(page) + 2, &raw[2..])?;
+        storage.write(page_offset(page), &raw[..2])?;
+        self.page = page;
+        Ok(())
+    }
+    // pin_retries changed, everything else stays as it was saved
+    pub fn save_pin_retries(&mut self, storage: &mut impl FIDO2Storage) -> Result<(), FIDO2InternalError> {
+        let mut slot = 0;
+        while slot < RETRIES_SLOTS
+            && !is_erased(storage, retries_entry_offset(slot), RETRIES_ENTRY_SIZE)?
+        {
+            slot += 1;
+        }
+        if slot >= RETRIES_SLOTS {
+            self.save(storage)?;
+            return storage.erase_page(FIDO2_STORAGE_PIN_RETRIES_PAGE);
+        }
+        let mut raw = [0u8; RETRIES_ENTRY_SIZE as usize];
+        LittleEndian::write_u32(&mut raw[0..4], self.seq);
+        raw[4] = self.pin_retries;
+        raw[5] = !self.pin_retries;
+        storage.write(retries_entry_offset(slot), &raw)
+    }
+}
@@ -0,0 +1,340 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use core::marker::PhantomData;
+
+use byteorder::{ByteOrder, LittleEndian};
+use rand_core::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    consts::{FIDO2_STORAGE_PAGE_SIZE, FIDO2_STORAGE_SEED_PAGE},
+    fido2_crypto::FIDO2HashProvider,
+    fido2_internal_error::FIDO2InternalError,
+    fido2_log::{self, log_error, FIDO2Secret},
+    fido2_storage::{page_offset, FIDO2Storage},
+};
+
+// Trait
+
+// raw output of a physical noise source, one sample at a time
+pub(crate) trait FIDO2NoiseSource {
+    fn sample(&mut self) -> u8;
+}
+
+// Health tests
+
+// SP 800-90B 4.4, the cutoffs assume H = 0.5 bit of min-entropy per sample and
+// a false positive rate of 2^-20:
+// - repetition count: C = 1 + ceil(20 / H) = 41
+// - adaptive proportion: C = 410 for W = 512 and H = 0.5 (4.4.2, table 2)
+// H is a design assumption, not a measurement. A sample mixes the low bytes of
+// two ADC conversions (Vrefint and the temperature sensor) with the conversion
+// time in core cycles: the total unadjusted error of the ADC is typically
+// +-2 LSB (STM32F103 datasheet, ADC characteristics), so the low bits wander
+// over a few codes, and the ADC clock is prescaled from APB2, which makes the
+// cycle count jitter. 0.5 bit stays below what those should give, it has to be
+// confirmed per board with the SP 800-90B non-IID estimators (ea_non_iid) on
+// raw samples
+const REPETITION_COUNT_CUTOFF: u32 = 41;
+const ADAPTIVE_PROPORTION_WINDOW: u32 = 512;
+const ADAPTIVE_PROPORTION_CUTOFF: u32 = 410;
+
+// continuous repetition count and adaptive proportion tests, a failure is permanent
+#[derive(Debug, Default)]
+pub(crate) struct FIDO2HealthTests {
+    last: u8,
+    repetitions: u32,
+    reference: u8,
+    window: u32,
+    matches: u32,
+    failed: bool,
+}
+impl FIDO2HealthTests {
+    // false once the source looks stuck or biased
+    pub fn check(&mut self, sample: u8) -> bool {
+        if self.repetitions > 0 && sample == self.last {
+            self.repetitions += 1;
+            if self.repetitions >= REPETITION_COUNT_CUTOFF {
+                self.failed = true;
+            }
+        } else {
+            self.last = sample;
+            self.repetitions = 1;
+        }
+        if self.window == 0 {
+            self.reference = sample;
+            self.matches = 1;
+        } else if sample == self.reference {
+            self.matches += 1;
+            if self.matches >= ADAPTIVE_PROPORTION_CUTOFF {
+                self.failed = true;
+            }
+        }
+        self.window = (self.window + 1) % ADAPTIVE_PROPORTION_WINDOW;
+        !self.failed
+    }
+}
+
+// Conditioning
+
+// 256 bits of min-entropy with a safety factor of two
+const SAMPLES_PER_SEED: usize = 1024;
+
+// SHA-256 over health tested samples
+fn condition(
+    noise: &mut impl FIDO2NoiseSource,
+    health: &mut FIDO2HealthTests,
+) -> Result<[u8; 32], FIDO2InternalError> {
+    let mut h = Sha256::new();
+    for _ in 0..SAMPLES_PER_SEED {
+        let sample = noise.sample();
+        if !health.check(sample) {
+            log_error!("noise source failed the health tests");
+            return Err(FIDO2InternalError::EntropyError);
+        }
+        h.update([sample]);
+    }
+    Ok(h.finalize().into())
+}
+
+// HMAC-DRBG
+
+// SP 800-90A 10.1.2 with SHA-256, without prediction resistance. HMAC comes
+// from the crypto provider (H), so the power-up self test covers it
+#[derive(Debug)]
+pub(crate) struct FIDO2HmacDrbg<H: FIDO2HashProvider> {
+    key: FIDO2Secret<[u8; 32]>,
+    v: FIDO2Secret<[u8; 32]>,
+    reseed_counter: u32,
+    hash: PhantomData<H>,
+}
+impl<H: FIDO2HashProvider> FIDO2HmacDrbg<H> {
+    fn hmac(key: &[u8; 32], parts: &[&[u8]]) -> [u8; 32] {
+        H::hmac_sha256(key, parts)
+    }
+    fn update(&mut self, data: &[&[u8]]) {
+        for round in [0x00u8, 0x01] {
+            if round == 0x01 && data.iter().all(|p| p.is_empty()) {
+                break;
+            }
+            let mut parts: [&[u8]; 5] = [&self.v[..], &[round], &[], &[], &[]];
+            parts[2..2 + data.len()].copy_from_slice(data);
+            *self.key = Self::hmac(&self.key, &parts[..2 + data.len()]);
+            *self.v = Self::hmac(&self.key, &[&self.v[..]]);
+        }
+    }
+    pub fn new(entropy: &[u8], nonce: &[u8], personalization: &[u8]) -> FIDO2HmacDrbg<H> {
+        let mut drbg = FIDO2HmacDrbg {
+            key: FIDO2Secret([0x00; 32]),
+            v: FIDO2Secret([0x01; 32]),
+            reseed_counter: 1,
+            hash: PhantomData,
+        };
+        drbg.update(&[entropy, nonce, personalization]);
+        drbg
+    }
+    pub fn reseed(&mut self, entropy: &[u8]) {
+        self.update(&[entropy]);
+        self.reseed_counter = 1;
+    }
+    pub fn generate(&mut self, out: &mut [u8]) {
+        for chunk in out.chunks_mut(32) {
+            *self.v = Self::hmac(&self.key, &[&self.v[..]]);
+            chunk.copy_from_slice(&self.v[..chunk.len()]);
+        }
+        self.update(&[]);
+        self.reseed_counter += 1;
+    }
+}
+
+// Seed log
+
+// every boot appends a fresh seed to the seed page, the page is erased once it is full
+const SEED_SLOTS: u32 = FIDO2_STORAGE_PAGE_SIZE / 32;
+
+// latest seed and the slot after it
+fn load_seed(storage: &mut impl FIDO2Storage) -> Result<([u8; 32], u32), FIDO2InternalError> {
+    let mut seed = [0xff; 32];
+    let mut next_slot = 0;
+    while next_slot < SEED_SLOTS {
+        let mut raw = [0u8; 32];
+        storage.read(page_offset(FIDO2_STORAGE_SEED_PAGE) + next_slot * 32, &mut raw)?;
+        if raw == [0xff; 32] {
+            break;
+        }
+        seed = raw;
+        next_slot += 1;
+    }
+    Ok((seed, next_slot))
+}
+fn store_seed(
+    storage: &mut impl FIDO2Storage,
+    seed: &[u8; 32],
+    mut next_slot: u32,
+) -> Result<(), FIDO2InternalError> {
+    if next_slot >= SEED_SLOTS {
+        storage.erase_page(FIDO2_STORAGE_SEED_PAGE)?;
+        next_slot = 0;
+    }
+    storage.write(page_offset(FIDO2_STORAGE_SEED_PAGE) + next_slot * 32, seed)
+}
+
+// RNG
+
+// generate requests between two reseeds from the noise source
+const RESEED_INTERVAL: u32 = 1024;
+
+// noise source -> health tests -> SHA-256 -> HMAC-DRBG
+pub struct FIDO2EntropyRng<N: FIDO2NoiseSource, H: FIDO2HashProvider> {
+    noise: N,
+    health: FIDO2HealthTests,
+    drbg: FIDO2HmacDrbg<H>,
+}
+impl<N: FIDO2NoiseSource, H: FIDO2HashProvider> FIDO2EntropyRng<N, H> {
+    // instantiated from fresh noise, the nonce and the seed left by the previous
+    // boot, then leaves a new seed for the next boot. the health tests over the
+    // first samples are the startup test of the source
+    pub fn new(
+        mut noise: N,
+        storage: &mut impl FIDO2Storage,
+        nonce: &[u8],
+    ) -> Result<FIDO2EntropyRng<N, H>, FIDO2InternalError> {
+        let mut health = FIDO2HealthTests::default();
+        let entropy = condition(&mut noise, &mut health)?;
+        let (seed, next_slot) = load_seed(storage)?;
+        let mut drbg = FIDO2HmacDrbg::new(&entropy, nonce, &seed);
+        let mut seed = [0u8; 32];
+        drbg.generate(&mut seed);
+        store_seed(storage, &seed, next_slot)?;
+        Ok(FIDO2EntropyRng {
+            noise,
+            health,
+            drbg,
+        })
+    }
+}
+impl<N: FIDO2NoiseSource, H: FIDO2HashProvider> RngCore for FIDO2EntropyRng<N, H> {
+    fn next_u32(&mut self) -> u32 {
+        let mut buf = [0u8; 4];
+        self.fill_bytes(&mut buf);
+        LittleEndian::read_u32(&buf)
+    }
+    fn next_u64(&mut self) -> u64 {
+        let mut buf = [0u8; 8];
+        self.fill_bytes(&mut buf);
+        LittleEndian::read_u64(&buf)
+    }
+    // a failed noise source is fatal, keys must never come from a stale DRBG
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        if self.drbg.reseed_counter > RESEED_INTERVAL {
+            match condition(&mut self.noise, &mut self.health) {
+                Ok(entropy) => self.drbg.reseed(&entropy),
+                Err(_) => {
+                    // panic_reset, the log would be cut off
+                    fido2_log::flush();
+                    panic!("entropy source failure")
+                }
+            }
+        }
+        self.drbg.generate(dest);
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+impl<N: FIDO2NoiseSource, H: FIDO2HashProvider> CryptoRng for FIDO2EntropyRng<N, H> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fido2_crypto::FIDO2SoftwareHash, fido2_crypto_self_test::hex};
+
+    // CAVP HMAC_DRBG.rsp, [SHA-256], no prediction resistance, no personalization
+    // string or additional input, COUNT = 0: instantiate, generate twice
+    #[test]
+    fn hmac_drbg_cavp() {
+        let entropy: [u8; 32] =
+            hex("ca851911349384bffe89de1cbdc46e6831e44d34a4fb935ee285dd14b71a7488");
+        let nonce: [u8; 16] = hex("659ba96c601dc69fc902940805ec0ca8");
+        let expected: [u8; 128] = hex(concat!(
+            "e528e9abf2dece54d47c7e75e5fe302149f817ea9fb4bee6f4199697d04d5b89",
+            "d54fbb978a15b5c443c9ec21036d2460b6f73ebad0dc2aba6e624abf07745bc1",
+            "07694bb7547bb0995f70de25d6b29e2d3011bb19d27676c07162c8b5ccde0668",
+            "961df86803482cb37ed6d5c0bb8d50cf1f50d476aa0458bdaba806f48be9dcb8",
+        ));
+        let mut drbg = FIDO2HmacDrbg::<FIDO2SoftwareHash>::new(&entropy, &nonce, &[]);
+        let mut out = [0u8; 128];
+        drbg.generate(&mut out);
+        drbg.generate(&mut out);
+        assert_eq!(out, expected);
+        assert_eq!(drbg.reseed_counter, 3);
+        drbg.reseed(&entropy);
+        assert_eq!(drbg.reseed_counter, 1);
+    }
+
+    #[test]
+    fn repetition_count_cutoff() {
+        let mut health = FIDO2HealthTests::default();
+        for _ in 1..REPETITION_COUNT_CUTOFF {
+            assert!(health.check(0x5a));
+        }
+        assert!(!health.check(0x5a));
+        // a failure is permanent
+        assert!(!health.check(0x00));
+        // runs one shorter than the cutoff, broken up
+        let mut health = FIDO2HealthTests::default();
+        for run in 0..3 {
+            for _ in 1..REPETITION_COUNT_CUTOFF {
+                assert!(health.check(0x5a));
+            }
+            assert!(health.check(run));
+        }
+    }
+
+    // one window of the reference value 0xaa, which comes first, and every fifth
+    // sample from the end something else
+    fn window(matches: usize) -> Vec<u8> {
+        let mut samples = vec![0xaa; ADAPTIVE_PROPORTION_WINDOW as usize];
+        let last = samples.len() - 1;
+        for k in 0..samples.len() - matches {
+            samples[last - 5 * k] = k as u8;
+        }
+        samples
+    }
+
+    #[test]
+    fn adaptive_proportion_cutoff() {
+        let cutoff = ADAPTIVE_PROPORTION_CUTOFF as usize;
+        // just below the cutoff, window after window
+        let mut health = FIDO2HealthTests::default();
+        for _ in 0..4 {
+            for sample in window(cutoff - 1) {
+                assert!(health.check(sample));
+            }
+        }
+        // the sample that matches the reference for the cutoff-th time fails
+        let mut health = FIDO2HealthTests::default();
+        let mut matches = 0;
+        for sample in window(cutoff) {
+            matches += (sample == 0xaa) as usize;
+            assert_eq!(health.check(sample), matches < cutoff);
+        }
+    }
+}
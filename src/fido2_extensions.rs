@@ -0,0 +1,270 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    fido2_authenticator::FIDO2Authenticator,
+    fido2_cbor::{FIDO2CborReader, FIDO2CborWriter},
+    fido2_client_pin::{FIDO2PinProtocol, FIDO2SharedSecret},
+    fido2_commands::FIDO2PacketCommandResponse,
+    fido2_cose::FIDO2CoseKey,
+    fido2_crypto::FIDO2CryptoProvider,
+    fido2_key_wrap::FIDO2CredentialKey,
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::FIDO2Storage,
+};
+
+// one or two salts, encrypted with protocol 2 this is 16 + 64 bytes
+pub(crate) const FIDO2_MAX_HMAC_SECRET_OUTPUT_LENGTH: usize = 80;
+pub(crate) const FIDO2_MAX_EXTENSIONS_OUTPUT_LENGTH: usize = 160;
+pub(crate) const FIDO2_SUPPORTED_EXTENSIONS: [&str; 5] =
+    ["credBlob", "credProtect", "hmac-secret", "largeBlobKey", "minPinLength"];
+
+// credProtect levels, credentials made before it was supported have 0
+pub(crate) const FIDO2_CRED_PROTECT_UV_OPTIONAL: u8 = 0x01;
+pub(crate) const FIDO2_CRED_PROTECT_UV_OPTIONAL_WITH_CREDENTIAL_ID_LIST: u8 = 0x02;
+pub(crate) const FIDO2_CRED_PROTECT_UV_REQUIRED: u8 = 0x03;
+
+// per credential key for the large-blob array, only handed out for credentials
+// made with largeBlobKey
+pub(crate) fn large_blob_key<C: FIDO2CryptoProvider>(key: &FIDO2CredentialKey) -> [u8; 32] {
+    C::hmac_sha256(&*key.private_key, &[b"unsafe{key} large blob key"])
+}
+
+// whether a credential may be used, allow_list is true if the platform named it
+pub(crate) fn cred_protect_allows(level: u8, uv: bool, allow_list: bool) -> bool {
+    match level {
+        FIDO2_CRED_PROTECT_UV_OPTIONAL_WITH_CREDENTIAL_ID_LIST => uv || allow_list,
+        FIDO2_CRED_PROTECT_UV_REQUIRED => uv,
+        _ => true,
+    }
+}
+
+// MakeCredential
+
+#[derive(Debug, Default)]
+pub(crate) struct FIDO2MakeCredentialExtensions<'a> {
+    pub cred_blob: Option<&'a [u8]>,
+    pub cred_protect: Option<u8>,
+    pub hmac_secret: bool,
+    pub large_blob_key: Option<bool>,
+    pub min_pin_length: bool,
+}
+impl<'a> FIDO2MakeCredentialExtensions<'a> {
+    pub fn unpack(packet: &'a [u8]) -> Result<FIDO2MakeCredentialExtensions<'a>, FIDO2StatusCode> {
+        let mut r = FIDO2CborReader::new(packet);
+        let mut extensions = FIDO2MakeCredentialExtensions::default();
+        for _ in 0..r.map()? {
+            match r.text()? {
+                "credBlob" => extensions.cred_blob = Some(r.bytes()?),
+                "credProtect" => {
+                    // unknown levels are ignored
+                    let level = r.unsigned()?;
+                    if level >= FIDO2_CRED_PROTECT_UV_OPTIONAL as u64
+                        && level <= FIDO2_CRED_PROTECT_UV_REQUIRED as u64
+                    {
+                        extensions.cred_protect = Some(level as u8);
+                    }
+                }
+                "hmac-secret" => extensions.hmac_secret = r.bool()?,
+                "largeBlobKey" => extensions.large_blob_key = Some(r.bool()?),
+                "minPinLength" => extensions.min_pin_length = r.bool()?,
+                _ => r.skip()?,
+            }
+        }
+        Ok(extensions)
+    }
+}
+
+// GetAssertion
+
+#[derive(Debug)]
+pub(crate) struct FIDO2HmacSecretInput<'a> {
+    pub key_agreement: FIDO2CoseKey,
+    pub salt_enc: &'a [u8],
+    pub salt_auth: &'a [u8],
+    pub pin_uv_auth_protocol: Option<u64>,
+}
+impl<'a> FIDO2HmacSecretInput<'a> {
+    pub fn unpack(r: &mut FIDO2CborReader<'a>) -> Result<FIDO2HmacSecretInput<'a>, FIDO2StatusCode> {
+        let mut key_agreement: Option<FIDO2CoseKey> = None;
+        let mut salt_enc: Option<&[u8]> = None;
+        let mut salt_auth: Option<&[u8]> = None;
+        let mut pin_uv_auth_protocol: Option<u64> = None;
+        for _ in 0..r.map()? {
+            match r.unsigned()? {
+                0x01 => key_agreement = Some(FIDO2CoseKey::read_ecdh(r)?),
+                0x02 => salt_enc = Some(r.bytes()?),
+                0x03 => salt_auth = Some(r.bytes()?),
+                0x04 => pin_uv_auth_protocol = Some(r.unsigned()?),
+                _ => r.skip()?,
+            }
+        }
+        Ok(FIDO2HmacSecretInput {
+            key_agreement: key_agreement.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?,
+            salt_enc: salt_enc.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?,
+            salt_auth: salt_auth.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?,
+            pin_uv_auth_protocol,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct FIDO2GetAssertionExtensions<'a> {
+    pub cred_blob: bool,
+    pub hmac_secret: Option<FIDO2HmacSecretInput<'a>>,
+    pub large_blob_key: Option<bool>,
+}
+impl<'a> FIDO2GetAssertionExtensions<'a> {
+    pub fn unpack(packet: &'a [u8]) -> Result<FIDO2GetAssertionExtensions<'a>, FIDO2StatusCode> {
+        let mut r = FIDO2CborReader::new(packet);
+        let mut extensions = FIDO2GetAssertionExtensions::default();
+        for _ in 0..r.map()? {
+            match r.text()? {
+                "credBlob" => extensions.cred_blob = r.bool()?,
+                "hmac-secret" => extensions.hmac_secret = Some(FIDO2HmacSecretInput::unpack(&mut r)?),
+                "largeBlobKey" => extensions.large_blob_key = Some(r.bool()?),
+                _ => r.skip()?,
+            }
+        }
+        Ok(extensions)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FIDO2HmacSecretSalt {
+    pub protocol: FIDO2PinProtocol,
+    pub shared_secret: FIDO2SharedSecret,
+    pub salt: [u8; 64],
+    pub salt_length: usize,
+}
+
+// checked GetAssertion extensions, kept for authenticatorGetNextAssertion
+#[derive(Debug, Default)]
+pub(crate) struct FIDO2AssertionExtensions {
+    pub cred_blob: bool,
+    // decrypted salts
+    pub hmac_secret: Option<FIDO2HmacSecretSalt>,
+    pub large_blob_key: bool,
+}
+
+// authenticatorData extensions
+#[derive(Debug, Default)]
+pub(crate) struct FIDO2ExtensionsOutput<'a> {
+    // MakeCredential
+    pub cred_blob_stored: Option<bool>,
+    pub cred_protect: Option<u8>,
+    pub hmac_secret_created: bool,
+    pub min_pin_length: Option<u8>,
+    // GetAssertion
+    pub cred_blob: Option<&'a [u8]>,
+    // encrypted outputs
+    pub hmac_secret: Option<&'a [u8]>,
+}
+impl<'a> FIDO2ExtensionsOutput<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.cred_blob_stored.is_none()
+            && self.cred_protect.is_none()
+            && !self.hmac_secret_created
+            && self.min_pin_length.is_none()
+            && self.cred_blob.is_none()
+            && self.hmac_secret.is_none()
+    }
+}
+impl<'a> FIDO2PacketCommandResponse for FIDO2ExtensionsOutput<'a> {
+    fn apply(self, arr: &mut [u8]) -> Option<u16> {
+        let mut w = FIDO2CborWriter::new(arr);
+        w.map(
+            self.cred_blob_stored.is_some() as usize
+                + self.cred_protect.is_some() as usize
+                + self.hmac_secret_created as usize
+                + self.cred_blob.is_some() as usize
+                + self.hmac_secret.is_some() as usize
+                + self.min_pin_length.is_some() as usize,
+        );
+        if let Some(stored) = self.cred_blob_stored {
+            w.text("credBlob").bool(stored);
+        }
+        if let Some(blob) = self.cred_blob {
+            w.text("credBlob").bytes(blob);
+        }
+        if let Some(level) = self.cred_protect {
+            w.text("credProtect").unsigned(level as u64);
+        }
+        if self.hmac_secret_created {
+            w.text("hmac-secret").bool(true);
+        }
+        if let Some(output) = self.hmac_secret {
+            w.text("hmac-secret").bytes(output);
+        }
+        if let Some(length) = self.min_pin_length {
+            w.text("minPinLength").unsigned(length as u64);
+        }
+        w.finish()
+    }
+}
+
+impl<S: FIDO2Storage, C: FIDO2CryptoProvider> FIDO2Authenticator<S, C> {
+    // checks saltAuth and decrypts the salts
+    pub fn hmac_secret_salt(
+        &mut self,
+        input: &FIDO2HmacSecretInput,
+    ) -> Result<FIDO2HmacSecretSalt, FIDO2StatusCode> {
+        let protocol = FIDO2PinProtocol::from_u64(input.pin_uv_auth_protocol.unwrap_or(1))?;
+        let shared_secret = self.pin_shared_secret(protocol, &input.key_agreement)?;
+        if !protocol.verify::<C>(&*shared_secret.hmac_key, &[input.salt_enc], input.salt_auth) {
+            return Err(FIDO2StatusCode::Ctap2ErrPinAuthInvalid);
+        }
+        let mut salt = [0u8; 64];
+        let salt_length = match protocol.decrypt::<C>(&shared_secret, input.salt_enc, &mut salt) {
+            Some(len) if len == 32 || len == 64 => len,
+            _ => return Err(FIDO2StatusCode::Ctap1ErrInvalidLength),
+        };
+        Ok(FIDO2HmacSecretSalt {
+            protocol,
+            shared_secret,
+            salt,
+            salt_length,
+        })
+    }
+    // HMAC-SHA-256(CredRandom, salt) for each salt, encrypted with the shared secret
+    pub fn hmac_secret_output(
+        &mut self,
+        salt: &FIDO2HmacSecretSalt,
+        key: &FIDO2CredentialKey,
+        uv: bool,
+        out: &mut [u8; FIDO2_MAX_HMAC_SECRET_OUTPUT_LENGTH],
+    ) -> Result<usize, FIDO2StatusCode> {
+        // CredRandom is derived from the private key, there is one with and one without UV
+        let cred_random = C::hmac_sha256(
+            &*key.private_key,
+            &[b"unsafe{key} cred random", &[uv as u8]],
+        );
+        let mut output = [0u8; 64];
+        for (i, s) in salt.salt[..salt.salt_length].chunks(32).enumerate() {
+            output[i * 32..i * 32 + 32].copy_from_slice(&C::hmac_sha256(&cred_random, &[s]));
+        }
+        salt.protocol
+            .encrypt(
+                &mut self.crypto,
+                &salt.shared_secret,
+                &output[..salt.salt_length],
+                out,
+            )
+            .ok_or(FIDO2StatusCode::Ctap1ErrOther)
+    }
+}
@@ -0,0 +1,183 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use stm32f1xx_hal::{flash::FlashWriter, pac};
+
+use crate::{
+    consts::{FIDO2_STORAGE_OFFSET, FIDO2_STORAGE_PAGES, FIDO2_STORAGE_PAGE_SIZE},
+    fido2_internal_error::FIDO2InternalError,
+    fido2_log::{log_error, log_warn},
+    fido2_storage::{page_offset, FIDO2Storage},
+};
+
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xcdef_89ab;
+
+// option bytes: RDP, USER, Data0, Data1, WRP0 ~ WRP3, each one the low byte of
+// a halfword, the hardware writes the complement to the high byte
+const OPTION_BYTES: u32 = 0x1fff_f800;
+const OPTION_BYTE_COUNT: usize = 8;
+const OPTION_RDP: usize = 0;
+const OPTION_WRP0: usize = 4;
+// 0xa5 is unprotected, anything else is level 1, the F1 has no level 2
+const RDP_LEVEL1: u8 = 0x00;
+const RDP_UNPROTECTED: u8 = 0xa5;
+// a cleared WRP bit protects 4 pages of 1K
+const WRP_PAGES: u32 = 4;
+
+// FIDO2Storage on the internal flash of the STM32F103
+pub struct FIDO2FlashStorage<'a> {
+    writer: FlashWriter<'a>,
+}
+impl<'a> FIDO2FlashStorage<'a> {
+    pub fn new(writer: FlashWriter<'a>) -> FIDO2FlashStorage<'a> {
+        FIDO2FlashStorage { writer }
+    }
+    fn check(offset: u32, len: usize) -> Result<u32, FIDO2InternalError> {
+        if offset as usize + len > (FIDO2_STORAGE_PAGES * FIDO2_STORAGE_PAGE_SIZE) as usize {
+            log_error!("flash access out of the storage: {:x}+{}", offset, len);
+            return Err(FIDO2InternalError::StorageError);
+        }
+        Ok(FIDO2_STORAGE_OFFSET + offset)
+    }
+    fn read_option_bytes() -> [u8; OPTION_BYTE_COUNT] {
+        let mut bytes = [0u8; OPTION_BYTE_COUNT];
+        for (k, byte) in bytes.iter_mut().enumerate() {
+            let address = (OPTION_BYTES + 2 * k as u32) as *const u16;
+            *byte = unsafe { core::ptr::read_volatile(address) } as u8;
+        }
+        bytes
+    }
+    fn wait_idle(flash: &pac::flash::RegisterBlock) -> Result<(), FIDO2InternalError> {
+        while flash.sr.read().bsy().bit_is_set() {}
+        let sr = flash.sr.read();
+        if sr.pgerr().bit_is_set() || sr.wrprterr().bit_is_set() {
+            log_error!("option bytes: flash error {:08x}", sr.bits());
+            return Err(FIDO2InternalError::StorageError);
+        }
+        Ok(())
+    }
+    // erases the option bytes and writes them back with the changes
+    fn erase_and_program(
+        flash: &pac::flash::RegisterBlock,
+        bytes: &[u8; OPTION_BYTE_COUNT],
+    ) -> Result<(), FIDO2InternalError> {
+        if flash.cr.read().optwre().bit_is_clear() {
+            log_error!("option bytes: still locked");
+            return Err(FIDO2InternalError::StorageError);
+        }
+        Self::wait_idle(flash)?;
+        flash.cr.modify(|_, w| w.opter().set_bit());
+        flash.cr.modify(|_, w| w.strt().set_bit());
+        let erased = Self::wait_idle(flash);
+        flash.cr.modify(|_, w| w.opter().clear_bit());
+        erased?;
+        // erased option bytes read as 0xff, RDP included, so the flash is
+        // never unprotected in between
+        flash.cr.modify(|_, w| w.optpg().set_bit());
+        let programmed = bytes
+            .iter()
+            .enumerate()
+            .filter(|(_, byte)| **byte != 0xff)
+            .try_for_each(|(k, byte)| {
+                let address = (OPTION_BYTES + 2 * k as u32) as *mut u16;
+                unsafe { core::ptr::write_volatile(address, *byte as u16) };
+                Self::wait_idle(flash)
+            });
+        flash.cr.modify(|_, w| w.optpg().clear_bit());
+        programmed
+    }
+    // the FlashWriter locks the flash again after each of its operations, so
+    // it is not in the middle of one here
+    fn program_option_bytes(bytes: &[u8; OPTION_BYTE_COUNT]) -> Result<(), FIDO2InternalError> {
+        let flash = unsafe { &*pac::FLASH::ptr() };
+        if flash.cr.read().lock().bit_is_set() {
+            flash.keyr.write(|w| unsafe { w.key().bits(FLASH_KEY1) });
+            flash.keyr.write(|w| unsafe { w.key().bits(FLASH_KEY2) });
+        }
+        flash
+            .optkeyr
+            .write(|w| unsafe { w.optkey().bits(FLASH_KEY1) });
+        flash
+            .optkeyr
+            .write(|w| unsafe { w.optkey().bits(FLASH_KEY2) });
+        // errors of earlier operations are sticky
+        flash
+            .sr
+            .write(|w| w.pgerr().set_bit().wrprterr().set_bit().eop().set_bit());
+        let result = Self::erase_and_program(flash, bytes);
+        flash
+            .cr
+            .modify(|_, w| w.optwre().clear_bit().lock().set_bit());
+        result?;
+        if Self::read_option_bytes() != *bytes {
+            log_error!("option bytes: read back differs");
+            return Err(FIDO2InternalError::StorageError);
+        }
+        Ok(())
+    }
+}
+impl<'a> FIDO2Storage for FIDO2FlashStorage<'a> {
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), FIDO2InternalError> {
+        let address = Self::check(offset, buf.len())?;
+        let data = self.writer.read(address, buf.len()).map_err(|e| {
+            log_error!("flash read at {:08x}: {:?}", address, e);
+            FIDO2InternalError::StorageError
+        })?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), FIDO2InternalError> {
+        let address = Self::check(offset, data.len())?;
+        self.writer.write(address, data).map_err(|e| {
+            log_error!("flash write at {:08x}: {:?}", address, e);
+            FIDO2InternalError::StorageError
+        })
+    }
+    fn erase_page(&mut self, page: u32) -> Result<(), FIDO2InternalError> {
+        let address = Self::check(page_offset(page), FIDO2_STORAGE_PAGE_SIZE as usize)?;
+        self.writer
+            .erase(address, FIDO2_STORAGE_PAGE_SIZE as usize)
+            .map_err(|e| {
+                log_error!("flash erase at {:08x}: {:?}", address, e);
+                FIDO2InternalError::StorageError
+            })
+    }
+    // the option bytes are loaded at reset, so the protection takes effect
+    // from the next boot on. called again once it is programmed it does nothing
+    fn protect_page(&mut self, page: u32) -> Result<(), FIDO2InternalError> {
+        let address = Self::check(page_offset(page), FIDO2_STORAGE_PAGE_SIZE as usize)?;
+        let group = address / FIDO2_STORAGE_PAGE_SIZE / WRP_PAGES;
+        let (wrp, bit) = (OPTION_WRP0 + (group / 8) as usize, 1u8 << (group % 8));
+        let current = Self::read_option_bytes();
+        let mut bytes = current;
+        if bytes[OPTION_RDP] == RDP_UNPROTECTED {
+            bytes[OPTION_RDP] = RDP_LEVEL1;
+        }
+        bytes[wrp] &= !bit;
+        if bytes == current {
+            return Ok(());
+        }
+        log_warn!(
+            "protecting the flash, pages {}~{} read only",
+            group * WRP_PAGES,
+            group * WRP_PAGES + WRP_PAGES - 1
+        );
+        Self::program_option_bytes(&bytes)
+    }
+}
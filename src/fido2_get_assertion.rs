@@ -0,0 +1,410 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    consts::FIDO2_GET_NEXT_ASSERTION_TIMEOUT_MS,
+    fido2_auth_data::{FIDO2AuthenticatorData, FIDO2_FLAG_UP, FIDO2_FLAG_UV, FIDO2_MAX_AUTH_DATA_LENGTH},
+    fido2_authenticator::{FIDO2Authenticator, FIDO2Platform},
+    fido2_cbor::{FIDO2CborReader, FIDO2CborWriter},
+    fido2_client_pin::FIDO2_PERMISSION_GA,
+    fido2_commands::FIDO2PacketCommandResponse,
+    fido2_credential_store::{FIDO2ResidentCredential, FIDO2_MAX_RESIDENT_CREDENTIALS},
+    fido2_crypto::FIDO2CryptoProvider,
+    fido2_extensions::{
+        cred_protect_allows, large_blob_key, FIDO2AssertionExtensions, FIDO2ExtensionsOutput,
+        FIDO2GetAssertionExtensions,
+        FIDO2_MAX_EXTENSIONS_OUTPUT_LENGTH, FIDO2_MAX_HMAC_SECRET_OUTPUT_LENGTH,
+    },
+    fido2_key_wrap::{unwrap, FIDO2CredentialKey},
+    fido2_make_credential::{read_credential_descriptor, FIDO2Options},
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::FIDO2Storage,
+};
+
+// GetAssertion
+
+#[derive(Debug)]
+pub(crate) struct FIDO2GetAssertionRequest<'a> {
+    pub rp_id: &'a str,
+    pub client_data_hash: &'a [u8],
+    pub allow_list: Option<&'a [u8]>,
+    pub extensions: FIDO2GetAssertionExtensions<'a>,
+    pub options: FIDO2Options,
+    pub pin_uv_auth_param: Option<&'a [u8]>,
+    pub pin_uv_auth_protocol: Option<u64>,
+}
+impl<'a> FIDO2GetAssertionRequest<'a> {
+    pub fn unpack(packet: &'a [u8]) -> Result<FIDO2GetAssertionRequest<'a>, FIDO2StatusCode> {
+        let mut r = FIDO2CborReader::new(packet);
+        let mut rp_id: Option<&str> = None;
+        let mut client_data_hash: Option<&[u8]> = None;
+        let mut allow_list: Option<&[u8]> = None;
+        let mut extensions = FIDO2GetAssertionExtensions::default();
+        let mut options = FIDO2Options::default();
+        let mut pin_uv_auth_param: Option<&[u8]> = None;
+        let mut pin_uv_auth_protocol: Option<u64> = None;
+        for _ in 0..r.map()? {
+            match r.unsigned()? {
+                0x01 => rp_id = Some(r.text()?),
+                0x02 => client_data_hash = Some(r.bytes()?),
+                0x03 => allow_list = Some(r.raw()?),
+                0x04 => extensions = FIDO2GetAssertionExtensions::unpack(r.raw()?)?,
+                0x05 => options = FIDO2Options::unpack(&mut r)?,
+                0x06 => pin_uv_auth_param = Some(r.bytes()?),
+                0x07 => pin_uv_auth_protocol = Some(r.unsigned()?),
+                _ => r.skip()?,
+            }
+        }
+        let rp_id = rp_id.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?;
+        let client_data_hash = client_data_hash.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?;
+        if client_data_hash.len() != 32 {
+            return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+        }
+        Ok(FIDO2GetAssertionRequest {
+            rp_id,
+            client_data_hash,
+            allow_list,
+            extensions,
+            options,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FIDO2GetAssertionResponse<'a> {
+    pub credential_id: &'a [u8],
+    pub auth_data: &'a [u8],
+    pub signature: &'a [u8],
+    // resident credentials only
+    pub user: Option<&'a FIDO2ResidentCredential>,
+    // user name and display name are only disclosed after user verification
+    pub user_details: bool,
+    pub number_of_credentials: Option<usize>,
+    pub large_blob_key: Option<&'a [u8; 32]>,
+}
+impl<'a> FIDO2PacketCommandResponse for FIDO2GetAssertionResponse<'a> {
+    fn apply(self, arr: &mut [u8]) -> Option<u16> {
+        let mut w = FIDO2CborWriter::new(arr);
+        w.map(
+            3 + self.user.is_some() as usize
+                + self.number_of_credentials.is_some() as usize
+                + self.large_blob_key.is_some() as usize,
+        );
+        w.unsigned(0x01).map(2);
+        w.text("id").bytes(self.credential_id);
+        w.text("type").text("public-key");
+        w.unsigned(0x02).bytes(self.auth_data);
+        w.unsigned(0x03).bytes(self.signature);
+        if let Some(user) = self.user {
+            w.unsigned(0x04);
+            user.write_user(&mut w, self.user_details);
+        }
+        if let Some(n) = self.number_of_credentials {
+            w.unsigned(0x05).unsigned(n as u64);
+        }
+        if let Some(key) = self.large_blob_key {
+            w.unsigned(0x07).bytes(key);
+        }
+        w.finish()
+    }
+}
+
+// GetNextAssertion
+
+#[derive(Debug)]
+pub(crate) struct FIDO2AssertionState {
+    pub rp_id_hash: [u8; 32],
+    pub client_data_hash: [u8; 32],
+    pub flags: u8,
+    pub extensions: FIDO2AssertionExtensions,
+    // resident credential slots, newest first
+    pub slots: [u8; FIDO2_MAX_RESIDENT_CREDENTIALS],
+    pub count: usize,
+    pub next: usize,
+    // uptime of the last GetAssertion or GetNextAssertion
+    pub last_ms: u64,
+}
+
+impl<S: FIDO2Storage, C: FIDO2CryptoProvider> FIDO2Authenticator<S, C> {
+    #[allow(clippy::too_many_arguments)]
+    fn assertion(
+        &mut self,
+        credential_id: &[u8],
+        key: &FIDO2CredentialKey,
+        rp_id_hash: &[u8; 32],
+        client_data_hash: &[u8],
+        flags: u8,
+        extensions: &FIDO2AssertionExtensions,
+        user: Option<&FIDO2ResidentCredential>,
+        number_of_credentials: Option<usize>,
+        out: &mut [u8],
+    ) -> Result<u16, FIDO2StatusCode> {
+        // only credentials made with hmac-secret answer it
+        let mut hmac_secret = [0u8; FIDO2_MAX_HMAC_SECRET_OUTPUT_LENGTH];
+        let hmac_secret_len = match &extensions.hmac_secret {
+            Some(salt) if key.hmac_secret => Some(self.hmac_secret_output(
+                salt,
+                key,
+                flags & FIDO2_FLAG_UV != 0,
+                &mut hmac_secret,
+            )?),
+            _ => None,
+        };
+        // non-resident credentials have an empty credBlob
+        let cred_blob = match user {
+            Some(cred) => cred.cred_blob.as_slice(),
+            None => &[],
+        };
+        let large_blob_key = if extensions.large_blob_key && key.large_blob_key {
+            Some(large_blob_key::<C>(key))
+        } else {
+            None
+        };
+        let output = FIDO2ExtensionsOutput {
+            cred_blob: if extensions.cred_blob { Some(cred_blob) } else { None },
+            hmac_secret: hmac_secret_len.map(|len| &hmac_secret[..len]),
+            ..Default::default()
+        };
+        let mut extensions_data = [0u8; FIDO2_MAX_EXTENSIONS_OUTPUT_LENGTH];
+        let extensions_len = if output.is_empty() {
+            None
+        } else {
+            Some(
+                output
+                    .apply(&mut extensions_data)
+                    .ok_or(FIDO2StatusCode::Ctap1ErrOther)? as usize,
+            )
+        };
+        let sign_count = self.counter.increment(&mut self.storage, &mut self.state)?;
+        let mut auth_data = [0u8; FIDO2_MAX_AUTH_DATA_LENGTH];
+        let auth_data_len = FIDO2AuthenticatorData {
+            rp_id_hash,
+            flags,
+            sign_count,
+            attested_credential: None,
+            extensions: extensions_len.map(|len| &extensions_data[..len]),
+        }
+        .apply(&mut auth_data)
+        .ok_or(FIDO2StatusCode::Ctap1ErrOther)? as usize;
+        let mut signature = [0u8; 72];
+        let signature_len = key
+            .sign::<C>(&[&auth_data[..auth_data_len], client_data_hash], &mut signature)
+            .ok_or(FIDO2StatusCode::Ctap1ErrOther)?;
+        FIDO2GetAssertionResponse {
+            credential_id,
+            auth_data: &auth_data[..auth_data_len],
+            signature: &signature[..signature_len],
+            user,
+            user_details: flags & FIDO2_FLAG_UV != 0,
+            number_of_credentials,
+            large_blob_key: large_blob_key.as_ref(),
+        }
+        .apply(out)
+        .ok_or(FIDO2StatusCode::Ctap1ErrOther)
+    }
+    pub fn get_assertion(
+        &mut self,
+        platform: &mut impl FIDO2Platform,
+        data: &[u8],
+        out: &mut [u8],
+    ) -> Result<u16, FIDO2StatusCode> {
+        let req = FIDO2GetAssertionRequest::unpack(data)?;
+        let rp_id_hash = C::sha256(&[req.rp_id.as_bytes()]);
+        let uv = self.check_pin_uv_auth_param(
+            platform,
+            req.pin_uv_auth_param,
+            req.pin_uv_auth_protocol,
+            req.client_data_hash,
+            FIDO2_PERMISSION_GA,
+            &rp_id_hash,
+        )?;
+        if !uv && self.state.always_uv {
+            return Err(if self.state.pin_hash.is_some() {
+                FIDO2StatusCode::Ctap2ErrPuatRequired
+            } else {
+                FIDO2StatusCode::Ctap2ErrPinNotSet
+            });
+        }
+        if req.options.rk.is_some() {
+            return Err(FIDO2StatusCode::Ctap2ErrUnsupportedOption);
+        }
+        // there is no built-in user verification
+        if req.options.uv == Some(true) {
+            return Err(FIDO2StatusCode::Ctap2ErrInvalidOption);
+        }
+        let up = req.options.up != Some(false);
+        let flags = if up { FIDO2_FLAG_UP } else { 0 } | if uv { FIDO2_FLAG_UV } else { 0 };
+        if req.extensions.large_blob_key == Some(false) {
+            return Err(FIDO2StatusCode::Ctap2ErrInvalidOption);
+        }
+        let extensions = FIDO2AssertionExtensions {
+            cred_blob: req.extensions.cred_blob,
+            hmac_secret: match &req.extensions.hmac_secret {
+                Some(input) => Some(self.hmac_secret_salt(input)?),
+                None => None,
+            },
+            large_blob_key: req.extensions.large_blob_key == Some(true),
+        };
+        // allowList: the first credential that was made by us for this rp
+        if let Some(allow_list) = req.allow_list {
+            let mut r = FIDO2CborReader::new(allow_list);
+            let mut found: Option<(&[u8], FIDO2CredentialKey, Option<usize>)> = None;
+            for _ in 0..r.array()? {
+                let id = match read_credential_descriptor(&mut r)? {
+                    Some(id) => id,
+                    None => continue,
+                };
+                if found.is_some() {
+                    continue;
+                }
+                let key = match unwrap::<C>(&self.state.master_secret, id, &rp_id_hash) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                if !cred_protect_allows(key.cred_protect, uv, true) {
+                    continue;
+                }
+                let slot = self.credentials.find_credential_id(&mut self.storage, id)?;
+                // a deleted resident credential is gone for good
+                if key.resident && slot.is_none() {
+                    continue;
+                }
+                found = Some((id, key, slot));
+            }
+            let (credential_id, key, slot) = found.ok_or(FIDO2StatusCode::Ctap2ErrNoCredentials)?;
+            let user = match slot {
+                Some(slot) => self.credentials.read(&mut self.storage, slot)?,
+                None => None,
+            };
+            if up {
+                platform.user_presence()?;
+            }
+            if uv {
+                self.pin.clear_after_use();
+            }
+            return self.assertion(
+                credential_id,
+                &key,
+                &rp_id_hash,
+                req.client_data_hash,
+                flags,
+                &extensions,
+                user.as_ref(),
+                None,
+                out,
+            );
+        }
+        // no allowList: resident credentials of the rp that credProtect lets us show
+        let mut found = [0u8; FIDO2_MAX_RESIDENT_CREDENTIALS];
+        let found_count = self
+            .credentials
+            .find(&mut self.storage, &rp_id_hash, &mut found)?;
+        let mut slots = [0u8; FIDO2_MAX_RESIDENT_CREDENTIALS];
+        let mut count = 0;
+        for slot in found[..found_count].iter() {
+            if let Some(cred) = self.credentials.read(&mut self.storage, *slot as usize)? {
+                if cred_protect_allows(cred.cred_protect, uv, false) {
+                    slots[count] = *slot;
+                    count += 1;
+                }
+            }
+        }
+        if count == 0 {
+            return Err(FIDO2StatusCode::Ctap2ErrNoCredentials);
+        }
+        if up {
+            platform.user_presence()?;
+        }
+        if uv {
+            self.pin.clear_after_use();
+        }
+        let mut state = FIDO2AssertionState {
+            rp_id_hash,
+            client_data_hash: req.client_data_hash.try_into().unwrap(),
+            flags,
+            extensions,
+            slots,
+            count,
+            next: 0,
+            last_ms: platform.uptime_ms(),
+        };
+        let length = self.next_resident_assertion(&mut state, true, out)?;
+        if state.next < state.count {
+            self.assertion = Some(state);
+        }
+        Ok(length)
+    }
+    fn next_resident_assertion(
+        &mut self,
+        state: &mut FIDO2AssertionState,
+        first: bool,
+        out: &mut [u8],
+    ) -> Result<u16, FIDO2StatusCode> {
+        let slot = state.slots[state.next] as usize;
+        state.next += 1;
+        let cred = self
+            .credentials
+            .read(&mut self.storage, slot)?
+            .ok_or(FIDO2StatusCode::Ctap2ErrNoCredentials)?;
+        let key = unwrap::<C>(
+            &self.state.master_secret,
+            cred.credential_id.as_slice(),
+            &state.rp_id_hash,
+        )
+        .ok_or(FIDO2StatusCode::Ctap2ErrInvalidCredential)?;
+        let number_of_credentials = if first && state.count > 1 {
+            Some(state.count)
+        } else {
+            None
+        };
+        self.assertion(
+            cred.credential_id.as_slice(),
+            &key,
+            &state.rp_id_hash,
+            &state.client_data_hash,
+            state.flags,
+            &state.extensions,
+            Some(&cred),
+            number_of_credentials,
+            out,
+        )
+    }
+    pub fn get_next_assertion(
+        &mut self,
+        platform: &mut impl FIDO2Platform,
+        out: &mut [u8],
+    ) -> Result<u16, FIDO2StatusCode> {
+        let mut state = self
+            .assertion
+            .take()
+            .ok_or(FIDO2StatusCode::Ctap2ErrNotAllowed)?;
+        // the platform has 30 seconds for each GetNextAssertion
+        let now = platform.uptime_ms();
+        if now.saturating_sub(state.last_ms) > FIDO2_GET_NEXT_ASSERTION_TIMEOUT_MS {
+            return Err(FIDO2StatusCode::Ctap2ErrNotAllowed);
+        }
+        state.last_ms = now;
+        let length = self.next_resident_assertion(&mut state, false, out)?;
+        if state.next < state.count {
+            self.assertion = Some(state);
+        }
+        Ok(length)
+    }
+}
@@ -0,0 +1,122 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    consts::{
+        FIDO2_MAX_CREDENTIAL_COUNT_IN_LIST, FIDO2_MAX_CREDENTIAL_ID_LENGTH,
+        FIDO2_MAX_CRED_BLOB_LENGTH, FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS,
+        FIDO2_MAX_SERIALIZED_LARGE_BLOB_ARRAY, FIDO2_MESSAGE_BUFFER_SIZE,
+    },
+    fido2_authenticator::FIDO2Authenticator,
+    fido2_cbor::FIDO2CborWriter,
+    fido2_commands::FIDO2PacketCommandResponse,
+    fido2_crypto::FIDO2CryptoProvider,
+    fido2_extensions::FIDO2_SUPPORTED_EXTENSIONS,
+    fido2_cose::FIDO2_SUPPORTED_ALGORITHMS,
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::FIDO2Storage,
+};
+
+// GetInfo
+
+#[derive(Debug)]
+pub(crate) struct FIDO2GetInfoResponse {
+    pub aaguid: [u8; 16],
+    pub client_pin: bool,
+    pub always_uv: bool,
+    pub enterprise_attestation: bool,
+    pub force_pin_change: bool,
+    pub min_pin_length: u8,
+    pub remaining_discoverable_credentials: u32,
+}
+impl FIDO2PacketCommandResponse for FIDO2GetInfoResponse {
+    fn apply(self, arr: &mut [u8]) -> Option<u16> {
+        let mut w = FIDO2CborWriter::new(arr);
+        w.map(16);
+        // versions
+        w.unsigned(0x01).array(2).text("FIDO_2_0").text("FIDO_2_1");
+        // extensions
+        w.unsigned(0x02).array(FIDO2_SUPPORTED_EXTENSIONS.len());
+        for extension in FIDO2_SUPPORTED_EXTENSIONS {
+            w.text(extension);
+        }
+        // aaguid
+        w.unsigned(0x03).bytes(&self.aaguid);
+        // options
+        w.unsigned(0x04).map(11);
+        w.text("ep").bool(self.enterprise_attestation);
+        w.text("rk").bool(true);
+        w.text("up").bool(true);
+        w.text("plat").bool(false);
+        w.text("alwaysUv").bool(self.always_uv);
+        w.text("credMgmt").bool(true);
+        w.text("authnrCfg").bool(true);
+        w.text("clientPin").bool(self.client_pin);
+        w.text("largeBlobs").bool(true);
+        w.text("pinUvAuthToken").bool(true);
+        w.text("setMinPINLength").bool(true);
+        // maxMsgSize
+        w.unsigned(0x05).unsigned(FIDO2_MESSAGE_BUFFER_SIZE as u64);
+        // pinUvAuthProtocols
+        w.unsigned(0x06).array(2).unsigned(2).unsigned(1);
+        // maxCredentialCountInList
+        w.unsigned(0x07).unsigned(FIDO2_MAX_CREDENTIAL_COUNT_IN_LIST as u64);
+        // maxCredentialIdLength
+        w.unsigned(0x08).unsigned(FIDO2_MAX_CREDENTIAL_ID_LENGTH as u64);
+        // transports
+        w.unsigned(0x09).array(1).text("usb");
+        // algorithms
+        w.unsigned(0x0A).array(FIDO2_SUPPORTED_ALGORITHMS.len());
+        for alg in FIDO2_SUPPORTED_ALGORITHMS {
+            w.map(2);
+            w.text("alg").int(alg as i64);
+            w.text("type").text("public-key");
+        }
+        // maxSerializedLargeBlobArray
+        w.unsigned(0x0B)
+            .unsigned(FIDO2_MAX_SERIALIZED_LARGE_BLOB_ARRAY as u64);
+        // forcePINChange
+        w.unsigned(0x0C).bool(self.force_pin_change);
+        // minPINLength
+        w.unsigned(0x0D).unsigned(self.min_pin_length as u64);
+        // maxCredBlobLength
+        w.unsigned(0x0F).unsigned(FIDO2_MAX_CRED_BLOB_LENGTH as u64);
+        // maxRPIDsForSetMinPINLength
+        w.unsigned(0x10)
+            .unsigned(FIDO2_MAX_MIN_PIN_LENGTH_RP_IDS as u64);
+        // remainingDiscoverableCredentials
+        w.unsigned(0x14)
+            .unsigned(self.remaining_discoverable_credentials as u64);
+        w.finish()
+    }
+}
+
+impl<S: FIDO2Storage, C: FIDO2CryptoProvider> FIDO2Authenticator<S, C> {
+    pub fn get_info(&mut self, out: &mut [u8]) -> Result<u16, FIDO2StatusCode> {
+        let resp = FIDO2GetInfoResponse {
+            aaguid: self.attestation.aaguid,
+            client_pin: self.state.pin_hash.is_some(),
+            always_uv: self.state.always_uv,
+            enterprise_attestation: self.state.enterprise_attestation,
+            force_pin_change: self.state.force_pin_change,
+            min_pin_length: self.state.min_pin_length,
+            remaining_discoverable_credentials: self.credentials.remaining(&mut self.storage)? as u32,
+        };
+        resp.apply(out).ok_or(FIDO2StatusCode::Ctap1ErrOther)
+    }
+}
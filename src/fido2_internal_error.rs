@@ -21,4 +21,7 @@ pub(crate) enum FIDO2InternalError {
     ReversedChannelError,
     DataLengthError,
     CommandNotFoundError,
+    StorageError,
+    // the noise source failed its health tests
+    EntropyError,
 }
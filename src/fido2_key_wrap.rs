@@ -0,0 +1,134 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    fido2_cose::{FIDO2CoseKey, COSE_ALG_EDDSA},
+    fido2_crypto::{ct_eq, FIDO2CryptoProvider},
+    fido2_log::FIDO2Secret,
+};
+
+// credential id
+// [version: 1] [algorithm: 1] [flags: 1] [iv: 16] [AES-256-CBC(private key): 32] [tag: 16]
+// flags = resident << 7 | hmacSecret << 6 | largeBlobKey << 5 | credProtect
+// tag = HMAC-SHA-256(mac key, version..private key || rpIdHash), truncated
+pub(crate) const FIDO2_CREDENTIAL_ID_LENGTH: usize = 67;
+const CREDENTIAL_ID_VERSION: u8 = 1;
+const CREDENTIAL_ID_TAG_OFFSET: usize = 51;
+const CREDENTIAL_ID_FLAG_RESIDENT: u8 = 0x80;
+const CREDENTIAL_ID_FLAG_HMAC_SECRET: u8 = 0x40;
+const CREDENTIAL_ID_FLAG_LARGE_BLOB_KEY: u8 = 0x20;
+const CREDENTIAL_ID_CRED_PROTECT_MASK: u8 = 0x03;
+
+#[derive(Debug, Clone)]
+pub(crate) struct FIDO2CredentialKey {
+    pub private_key: FIDO2Secret<[u8; 32]>,
+    pub algorithm: i32,
+    pub cred_protect: u8,
+    // only valid while it is in the credential store
+    pub resident: bool,
+    // created with the hmac-secret extension
+    pub hmac_secret: bool,
+    // created with the largeBlobKey extension
+    pub large_blob_key: bool,
+}
+
+// the algorithm is one of FIDO2_SUPPORTED_ALGORITHMS
+pub(crate) fn generate_private_key(crypto: &mut impl FIDO2CryptoProvider, algorithm: i32) -> [u8; 32] {
+    match algorithm {
+        COSE_ALG_EDDSA => crypto.ed25519_generate(),
+        _ => crypto.p256_generate(),
+    }
+}
+
+impl FIDO2CredentialKey {
+    pub fn public_key<C: FIDO2CryptoProvider>(&self) -> Option<FIDO2CoseKey> {
+        match self.algorithm {
+            COSE_ALG_EDDSA => Some(FIDO2CoseKey::EdDSA(C::ed25519_public_key(&self.private_key))),
+            _ => {
+                let (x, y) = C::p256_public_key(&self.private_key)?;
+                Some(FIDO2CoseKey::ES256(x, y))
+            }
+        }
+    }
+    // signature over the concatenation of the parts, returns the signature length
+    pub fn sign<C: FIDO2CryptoProvider>(&self, parts: &[&[u8]], out: &mut [u8]) -> Option<usize> {
+        match self.algorithm {
+            COSE_ALG_EDDSA => C::ed25519_sign(&self.private_key, parts, out),
+            _ => C::p256_sign(&self.private_key, parts, out),
+        }
+    }
+}
+
+// keys derived from the master secret
+fn wrap_keys<C: FIDO2CryptoProvider>(master_secret: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    (
+        C::hmac_sha256(master_secret, &[b"unsafe{key} wrap enc"]),
+        C::hmac_sha256(master_secret, &[b"unsafe{key} wrap mac"]),
+    )
+}
+
+pub(crate) fn wrap<C: FIDO2CryptoProvider>(
+    master_secret: &[u8; 32],
+    crypto: &mut C,
+    key: &FIDO2CredentialKey,
+    rp_id_hash: &[u8; 32],
+) -> [u8; FIDO2_CREDENTIAL_ID_LENGTH] {
+    let (enc_key, mac_key) = wrap_keys::<C>(master_secret);
+    let mut id = [0u8; FIDO2_CREDENTIAL_ID_LENGTH];
+    id[0] = CREDENTIAL_ID_VERSION;
+    id[1] = key.algorithm as i8 as u8;
+    id[2] = key.cred_protect
+        | if key.resident { CREDENTIAL_ID_FLAG_RESIDENT } else { 0 }
+        | if key.hmac_secret { CREDENTIAL_ID_FLAG_HMAC_SECRET } else { 0 }
+        | if key.large_blob_key { CREDENTIAL_ID_FLAG_LARGE_BLOB_KEY } else { 0 };
+    let mut iv = [0u8; 16];
+    crypto.fill_bytes(&mut iv);
+    id[3..19].copy_from_slice(&iv);
+    id[19..51].copy_from_slice(&*key.private_key);
+    C::aes256_cbc_encrypt(&enc_key, &iv, &mut id[19..51]).unwrap();
+    let tag = C::hmac_sha256(&mac_key, &[&id[..CREDENTIAL_ID_TAG_OFFSET], rp_id_hash]);
+    id[CREDENTIAL_ID_TAG_OFFSET..].copy_from_slice(&tag[..16]);
+    id
+}
+
+// None if the id was not created by this authenticator for this rpIdHash
+pub(crate) fn unwrap<C: FIDO2CryptoProvider>(
+    master_secret: &[u8; 32],
+    id: &[u8],
+    rp_id_hash: &[u8; 32],
+) -> Option<FIDO2CredentialKey> {
+    if id.len() != FIDO2_CREDENTIAL_ID_LENGTH || id[0] != CREDENTIAL_ID_VERSION {
+        return None;
+    }
+    let (enc_key, mac_key) = wrap_keys::<C>(master_secret);
+    let tag = C::hmac_sha256(&mac_key, &[&id[..CREDENTIAL_ID_TAG_OFFSET], rp_id_hash]);
+    if !ct_eq(&tag[..16], &id[CREDENTIAL_ID_TAG_OFFSET..]) {
+        return None;
+    }
+    let iv: [u8; 16] = id[3..19].try_into().unwrap();
+    let mut private_key: [u8; 32] = id[19..51].try_into().unwrap();
+    C::aes256_cbc_decrypt(&enc_key, &iv, &mut private_key)?;
+    Some(FIDO2CredentialKey {
+        private_key: FIDO2Secret(private_key),
+        algorithm: id[1] as i8 as i32,
+        cred_protect: id[2] & CREDENTIAL_ID_CRED_PROTECT_MASK,
+        resident: id[2] & CREDENTIAL_ID_FLAG_RESIDENT != 0,
+        hmac_secret: id[2] & CREDENTIAL_ID_FLAG_HMAC_SECRET != 0,
+        large_blob_key: id[2] & CREDENTIAL_ID_FLAG_LARGE_BLOB_KEY != 0,
+    })
+}
@@ -0,0 +1,259 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{
+    consts::{
+        FIDO2_MAX_SERIALIZED_LARGE_BLOB_ARRAY, FIDO2_MESSAGE_BUFFER_SIZE,
+        FIDO2_STORAGE_LARGE_BLOB_PAGE,
+    },
+    fido2_authenticator::FIDO2Authenticator,
+    fido2_cbor::{FIDO2CborReader, FIDO2CborWriter},
+    fido2_client_pin::FIDO2_PERMISSION_LBW,
+    fido2_commands::FIDO2PacketCommandResponse,
+    fido2_crypto::{ct_eq, FIDO2CryptoProvider},
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::{page_offset, FIDO2Storage},
+};
+
+// empty CBOR array followed by LEFT(SHA-256(h'80'), 16)
+const INITIAL_LARGE_BLOB_ARRAY: [u8; 17] = [
+    0x80, 0x76, 0xbe, 0x8b, 0x52, 0x8d, 0x00, 0x75, 0xf7, 0xaa, 0xe9, 0x8d, 0x6f, 0xa5, 0x7a, 0x6d,
+    0x3c,
+];
+// maxMsgSize minus 64
+const MAX_FRAGMENT_LENGTH: usize = FIDO2_MESSAGE_BUFFER_SIZE - 64;
+
+// LargeBlobs
+
+#[derive(Debug)]
+pub(crate) struct FIDO2LargeBlobsRequest<'a> {
+    pub get: Option<u64>,
+    pub set: Option<&'a [u8]>,
+    pub offset: u64,
+    pub length: Option<u64>,
+    pub pin_uv_auth_param: Option<&'a [u8]>,
+    pub pin_uv_auth_protocol: Option<u64>,
+}
+impl<'a> FIDO2LargeBlobsRequest<'a> {
+    pub fn unpack(packet: &'a [u8]) -> Result<FIDO2LargeBlobsRequest<'a>, FIDO2StatusCode> {
+        let mut r = FIDO2CborReader::new(packet);
+        let mut get: Option<u64> = None;
+        let mut set: Option<&[u8]> = None;
+        let mut offset: Option<u64> = None;
+        let mut length: Option<u64> = None;
+        let mut pin_uv_auth_param: Option<&[u8]> = None;
+        let mut pin_uv_auth_protocol: Option<u64> = None;
+        for _ in 0..r.map()? {
+            match r.unsigned()? {
+                0x01 => get = Some(r.unsigned()?),
+                0x02 => set = Some(r.bytes()?),
+                0x03 => offset = Some(r.unsigned()?),
+                0x04 => length = Some(r.unsigned()?),
+                0x05 => pin_uv_auth_param = Some(r.bytes()?),
+                0x06 => pin_uv_auth_protocol = Some(r.unsigned()?),
+                _ => r.skip()?,
+            }
+        }
+        Ok(FIDO2LargeBlobsRequest {
+            get,
+            set,
+            offset: offset.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?,
+            length,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FIDO2LargeBlobsResponse<'a> {
+    pub config: &'a [u8],
+}
+impl<'a> FIDO2PacketCommandResponse for FIDO2LargeBlobsResponse<'a> {
+    fn apply(self, arr: &mut [u8]) -> Option<u16> {
+        let mut w = FIDO2CborWriter::new(arr);
+        w.map(1);
+        w.unsigned(0x01).bytes(self.config);
+        w.finish()
+    }
+}
+
+// a write in progress, fragments go straight to the spare copy
+#[derive(Debug)]
+pub(crate) struct FIDO2LargeBlobWrite {
+    pub page: u32,
+    pub expected_length: usize,
+    pub next_offset: usize,
+    // flash is written in halfwords, an odd byte waits for the next fragment
+    pub pending: Option<u8>,
+}
+
+impl<S: FIDO2Storage, C: FIDO2CryptoProvider> FIDO2Authenticator<S, C> {
+    // the serialized array in use
+    fn read_large_blob(
+        &mut self,
+        buf: &mut [u8; FIDO2_MAX_SERIALIZED_LARGE_BLOB_ARRAY],
+    ) -> Result<usize, FIDO2StatusCode> {
+        let length = self.state.large_blob_length as usize;
+        if length == 0 {
+            buf[..INITIAL_LARGE_BLOB_ARRAY.len()].copy_from_slice(&INITIAL_LARGE_BLOB_ARRAY);
+            return Ok(INITIAL_LARGE_BLOB_ARRAY.len());
+        }
+        self.storage
+            .read(page_offset(self.state.large_blob_page), &mut buf[..length])?;
+        Ok(length)
+    }
+    fn write_large_blob_fragment(&mut self, data: &[u8]) -> Result<(), FIDO2StatusCode> {
+        let write = self
+            .large_blob
+            .as_mut()
+            .ok_or(FIDO2StatusCode::Ctap1ErrInvalidSeq)?;
+        let base = page_offset(write.page);
+        let mut offset = write.next_offset;
+        let mut data = data;
+        if let (Some(b), Some(first)) = (write.pending, data.first()) {
+            self.storage.write(base + offset as u32 - 1, &[b, *first])?;
+            write.pending = None;
+            offset += 1;
+            data = &data[1..];
+        }
+        let even = data.len() & !1;
+        if even > 0 {
+            self.storage.write(base + offset as u32, &data[..even])?;
+        }
+        if even < data.len() {
+            write.pending = Some(data[even]);
+        }
+        write.next_offset = offset + data.len();
+        if write.next_offset == write.expected_length {
+            if let Some(b) = write.pending.take() {
+                self.storage
+                    .write(base + write.next_offset as u32 - 1, &[b, 0xff])?;
+            }
+        }
+        Ok(())
+    }
+    pub fn large_blobs(&mut self, data: &[u8], out: &mut [u8]) -> Result<u16, FIDO2StatusCode> {
+        let req = FIDO2LargeBlobsRequest::unpack(data)?;
+        let offset =
+            usize::try_from(req.offset).map_err(|_| FIDO2StatusCode::Ctap1ErrInvalidParameter)?;
+        if let Some(get) = req.get {
+            if req.set.is_some() || req.length.is_some() {
+                return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+            }
+            // usize is 32 bits on the device, too large either way
+            let get = usize::try_from(get).unwrap_or(usize::MAX);
+            if get > MAX_FRAGMENT_LENGTH {
+                return Err(FIDO2StatusCode::Ctap1ErrInvalidLength);
+            }
+            let mut buf = [0u8; FIDO2_MAX_SERIALIZED_LARGE_BLOB_ARRAY];
+            let length = self.read_large_blob(&mut buf)?;
+            if offset > length {
+                return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+            }
+            let end = core::cmp::min(length, offset + get);
+            return FIDO2LargeBlobsResponse {
+                config: &buf[offset..end],
+            }
+            .apply(out)
+            .ok_or(FIDO2StatusCode::Ctap1ErrOther);
+        }
+        let set = req.set.ok_or(FIDO2StatusCode::Ctap1ErrInvalidParameter)?;
+        if set.len() > MAX_FRAGMENT_LENGTH {
+            return Err(FIDO2StatusCode::Ctap1ErrInvalidLength);
+        }
+        let expected_length = if offset == 0 {
+            let length = req
+                .length
+                .ok_or(FIDO2StatusCode::Ctap1ErrInvalidParameter)?;
+            let length = usize::try_from(length).unwrap_or(usize::MAX);
+            if length > FIDO2_MAX_SERIALIZED_LARGE_BLOB_ARRAY {
+                return Err(FIDO2StatusCode::Ctap2ErrLargeBlobStorageFull);
+            }
+            if length < INITIAL_LARGE_BLOB_ARRAY.len() {
+                return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+            }
+            length
+        } else {
+            if req.length.is_some() {
+                return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+            }
+            match &self.large_blob {
+                Some(write) if write.next_offset == offset => write.expected_length,
+                _ => return Err(FIDO2StatusCode::Ctap1ErrInvalidSeq),
+            }
+        };
+        if self.state.pin_hash.is_some() || self.state.always_uv || req.pin_uv_auth_param.is_some() {
+            let param = req
+                .pin_uv_auth_param
+                .ok_or(FIDO2StatusCode::Ctap2ErrPuatRequired)?;
+            let mut offset_le = [0u8; 4];
+            LittleEndian::write_u32(&mut offset_le, offset as u32);
+            self.verify_pin_uv_auth_token(
+                req.pin_uv_auth_protocol,
+                param,
+                &[&[0xff; 32], &[0x0c, 0x00], &offset_le, &C::sha256(&[set])],
+                FIDO2_PERMISSION_LBW,
+                None,
+            )?;
+        }
+        if offset + set.len() > expected_length {
+            return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+        }
+        if offset == 0 {
+            // the copy that is not in use, the current array stays readable
+            let page = if self.state.large_blob_page == FIDO2_STORAGE_LARGE_BLOB_PAGE {
+                FIDO2_STORAGE_LARGE_BLOB_PAGE + 1
+            } else {
+                FIDO2_STORAGE_LARGE_BLOB_PAGE
+            };
+            self.large_blob = None;
+            self.storage.erase_page(page)?;
+            self.large_blob = Some(FIDO2LargeBlobWrite {
+                page,
+                expected_length,
+                next_offset: 0,
+                pending: None,
+            });
+        }
+        if let Err(e) = self.write_large_blob_fragment(set) {
+            self.large_blob = None;
+            return Err(e);
+        }
+        let write = self.large_blob.as_ref().unwrap();
+        if write.next_offset < expected_length {
+            return Ok(0);
+        }
+        let page = write.page;
+        self.large_blob = None;
+        // check what actually ended up in flash
+        let mut buf = [0u8; FIDO2_MAX_SERIALIZED_LARGE_BLOB_ARRAY];
+        self.storage
+            .read(page_offset(page), &mut buf[..expected_length])?;
+        let (array, hash) = buf[..expected_length].split_at(expected_length - 16);
+        if !ct_eq(&C::sha256(&[array])[..16], hash) {
+            return Err(FIDO2StatusCode::Ctap2ErrIntegrityFailure);
+        }
+        self.state.large_blob_page = page;
+        self.state.large_blob_length = expected_length as u16;
+        self.state.save(&mut self.storage)?;
+        Ok(0)
+    }
+}
@@ -0,0 +1,312 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+// logging facade: records go to one sink (the debug UART, RTT or stdout on
+// the host), levels above FIDO2_LOG_MAX_LEVEL are compiled out
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub(crate) enum FIDO2LogLevel {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+impl fmt::Display for FIDO2LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FIDO2LogLevel::Error => "ERROR",
+            FIDO2LogLevel::Warn => "WARN",
+            FIDO2LogLevel::Info => "INFO",
+            FIDO2LogLevel::Debug => "DEBUG",
+            FIDO2LogLevel::Trace => "TRACE",
+        })
+    }
+}
+
+// the most verbose log-* feature wins, warnings and errors without any
+pub(crate) const FIDO2_LOG_MAX_LEVEL: FIDO2LogLevel = if cfg!(feature = "log-trace") {
+    FIDO2LogLevel::Trace
+} else if cfg!(feature = "log-debug") {
+    FIDO2LogLevel::Debug
+} else if cfg!(feature = "log-info") {
+    FIDO2LogLevel::Info
+} else if cfg!(feature = "log-warn") {
+    FIDO2LogLevel::Warn
+} else if cfg!(feature = "log-error") {
+    FIDO2LogLevel::Error
+} else {
+    FIDO2LogLevel::Warn
+};
+
+// key material, PIN hashes and tokens: Debug never shows the value, so the
+// structs holding them can be logged as a whole. no Copy, so copies are
+// explicit, and no PartialEq: compare with fido2_crypto::ct_eq
+#[derive(Clone)]
+pub(crate) struct FIDO2Secret<T>(pub T);
+impl<T> Deref for FIDO2Secret<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+impl<T> DerefMut for FIDO2Secret<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+impl<T> fmt::Debug for FIDO2Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+// where records go, flush waits until the device has sent everything
+pub(crate) trait FIDO2LogSink: Write {
+    fn flush(&mut self) {}
+}
+
+// with_sink hands out the sink as a plain fmt::Write
+struct FIDO2LogWriter<'a>(&'a mut dyn FIDO2LogSink);
+impl Write for FIDO2LogWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s)
+    }
+}
+
+// runs f with the tasks that log masked
+pub(crate) type FIDO2LogCritical = fn(f: &mut dyn FnMut());
+
+fn no_critical(f: &mut dyn FnMut()) {
+    f()
+}
+
+struct FIDO2LogSlot(UnsafeCell<Option<&'static mut dyn FIDO2LogSink>>);
+// only touched by the owner of FIDO2_LOG_BUSY
+unsafe impl Sync for FIDO2LogSlot {}
+struct FIDO2LogCriticalSlot(UnsafeCell<FIDO2LogCritical>);
+// only written by set_sink, before the tasks start
+unsafe impl Sync for FIDO2LogCriticalSlot {}
+
+static FIDO2_LOG_SINK: FIDO2LogSlot = FIDO2LogSlot(UnsafeCell::new(None));
+static FIDO2_LOG_CRITICAL: FIDO2LogCriticalSlot =
+    FIDO2LogCriticalSlot(UnsafeCell::new(no_critical));
+static FIDO2_LOG_BUSY: AtomicBool = AtomicBool::new(false);
+static FIDO2_LOG_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+// from init (or the host) only. critical keeps the tasks that log from
+// preempting a writer (the firmware raises BASEPRI to their priority), those
+// never find the sink busy then, so nothing they write is lost
+pub(crate) fn set_sink(sink: &'static mut dyn FIDO2LogSink, critical: FIDO2LogCritical) {
+    while FIDO2_LOG_BUSY.swap(true, Ordering::Acquire) {}
+    unsafe {
+        *FIDO2_LOG_SINK.0.get() = Some(sink);
+        *FIDO2_LOG_CRITICAL.0.get() = critical;
+    }
+    FIDO2_LOG_BUSY.store(false, Ordering::Release);
+}
+
+// runs f on the sink. nothing waits: a task that is not masked by the critical
+// section and preempts a writer loses its output instead
+pub(crate) fn with_sink<R>(f: impl FnOnce(&mut dyn Write) -> R) -> Option<R> {
+    let mut f = Some(f);
+    let mut result = None;
+    let critical = unsafe { *FIDO2_LOG_CRITICAL.0.get() };
+    critical(&mut || {
+        if FIDO2_LOG_BUSY.swap(true, Ordering::Acquire) {
+            FIDO2_LOG_DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if let (Some(sink), Some(f)) = (
+            unsafe { (*FIDO2_LOG_SINK.0.get()).as_deref_mut() },
+            f.take(),
+        ) {
+            result = Some(f(&mut FIDO2LogWriter(sink)));
+        }
+        FIDO2_LOG_BUSY.store(false, Ordering::Release);
+    });
+    result
+}
+
+// waits until everything written so far is out, e.g. before a reset
+pub(crate) fn flush() {
+    let critical = unsafe { *FIDO2_LOG_CRITICAL.0.get() };
+    critical(&mut || {
+        if FIDO2_LOG_BUSY.swap(true, Ordering::Acquire) {
+            return;
+        }
+        if let Some(sink) = unsafe { (*FIDO2_LOG_SINK.0.get()).as_deref_mut() } {
+            sink.flush();
+        }
+        FIDO2_LOG_BUSY.store(false, Ordering::Release);
+    });
+}
+
+// records lost to with_sink
+pub(crate) fn dropped() -> u32 {
+    FIDO2_LOG_DROPPED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn log(level: FIDO2LogLevel, target: &str, args: fmt::Arguments) {
+    // without the crate name
+    let target = target.split_once("::").map_or(target, |(_, module)| module);
+    with_sink(|sink| write!(sink, "[{} {}] {}\r\n", level, target, args).ok());
+}
+
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)+) => {
+        if $level <= $crate::fido2_log::FIDO2_LOG_MAX_LEVEL {
+            $crate::fido2_log::log($level, module_path!(), format_args!($($arg)+))
+        }
+    };
+}
+macro_rules! log_error {
+    ($($arg:tt)+) => {
+        $crate::fido2_log::log_at!($crate::fido2_log::FIDO2LogLevel::Error, $($arg)+)
+    };
+}
+macro_rules! log_warn {
+    ($($arg:tt)+) => {
+        $crate::fido2_log::log_at!($crate::fido2_log::FIDO2LogLevel::Warn, $($arg)+)
+    };
+}
+macro_rules! log_info {
+    ($($arg:tt)+) => {
+        $crate::fido2_log::log_at!($crate::fido2_log::FIDO2LogLevel::Info, $($arg)+)
+    };
+}
+macro_rules! log_debug {
+    ($($arg:tt)+) => {
+        $crate::fido2_log::log_at!($crate::fido2_log::FIDO2LogLevel::Debug, $($arg)+)
+    };
+}
+pub(crate) use {log_at, log_debug, log_error, log_info, log_warn};
+
+// sinks, the debug UART is implemented by the firmware (serial::Tx)
+
+// RTT up channel, read through openocd (see openocd.cfg), the host reads it
+// while the target runs so there is nothing to flush
+#[cfg(feature = "log-rtt")]
+pub(crate) struct FIDO2RttSink(pub rtt_target::UpChannel);
+#[cfg(feature = "log-rtt")]
+impl Write for FIDO2RttSink {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(s.as_bytes());
+        Ok(())
+    }
+}
+#[cfg(feature = "log-rtt")]
+impl FIDO2LogSink for FIDO2RttSink {}
+
+// stdout of the host build
+#[cfg(not(target_os = "none"))]
+extern crate std;
+
+#[cfg(not(target_os = "none"))]
+pub(crate) struct FIDO2StdoutSink;
+#[cfg(not(target_os = "none"))]
+impl Write for FIDO2StdoutSink {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for part in s.split('\r') {
+            std::print!("{}", part);
+        }
+        Ok(())
+    }
+}
+#[cfg(not(target_os = "none"))]
+impl FIDO2LogSink for FIDO2StdoutSink {
+    fn flush(&mut self) {
+        use std::io::Write as _;
+        std::io::stdout().flush().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{boxed::Box, string::String, sync::Mutex};
+
+    static CAPTURED: Mutex<String> = Mutex::new(String::new());
+    static FLUSHED: AtomicU32 = AtomicU32::new(0);
+
+    struct FIDO2CaptureSink;
+    impl Write for FIDO2CaptureSink {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            CAPTURED.lock().unwrap().push_str(s);
+            Ok(())
+        }
+    }
+    impl FIDO2LogSink for FIDO2CaptureSink {
+        fn flush(&mut self) {
+            FLUSHED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // the sink is global, so one test drives all of it
+    #[test]
+    fn sink() {
+        set_sink(Box::leak(Box::new(FIDO2CaptureSink)), no_critical);
+        // without a log-* feature only warnings and errors are built
+        assert_eq!(FIDO2_LOG_MAX_LEVEL, FIDO2LogLevel::Warn);
+        log_error!("flash write failed at {:08x}", 0x0800_f000u32);
+        log_warn!("{:?}", FIDO2Secret([1u8, 2, 3]));
+        log_info!("not built");
+        log_debug!("not built");
+        {
+            let captured = CAPTURED.lock().unwrap();
+            assert!(
+                captured.contains("[ERROR fido2_log::tests] flash write failed at 0800f000\r\n")
+            );
+            assert!(captured.contains("[WARN fido2_log::tests] <redacted>\r\n"));
+            assert!(!captured.contains("not built"));
+        }
+        // a writer preempted by a task outside the critical section
+        let dropped_before = dropped();
+        let nested = with_sink(|outer| {
+            let inner = with_sink(|inner| inner.write_str("lost").is_ok());
+            outer.write_str("kept").ok();
+            inner
+        });
+        assert_eq!(nested, Some(None));
+        assert_eq!(dropped(), dropped_before + 1);
+        assert!(CAPTURED.lock().unwrap().contains("kept"));
+        assert!(!CAPTURED.lock().unwrap().contains("lost"));
+        // the critical section wraps the writer
+        fn counting(f: &mut dyn FnMut()) {
+            FLUSHED.fetch_add(100, Ordering::Relaxed);
+            f()
+        }
+        set_sink(Box::leak(Box::new(FIDO2CaptureSink)), counting);
+        flush();
+        assert_eq!(FLUSHED.load(Ordering::Relaxed), 101);
+        // back to stdout
+        set_sink(Box::leak(Box::new(FIDO2StdoutSink)), no_critical);
+        assert_eq!(
+            with_sink(|sink| sink.write_str("stdout sink\r\n").is_ok()),
+            Some(true)
+        );
+        flush();
+    }
+}
@@ -0,0 +1,402 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    consts::{
+        FIDO2_MAX_ATTESTATION_CERTIFICATE_LENGTH, FIDO2_MAX_CRED_BLOB_LENGTH, FIDO2_MAX_USER_ID_LENGTH,
+    },
+    fido2_auth_data::{FIDO2AuthenticatorData, FIDO2_FLAG_UP, FIDO2_FLAG_UV, FIDO2_MAX_AUTH_DATA_LENGTH},
+    fido2_authenticator::{FIDO2Authenticator, FIDO2Platform},
+    fido2_cbor::{FIDO2CborReader, FIDO2CborWriter},
+    fido2_client_pin::FIDO2_PERMISSION_MC,
+    fido2_commands::FIDO2PacketCommandResponse,
+    fido2_cose::{COSE_ALG_ES256, FIDO2_SUPPORTED_ALGORITHMS},
+    fido2_credential_store::FIDO2ResidentCredential,
+    fido2_crypto::FIDO2CryptoProvider,
+    fido2_extensions::{
+        cred_protect_allows, large_blob_key, FIDO2ExtensionsOutput, FIDO2MakeCredentialExtensions,
+        FIDO2_CRED_PROTECT_UV_OPTIONAL, FIDO2_MAX_EXTENSIONS_OUTPUT_LENGTH,
+    },
+    fido2_key_wrap::{generate_private_key, unwrap, wrap, FIDO2CredentialKey},
+    fido2_log::FIDO2Secret,
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::FIDO2Storage,
+    utils::FIDO2Bytes,
+};
+
+// Options
+
+#[derive(Debug, Default)]
+pub(crate) struct FIDO2Options {
+    pub rk: Option<bool>,
+    pub up: Option<bool>,
+    pub uv: Option<bool>,
+}
+impl FIDO2Options {
+    pub fn unpack(r: &mut FIDO2CborReader) -> Result<FIDO2Options, FIDO2StatusCode> {
+        let mut options = FIDO2Options::default();
+        for _ in 0..r.map()? {
+            match r.text()? {
+                "rk" => options.rk = Some(r.bool()?),
+                "up" => options.up = Some(r.bool()?),
+                "uv" => options.uv = Some(r.bool()?),
+                _ => r.skip()?,
+            }
+        }
+        Ok(options)
+    }
+}
+
+// PublicKeyCredentialDescriptor, returns the id if the type is known
+pub(crate) fn read_credential_descriptor<'a>(
+    r: &mut FIDO2CborReader<'a>,
+) -> Result<Option<&'a [u8]>, FIDO2StatusCode> {
+    let mut id: Option<&[u8]> = None;
+    let mut public_key = false;
+    for _ in 0..r.map()? {
+        match r.text()? {
+            "id" => id = Some(r.bytes()?),
+            "type" => public_key = r.text()? == "public-key",
+            _ => r.skip()?,
+        }
+    }
+    let id = id.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?;
+    Ok(if public_key { Some(id) } else { None })
+}
+
+// MakeCredential
+
+#[derive(Debug)]
+pub(crate) struct FIDO2MakeCredentialRequest<'a> {
+    pub client_data_hash: &'a [u8],
+    pub rp_id: &'a str,
+    pub user_id: &'a [u8],
+    pub user_name: &'a str,
+    pub user_display_name: &'a str,
+    // first supported entry of pubKeyCredParams
+    pub algorithm: Option<i32>,
+    pub exclude_list: Option<&'a [u8]>,
+    pub extensions: FIDO2MakeCredentialExtensions<'a>,
+    pub options: FIDO2Options,
+    pub pin_uv_auth_param: Option<&'a [u8]>,
+    pub pin_uv_auth_protocol: Option<u64>,
+    pub enterprise_attestation: Option<u64>,
+}
+impl<'a> FIDO2MakeCredentialRequest<'a> {
+    pub fn unpack(packet: &'a [u8]) -> Result<FIDO2MakeCredentialRequest<'a>, FIDO2StatusCode> {
+        let mut r = FIDO2CborReader::new(packet);
+        let mut client_data_hash: Option<&[u8]> = None;
+        let mut rp_id: Option<&str> = None;
+        let mut user_id: Option<&[u8]> = None;
+        let mut user_name = "";
+        let mut user_display_name = "";
+        let mut algorithm: Option<i32> = None;
+        let mut has_params = false;
+        let mut exclude_list: Option<&[u8]> = None;
+        let mut extensions = FIDO2MakeCredentialExtensions::default();
+        let mut options = FIDO2Options::default();
+        let mut pin_uv_auth_param: Option<&[u8]> = None;
+        let mut pin_uv_auth_protocol: Option<u64> = None;
+        let mut enterprise_attestation: Option<u64> = None;
+        for _ in 0..r.map()? {
+            match r.unsigned()? {
+                0x01 => client_data_hash = Some(r.bytes()?),
+                0x02 => {
+                    for _ in 0..r.map()? {
+                        match r.text()? {
+                            "id" => rp_id = Some(r.text()?),
+                            _ => r.skip()?,
+                        }
+                    }
+                }
+                0x03 => {
+                    for _ in 0..r.map()? {
+                        match r.text()? {
+                            "id" => user_id = Some(r.bytes()?),
+                            "name" => user_name = r.text()?,
+                            "displayName" => user_display_name = r.text()?,
+                            _ => r.skip()?,
+                        }
+                    }
+                }
+                0x04 => {
+                    has_params = true;
+                    for _ in 0..r.array()? {
+                        let mut alg: Option<i64> = None;
+                        let mut public_key = false;
+                        for _ in 0..r.map()? {
+                            match r.text()? {
+                                "alg" => alg = Some(r.int()?),
+                                "type" => public_key = r.text()? == "public-key",
+                                _ => r.skip()?,
+                            }
+                        }
+                        let alg = alg.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?;
+                        if algorithm.is_none()
+                            && public_key
+                            && FIDO2_SUPPORTED_ALGORITHMS.contains(&(alg as i32))
+                        {
+                            algorithm = Some(alg as i32);
+                        }
+                    }
+                }
+                0x05 => exclude_list = Some(r.raw()?),
+                0x06 => extensions = FIDO2MakeCredentialExtensions::unpack(r.raw()?)?,
+                0x07 => options = FIDO2Options::unpack(&mut r)?,
+                0x08 => pin_uv_auth_param = Some(r.bytes()?),
+                0x09 => pin_uv_auth_protocol = Some(r.unsigned()?),
+                0x0A => enterprise_attestation = Some(r.unsigned()?),
+                _ => r.skip()?,
+            }
+        }
+        let client_data_hash = client_data_hash.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?;
+        let rp_id = rp_id.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?;
+        let user_id = user_id.ok_or(FIDO2StatusCode::Ctap2ErrMissingParameter)?;
+        if !has_params {
+            return Err(FIDO2StatusCode::Ctap2ErrMissingParameter);
+        }
+        if client_data_hash.len() != 32 || user_id.len() > FIDO2_MAX_USER_ID_LENGTH {
+            return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+        }
+        Ok(FIDO2MakeCredentialRequest {
+            client_data_hash,
+            rp_id,
+            user_id,
+            user_name,
+            user_display_name,
+            algorithm,
+            exclude_list,
+            extensions,
+            options,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+            enterprise_attestation,
+        })
+    }
+}
+
+// "packed" attestation, self attestation when there is no certificate
+#[derive(Debug)]
+pub(crate) struct FIDO2MakeCredentialResponse<'a> {
+    pub auth_data: &'a [u8],
+    pub algorithm: i32,
+    pub signature: &'a [u8],
+    pub certificate: Option<&'a [u8]>,
+    pub large_blob_key: Option<&'a [u8; 32]>,
+}
+impl<'a> FIDO2PacketCommandResponse for FIDO2MakeCredentialResponse<'a> {
+    fn apply(self, arr: &mut [u8]) -> Option<u16> {
+        let mut w = FIDO2CborWriter::new(arr);
+        w.map(3 + self.large_blob_key.is_some() as usize);
+        w.unsigned(0x01).text("packed");
+        w.unsigned(0x02).bytes(self.auth_data);
+        w.unsigned(0x03).map(2 + self.certificate.is_some() as usize);
+        w.text("alg").int(self.algorithm as i64);
+        w.text("sig").bytes(self.signature);
+        if let Some(certificate) = self.certificate {
+            w.text("x5c").array(1).bytes(certificate);
+        }
+        if let Some(key) = self.large_blob_key {
+            w.unsigned(0x05).bytes(key);
+        }
+        w.finish()
+    }
+}
+
+impl<S: FIDO2Storage, C: FIDO2CryptoProvider> FIDO2Authenticator<S, C> {
+    pub fn make_credential(
+        &mut self,
+        platform: &mut impl FIDO2Platform,
+        data: &[u8],
+        out: &mut [u8],
+    ) -> Result<u16, FIDO2StatusCode> {
+        let req = FIDO2MakeCredentialRequest::unpack(data)?;
+        let rp_id_hash = C::sha256(&[req.rp_id.as_bytes()]);
+        let uv = self.check_pin_uv_auth_param(
+            platform,
+            req.pin_uv_auth_param,
+            req.pin_uv_auth_protocol,
+            req.client_data_hash,
+            FIDO2_PERMISSION_MC,
+            &rp_id_hash,
+        )?;
+        if !uv && self.state.pin_hash.is_some() {
+            return Err(FIDO2StatusCode::Ctap2ErrPuatRequired);
+        }
+        if !uv && self.state.always_uv {
+            return Err(FIDO2StatusCode::Ctap2ErrPinNotSet);
+        }
+        // accepted once enabled, there is no enterprise attestation certificate
+        // so the attestation statement doesn't change
+        if let Some(ea) = req.enterprise_attestation {
+            if !self.state.enterprise_attestation {
+                return Err(FIDO2StatusCode::Ctap1ErrInvalidParameter);
+            }
+            if ea != 1 && ea != 2 {
+                return Err(FIDO2StatusCode::Ctap2ErrInvalidOption);
+            }
+        }
+        let algorithm = req
+            .algorithm
+            .ok_or(FIDO2StatusCode::Ctap2ErrUnsupportedAlgorithm)?;
+        // there is no built-in user verification
+        if req.options.up == Some(false) || req.options.uv == Some(true) {
+            return Err(FIDO2StatusCode::Ctap2ErrInvalidOption);
+        }
+        let rk = req.options.rk == Some(true);
+        // the large-blob array is found through discoverable credentials only
+        if req.extensions.large_blob_key == Some(false)
+            || (req.extensions.large_blob_key == Some(true) && !rk)
+        {
+            return Err(FIDO2StatusCode::Ctap2ErrInvalidOption);
+        }
+        if let Some(exclude_list) = req.exclude_list {
+            let mut r = FIDO2CborReader::new(exclude_list);
+            for _ in 0..r.array()? {
+                let id = match read_credential_descriptor(&mut r)? {
+                    Some(id) => id,
+                    None => continue,
+                };
+                let key = match unwrap::<C>(&self.state.master_secret, id, &rp_id_hash) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                // a credential that needs UV can't be revealed without it
+                if !cred_protect_allows(key.cred_protect, uv, true) {
+                    continue;
+                }
+                if !key.resident
+                    || self
+                        .credentials
+                        .find_credential_id(&mut self.storage, id)?
+                        .is_some()
+                {
+                    platform.user_presence()?;
+                    return Err(FIDO2StatusCode::Ctap2ErrCredentialExcluded);
+                }
+            }
+        }
+        // don't ask for presence if the credential can't be stored anyway
+        if rk
+            && self.credentials.remaining(&mut self.storage)? == 0
+            && self
+                .credentials
+                .find_user(&mut self.storage, &rp_id_hash, req.user_id)?
+                .is_none()
+        {
+            return Err(FIDO2StatusCode::Ctap2ErrKeyStoreFull);
+        }
+        platform.user_presence()?;
+        if uv {
+            self.pin.clear_after_use();
+        }
+        let key = FIDO2CredentialKey {
+            private_key: FIDO2Secret(generate_private_key(&mut self.crypto, algorithm)),
+            algorithm,
+            cred_protect: req
+                .extensions
+                .cred_protect
+                .unwrap_or(FIDO2_CRED_PROTECT_UV_OPTIONAL),
+            resident: rk,
+            hmac_secret: req.extensions.hmac_secret,
+            large_blob_key: req.extensions.large_blob_key == Some(true),
+        };
+        let credential_id = wrap(&self.state.master_secret, &mut self.crypto, &key, &rp_id_hash);
+        // credBlob is kept with resident credentials only
+        let cred_blob = match req.extensions.cred_blob {
+            Some(blob) if rk && blob.len() <= FIDO2_MAX_CRED_BLOB_LENGTH => Some(blob),
+            _ => None,
+        };
+        if rk {
+            let mut cred = FIDO2ResidentCredential {
+                creation_order: 0,
+                rp_id_hash,
+                cred_protect: key.cred_protect,
+                credential_id: FIDO2Bytes::from_slice(&credential_id),
+                rp_id: FIDO2Bytes::from_str(req.rp_id),
+                user_id: FIDO2Bytes::from_slice(req.user_id),
+                user_name: FIDO2Bytes::from_str(req.user_name),
+                display_name: FIDO2Bytes::from_str(req.user_display_name),
+                cred_blob: FIDO2Bytes::from_slice(cred_blob.unwrap_or(&[])),
+            };
+            self.credentials.store(&mut self.storage, &mut cred)?;
+        }
+        let sign_count = self.counter.increment(&mut self.storage, &mut self.state)?;
+        let public_key = key.public_key::<C>().ok_or(FIDO2StatusCode::Ctap1ErrOther)?;
+        let extensions = FIDO2ExtensionsOutput {
+            cred_blob_stored: req.extensions.cred_blob.map(|_| cred_blob.is_some()),
+            cred_protect: req.extensions.cred_protect,
+            hmac_secret_created: key.hmac_secret,
+            min_pin_length: if req.extensions.min_pin_length
+                && self.state.min_pin_length_rp_ids[..self.state.min_pin_length_rp_id_count]
+                    .contains(&rp_id_hash)
+            {
+                Some(self.state.min_pin_length)
+            } else {
+                None
+            },
+            ..Default::default()
+        };
+        let mut extensions_data = [0u8; FIDO2_MAX_EXTENSIONS_OUTPUT_LENGTH];
+        let extensions_len = if extensions.is_empty() {
+            None
+        } else {
+            Some(
+                extensions
+                    .apply(&mut extensions_data)
+                    .ok_or(FIDO2StatusCode::Ctap1ErrOther)? as usize,
+            )
+        };
+        let mut auth_data = [0u8; FIDO2_MAX_AUTH_DATA_LENGTH];
+        let auth_data_len = FIDO2AuthenticatorData {
+            rp_id_hash: &rp_id_hash,
+            flags: FIDO2_FLAG_UP | if uv { FIDO2_FLAG_UV } else { 0 },
+            sign_count,
+            attested_credential: Some((&self.attestation.aaguid, &credential_id, public_key)),
+            extensions: extensions_len.map(|len| &extensions_data[..len]),
+        }
+        .apply(&mut auth_data)
+        .ok_or(FIDO2StatusCode::Ctap1ErrOther)? as usize;
+        let message: [&[u8]; 2] = [&auth_data[..auth_data_len], req.client_data_hash];
+        let mut signature = [0u8; 72];
+        let mut certificate = [0u8; FIDO2_MAX_ATTESTATION_CERTIFICATE_LENGTH];
+        let (attestation_algorithm, signature_len, certificate) = match &self.attestation.private_key {
+            Some(attestation_key) => (
+                COSE_ALG_ES256,
+                C::p256_sign(attestation_key, &message, &mut signature),
+                Some(self.attestation.read_certificate(&mut self.storage, &mut certificate)?),
+            ),
+            None => (algorithm, key.sign::<C>(&message, &mut signature), None),
+        };
+        let signature_len = signature_len.ok_or(FIDO2StatusCode::Ctap1ErrOther)?;
+        let large_blob_key = if key.large_blob_key {
+            Some(large_blob_key::<C>(&key))
+        } else {
+            None
+        };
+        FIDO2MakeCredentialResponse {
+            auth_data: &auth_data[..auth_data_len],
+            algorithm: attestation_algorithm,
+            signature: &signature[..signature_len],
+            certificate,
+            large_blob_key: large_blob_key.as_ref(),
+        }
+        .apply(out)
+        .ok_or(FIDO2StatusCode::Ctap1ErrOther)
+    }
+}
@@ -0,0 +1,65 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+use rtic_monotonic::Monotonic;
+
+use crate::fido2_clock::{FIDO2Duration, FIDO2Instant};
+
+// RTIC monotonic on SysTick: one interrupt per millisecond counts the ticks,
+// schedule times are checked on every tick instead of a compare register
+pub(crate) struct FIDO2SysTick {
+    syst: SYST,
+    ticks: u64,
+}
+impl FIDO2SysTick {
+    pub fn new(mut syst: SYST, sysclk_hz: u32) -> FIDO2SysTick {
+        syst.disable_counter();
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(sysclk_hz / 1000 - 1);
+        syst.clear_current();
+        FIDO2SysTick { syst, ticks: 0 }
+    }
+}
+impl Monotonic for FIDO2SysTick {
+    // the tick counter needs every interrupt
+    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+    type Instant = FIDO2Instant;
+    type Duration = FIDO2Duration;
+    fn now(&mut self) -> FIDO2Instant {
+        // a wrap whose interrupt has not run yet, reading COUNTFLAG clears it
+        if self.syst.has_wrapped() {
+            self.ticks = self.ticks.wrapping_add(1);
+        }
+        FIDO2Instant::from_ticks(self.ticks)
+    }
+    fn set_compare(&mut self, _instant: FIDO2Instant) {}
+    fn clear_compare_flag(&mut self) {}
+    fn zero() -> FIDO2Instant {
+        FIDO2Instant::from_ticks(0)
+    }
+    unsafe fn reset(&mut self) {
+        self.syst.enable_interrupt();
+        self.syst.enable_counter();
+    }
+    fn on_interrupt(&mut self) {
+        if self.syst.has_wrapped() {
+            self.ticks = self.ticks.wrapping_add(1);
+        }
+    }
+}
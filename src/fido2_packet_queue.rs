@@ -0,0 +1,69 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// fixed size FIFO of HID reports between the USB interrupt and the application,
+// it lives in a Mutex<RefCell<..>> on the device
+#[derive(Debug)]
+pub(crate) struct FIDO2PacketQueue<const N: usize> {
+    packets: [[u8; 64]; N],
+    head: usize,
+    len: usize,
+}
+impl<const N: usize> FIDO2PacketQueue<N> {
+    pub const fn new() -> FIDO2PacketQueue<N> {
+        FIDO2PacketQueue {
+            packets: [[0u8; 64]; N],
+            head: 0,
+            len: 0,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+    // false if the queue is full, the packet is not taken
+    pub fn push(&mut self, packet: &[u8; 64]) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.packets[(self.head + self.len) % N] = *packet;
+        self.len += 1;
+        true
+    }
+    pub fn peek(&self) -> Option<&[u8; 64]> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(&self.packets[self.head])
+    }
+    pub fn pop(&mut self) -> Option<[u8; 64]> {
+        let packet = *self.peek()?;
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(packet)
+    }
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
@@ -19,12 +19,15 @@
 use num_enum::TryFromPrimitive;
 
 use crate::{
-    consts::{BUILD_VERSION, FIDO2_MAX_DATA_LENGTH, MAJOR_VERSION, MINOR_VERSION},
+    consts::{
+        BUILD_VERSION, FIDO2_MAX_DATA_LENGTH, FIDO2_MAX_NORMAL_PACKET_DATA_SIZE, MAJOR_VERSION,
+        MINOR_VERSION,
+    },
     fido2_internal_error::FIDO2InternalError,
     utils::{channel_id_to_array, channel_id_to_u32, data_len_to_array, data_len_to_u16},
 };
 
-#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive)]
 #[repr(u8)]
 pub(crate) enum FIDO2PacketCommand {
     CtapHIDMsg = 0x03,
@@ -36,6 +39,10 @@ pub(crate) enum FIDO2PacketCommand {
     CtapHIDKeepalive = 0x3B,
     CtapHIDWink = 0x08,
     CtapHIDLock = 0x04,
+    // vendor: attestation provisioning
+    CtapHIDVendorProvision = 0x50,
+    // vendor: firmware version, build time and device id
+    CtapHIDVendorFirmwareInfo = 0x51,
 }
 // packet struct
 #[derive(Debug)]
@@ -71,7 +78,7 @@ impl FIDO2PacketBuilder {
                 return Err(FIDO2InternalError::DataLengthError);
             }
             // select data
-            if data_length_raw > 1 {
+            if data_length_raw > 0 {
                 let data_raw: &[u8] =
                     &packet[7..];
                 for (k, v) in data_raw.iter().enumerate() {
@@ -130,7 +137,8 @@ impl FIDO2PacketBuilder {
         packet[5] = packet_len_arr[0];
         packet[6] = packet_len_arr[1];
         // data
-        for (k, v) in self.data[..self.data_length as usize].iter().enumerate() {
+        let first_len = core::cmp::min(self.data_length as usize, FIDO2_MAX_NORMAL_PACKET_DATA_SIZE);
+        for (k, v) in self.data[..first_len].iter().enumerate() {
             packet[k + 7] = *v;
         }
         return Ok(packet);
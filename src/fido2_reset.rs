@@ -0,0 +1,64 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    consts::{
+        FIDO2_RESET_WINDOW_MS, FIDO2_STORAGE_COUNTER_PAGE, FIDO2_STORAGE_PAGE_SIZE,
+        FIDO2_STORAGE_SEED_PAGE,
+    },
+    fido2_authenticator::{FIDO2Authenticator, FIDO2Platform},
+    fido2_client_pin::FIDO2ClientPinState,
+    fido2_counter::FIDO2SignatureCounter,
+    fido2_credential_store::FIDO2CredentialStore,
+    fido2_crypto::FIDO2CryptoProvider,
+    fido2_device_state::FIDO2DeviceState,
+    fido2_internal_error::FIDO2InternalError,
+    fido2_status_code::FIDO2StatusCode,
+    fido2_storage::{is_erased, page_offset, FIDO2Storage},
+};
+
+impl<S: FIDO2Storage, C: FIDO2CryptoProvider> FIDO2Authenticator<S, C> {
+    // erase the user data pages, then clear the pending flag,
+    // safe to run again if the power goes away halfway
+    pub fn wipe(storage: &mut S, state: &mut FIDO2DeviceState) -> Result<(), FIDO2InternalError> {
+        for page in FIDO2_STORAGE_COUNTER_PAGE..FIDO2_STORAGE_SEED_PAGE {
+            if !is_erased(storage, page_offset(page), FIDO2_STORAGE_PAGE_SIZE)? {
+                storage.erase_page(page)?;
+            }
+        }
+        state.reset_pending = false;
+        // also overwrites the older copy, which still holds the old master secret
+        state.save(storage)
+    }
+    pub fn reset(&mut self, platform: &mut impl FIDO2Platform) -> Result<u16, FIDO2StatusCode> {
+        if platform.uptime_ms() > FIDO2_RESET_WINDOW_MS {
+            return Err(FIDO2StatusCode::Ctap2ErrNotAllowed);
+        }
+        platform.user_presence()?;
+        // the new master secret makes every credential unusable at once
+        let mut state = self.state.reset(&mut self.crypto);
+        state.save(&mut self.storage)?;
+        FIDO2Authenticator::<S, C>::wipe(&mut self.storage, &mut state)?;
+        self.state = state;
+        self.counter = FIDO2SignatureCounter::load(&mut self.storage, &self.state)?;
+        self.credentials = FIDO2CredentialStore::load(&mut self.storage)?;
+        self.pin = FIDO2ClientPinState::new(&mut self.crypto);
+        self.large_blob = None;
+        Ok(0)
+    }
+}
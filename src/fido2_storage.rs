@@ -0,0 +1,59 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{consts::FIDO2_STORAGE_PAGE_SIZE, fido2_internal_error::FIDO2InternalError};
+
+// Trait
+
+// persistent storage, offsets are relative to the start of the storage region
+// and writes follow the STM32F1 rules: halfword aligned, only into erased
+// memory or overwriting with zeros
+pub(crate) trait FIDO2Storage {
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), FIDO2InternalError>;
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), FIDO2InternalError>;
+    fn erase_page(&mut self, page: u32) -> Result<(), FIDO2InternalError>;
+    // readout protection for the whole storage and write protection for the
+    // page, for good: it only goes away with a mass erase of the chip.
+    // storage in RAM has nothing to protect
+    fn protect_page(&mut self, _page: u32) -> Result<(), FIDO2InternalError> {
+        Ok(())
+    }
+}
+
+pub(crate) fn page_offset(page: u32) -> u32 {
+    page * FIDO2_STORAGE_PAGE_SIZE
+}
+
+// true if every byte in the range reads as erased flash (0xff)
+pub(crate) fn is_erased(
+    storage: &mut impl FIDO2Storage,
+    offset: u32,
+    len: u32,
+) -> Result<bool, FIDO2InternalError> {
+    let mut buf = [0u8; 64];
+    let mut done = 0;
+    while done < len {
+        let n = core::cmp::min(buf.len() as u32, len - done) as usize;
+        storage.read(offset + done, &mut buf[..n])?;
+        if buf[..n].iter().any(|v| *v != 0xff) {
+            return Ok(false);
+        }
+        done += n as u32;
+    }
+    Ok(true)
+}
@@ -0,0 +1,249 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "debug-console")]
+use crate::fido2_debug_console::FIDO2ConsoleCommand;
+use crate::{
+    consts::{FIDO2_LED_BLINK_MS, FIDO2_LED_WINK_MS},
+    fido2_clock::{FIDO2Clock, FIDO2Duration},
+    fido2_commands::FIDO2KeepAliveCode,
+    fido2_parser::FIDO2PacketCommand,
+    fido2_status_code::FIDO2StatusCode,
+    global_buffer::GlobalBuffer,
+};
+
+// messages between the tasks, the message buffer is never shared: it moves
+// from the transport task to the CTAP task with the request and comes back
+// once the response is sent
+
+// to the transport task
+#[derive(Debug)]
+pub(crate) enum FIDO2TransportMessage {
+    // HID report from the host
+    Packet([u8; 64]),
+    // the CTAP task is done with the buffer
+    Release(&'static mut GlobalBuffer),
+    // channel table or buffer dump for the debug console
+    #[cfg(feature = "debug-console")]
+    Console(FIDO2ConsoleCommand),
+}
+
+// to the CTAP task, a complete request in the request buffer
+#[derive(Debug)]
+pub(crate) struct FIDO2CtapJob {
+    pub channel_id: u32,
+    pub command: FIDO2PacketCommand,
+    pub length: u16,
+    pub buffer: &'static mut GlobalBuffer,
+}
+
+// the request in the CTAP task, seen by the transport, keepalive and button tasks
+#[derive(Debug)]
+pub struct FIDO2BusyState {
+    pub channel_id: u32,
+    pub up_needed: bool,
+    // a press while up_needed
+    pub confirmed: bool,
+    // CTAPHID_CANCEL or CTAPHID_INIT on the busy channel
+    pub cancelled: bool,
+    // the response is being sent, no more keepalives
+    pub done: bool,
+}
+impl FIDO2BusyState {
+    pub fn new(channel_id: u32) -> FIDO2BusyState {
+        FIDO2BusyState {
+            channel_id,
+            up_needed: false,
+            confirmed: false,
+            cancelled: false,
+            done: false,
+        }
+    }
+    // status for the next CTAPHID_KEEPALIVE, if one should be sent
+    pub fn keepalive(&self) -> Option<FIDO2KeepAliveCode> {
+        if self.done {
+            return None;
+        }
+        Some(if self.up_needed {
+            FIDO2KeepAliveCode::StatusUpNeeded
+        } else {
+            FIDO2KeepAliveCode::StatusProcessing
+        })
+    }
+}
+
+// the user presence wait of the CTAP task, until a press, a cancel or the
+// timeout. `busy` reads (cancelled, confirmed) of the request, `idle` lets the
+// other tasks run (wfi on the device)
+pub(crate) fn wait_user_presence<C: FIDO2Clock>(
+    clock: &mut C,
+    timeout: FIDO2Duration,
+    mut busy: impl FnMut() -> (bool, bool),
+    mut idle: impl FnMut(&mut C),
+) -> Result<(), FIDO2StatusCode> {
+    let deadline = clock.now() + timeout;
+    loop {
+        let (cancelled, confirmed) = busy();
+        if cancelled {
+            return Err(FIDO2StatusCode::Ctap2ErrKeepaliveCancel);
+        }
+        if confirmed {
+            return Ok(());
+        }
+        if clock.expired(deadline) {
+            return Err(FIDO2StatusCode::Ctap2ErrUserActionTimeout);
+        }
+        idle(clock);
+    }
+}
+
+// to the LED task
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum FIDO2LedPattern {
+    Off,
+    // CTAPHID_WINK, on for a while
+    Wink,
+    // blinking while waiting for the user
+    UpNeeded,
+}
+
+#[derive(Debug)]
+pub(crate) struct FIDO2LedState {
+    pattern: FIDO2LedPattern,
+    since: u64,
+}
+impl FIDO2LedState {
+    pub fn new() -> FIDO2LedState {
+        FIDO2LedState {
+            pattern: FIDO2LedPattern::Off,
+            since: 0,
+        }
+    }
+    pub fn set(&mut self, pattern: FIDO2LedPattern, now: u64) {
+        self.pattern = pattern;
+        self.since = now;
+    }
+    // true if the LED should be on
+    pub fn level(&mut self, now: u64) -> bool {
+        let elapsed = now - self.since;
+        match self.pattern {
+            FIDO2LedPattern::Off => false,
+            FIDO2LedPattern::Wink if elapsed < FIDO2_LED_WINK_MS => true,
+            FIDO2LedPattern::Wink => {
+                self.pattern = FIDO2LedPattern::Off;
+                false
+            }
+            FIDO2LedPattern::UpNeeded => (elapsed / FIDO2_LED_BLINK_MS).is_multiple_of(2),
+        }
+    }
+}
+
+// transport events in the debug log, off until "trace on" on the debug console
+static FIDO2_PACKET_TRACE: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn packet_trace() -> bool {
+    FIDO2_PACKET_TRACE.load(Ordering::Relaxed)
+}
+pub(crate) fn set_packet_trace(enabled: bool) {
+    FIDO2_PACKET_TRACE.store(enabled, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consts::FIDO2_USER_PRESENCE_TIMEOUT_MS,
+        fido2_button::{FIDO2Button, FIDO2ButtonEventKind, FIDO2PresenceInput},
+        fido2_clock::{FIDO2Instant, FIDO2ManualClock},
+    };
+    use core::cell::Cell;
+    use fugit::ExtU64;
+
+    // the CTAP task waiting while the button task polls the pin every
+    // millisecond, `pressed` is the pin level at a given time
+    fn wait(
+        pressed: impl Fn(u64) -> bool,
+        cancel_at: Option<u64>,
+    ) -> (Result<(), FIDO2StatusCode>, u64) {
+        let mut clock = FIDO2ManualClock::new(FIDO2Instant::from_ticks(0));
+        let mut button = FIDO2Button::new();
+        let confirmed = Cell::new(false);
+        let cancelled = Cell::new(false);
+        let result = wait_user_presence(
+            &mut clock,
+            FIDO2_USER_PRESENCE_TIMEOUT_MS.millis(),
+            || (cancelled.get(), confirmed.get()),
+            |clock| {
+                clock.advance(1.millis());
+                let now = clock.now_ms();
+                if let Some(event) = button.update(pressed(now), now) {
+                    if matches!(
+                        event.kind,
+                        FIDO2ButtonEventKind::Press | FIDO2ButtonEventKind::LongPress
+                    ) {
+                        confirmed.set(true);
+                    }
+                }
+                if cancel_at == Some(now) {
+                    cancelled.set(true);
+                }
+            },
+        );
+        (result, clock.now_ms())
+    }
+
+    #[test]
+    fn user_presence_times_out_after_30_seconds() {
+        assert_eq!(
+            wait(|_| false, None),
+            (
+                Err(FIDO2StatusCode::Ctap2ErrUserActionTimeout),
+                FIDO2_USER_PRESENCE_TIMEOUT_MS
+            )
+        );
+        // a press still held at the deadline does not count
+        assert_eq!(
+            wait(|now| now >= FIDO2_USER_PRESENCE_TIMEOUT_MS - 100, None),
+            (
+                Err(FIDO2StatusCode::Ctap2ErrUserActionTimeout),
+                FIDO2_USER_PRESENCE_TIMEOUT_MS
+            )
+        );
+    }
+
+    #[test]
+    fn user_presence_is_confirmed_by_a_tap_or_a_long_press() {
+        // pressed 1000..1100, the release is taken 20 ms later
+        assert_eq!(
+            wait(|now| (1000..1100).contains(&now), None),
+            (Ok(()), 1120)
+        );
+        // held from 1000 on, pressed is taken at 1020 and long at 2520
+        assert_eq!(wait(|now| now >= 1000, None), (Ok(()), 2520));
+    }
+
+    #[test]
+    fn user_presence_is_cancelled() {
+        assert_eq!(
+            wait(|_| false, Some(5000)),
+            (Err(FIDO2StatusCode::Ctap2ErrKeepaliveCancel), 5000)
+        );
+    }
+}
@@ -0,0 +1,186 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    consts::{
+        FIDO2_TOUCH_CALIBRATION_SAMPLES, FIDO2_TOUCH_RECALIBRATE_MS, FIDO2_TOUCH_THRESHOLD_PERCENT,
+    },
+    fido2_button::{FIDO2Button, FIDO2ButtonEvent},
+};
+
+// the baseline is kept with 4 fractional bits, it follows slow changes
+// (temperature, humidity) with a 1/16 step while the pad is not touched
+const BASELINE_SHIFT: u32 = 4;
+
+// touch detection from charge times, the level goes through the same
+// debouncing and event logic as the button
+#[derive(Debug)]
+pub(crate) struct FIDO2TouchSensor {
+    button: FIDO2Button,
+    baseline: u32,
+    calibration_sum: u32,
+    calibration_samples: u32,
+    touched: bool,
+    touched_since: u64,
+}
+impl FIDO2TouchSensor {
+    pub fn new() -> FIDO2TouchSensor {
+        FIDO2TouchSensor {
+            button: FIDO2Button::new(),
+            baseline: 0,
+            calibration_sum: 0,
+            calibration_samples: 0,
+            touched: false,
+            touched_since: 0,
+        }
+    }
+    fn recalibrate(&mut self) {
+        self.calibration_sum = 0;
+        self.calibration_samples = 0;
+        self.touched = false;
+    }
+    // charge time of one measurement, in timer ticks
+    pub fn sample(&mut self, charge_time: u32, now: u64) {
+        if self.calibration_samples < FIDO2_TOUCH_CALIBRATION_SAMPLES {
+            self.calibration_sum += charge_time;
+            self.calibration_samples += 1;
+            if self.calibration_samples == FIDO2_TOUCH_CALIBRATION_SAMPLES {
+                self.baseline =
+                    (self.calibration_sum << BASELINE_SHIFT) / FIDO2_TOUCH_CALIBRATION_SAMPLES;
+            }
+            return;
+        }
+        let baseline = self.baseline >> BASELINE_SHIFT;
+        let threshold = (baseline * FIDO2_TOUCH_THRESHOLD_PERCENT / 100).max(2);
+        if self.touched {
+            // hysteresis, released at half the threshold
+            if charge_time < baseline + threshold / 2 {
+                self.touched = false;
+            } else if now - self.touched_since >= FIDO2_TOUCH_RECALIBRATE_MS {
+                self.recalibrate();
+            }
+        } else if charge_time > baseline + threshold {
+            self.touched = true;
+            self.touched_since = now;
+        } else {
+            let sample = charge_time << BASELINE_SHIFT;
+            if sample > self.baseline {
+                self.baseline += (sample - self.baseline) >> BASELINE_SHIFT;
+            } else {
+                self.baseline -= (self.baseline - sample) >> BASELINE_SHIFT;
+            }
+        }
+    }
+    pub fn update(&mut self, now: u64) -> Option<FIDO2ButtonEvent> {
+        self.button.update(self.touched, now)
+    }
+    pub fn clear(&mut self) {
+        self.button.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::FIDO2_BUTTON_DEBOUNCE_MS, fido2_button::FIDO2ButtonEventKind};
+
+    // a sensor calibrated to 1000 ticks, the threshold is 200 above that
+    fn calibrated() -> FIDO2TouchSensor {
+        let mut sensor = FIDO2TouchSensor::new();
+        for k in 0..FIDO2_TOUCH_CALIBRATION_SAMPLES {
+            sensor.sample(if k % 2 == 0 { 990 } else { 1010 }, 0);
+        }
+        assert_eq!(sensor.baseline >> BASELINE_SHIFT, 1000);
+        sensor
+    }
+
+    #[test]
+    fn calibration_ignores_touches() {
+        let mut sensor = FIDO2TouchSensor::new();
+        for _ in 1..FIDO2_TOUCH_CALIBRATION_SAMPLES {
+            sensor.sample(5000, 0);
+            assert!(!sensor.touched);
+        }
+    }
+
+    #[test]
+    fn threshold_and_hysteresis() {
+        let mut sensor = calibrated();
+        sensor.sample(1200, 10);
+        assert!(!sensor.touched);
+        let mut sensor = calibrated();
+        sensor.sample(1201, 20);
+        assert!(sensor.touched);
+        // released below half the threshold only
+        sensor.sample(1100, 30);
+        assert!(sensor.touched);
+        sensor.sample(1099, 40);
+        assert!(!sensor.touched);
+    }
+
+    #[test]
+    fn baseline_follows_slow_drift() {
+        let mut sensor = calibrated();
+        // 15% up over time is followed, no touch
+        for k in 0..400 {
+            sensor.sample(1150, k * 5);
+            assert!(!sensor.touched);
+        }
+        assert!(sensor.baseline >> BASELINE_SHIFT >= 1140);
+        // the threshold moves with it
+        sensor.sample(1300, 2000);
+        assert!(!sensor.touched);
+        sensor.sample(1400, 2005);
+        assert!(sensor.touched);
+    }
+
+    #[test]
+    fn held_too_long_recalibrates() {
+        let mut sensor = calibrated();
+        sensor.sample(2000, 100);
+        assert!(sensor.touched);
+        sensor.sample(2000, 100 + FIDO2_TOUCH_RECALIBRATE_MS - 1);
+        assert!(sensor.touched);
+        sensor.sample(2000, 100 + FIDO2_TOUCH_RECALIBRATE_MS);
+        assert!(!sensor.touched);
+        // the new baseline is the covered pad
+        for k in 0..FIDO2_TOUCH_CALIBRATION_SAMPLES as u64 {
+            sensor.sample(2000, 20_000 + k * 5);
+        }
+        assert_eq!(sensor.baseline >> BASELINE_SHIFT, 2000);
+        sensor.sample(2000, 21_000);
+        assert!(!sensor.touched);
+    }
+
+    #[test]
+    fn touch_is_a_debounced_press() {
+        let mut sensor = calibrated();
+        sensor.sample(1500, 100);
+        assert_eq!(sensor.update(100), None);
+        assert_eq!(sensor.update(100 + FIDO2_BUTTON_DEBOUNCE_MS), None);
+        sensor.sample(1000, 200);
+        assert_eq!(sensor.update(200), None);
+        assert_eq!(
+            sensor.update(200 + FIDO2_BUTTON_DEBOUNCE_MS),
+            Some(FIDO2ButtonEvent {
+                kind: FIDO2ButtonEventKind::Press,
+                timestamp: 200 + FIDO2_BUTTON_DEBOUNCE_MS,
+            })
+        );
+    }
+}
@@ -0,0 +1,316 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::{
+    consts::{
+        FIDO2_BROADCAST_CHANNEL_ID, FIDO2_CHANNEL_COUNT, FIDO2_CHANNEL_ID_FIRST,
+        FIDO2_MAX_CHUNK_PACKET_DATA_SIZE, FIDO2_MAX_NORMAL_PACKET_DATA_SIZE,
+    },
+    fido2_chunk::{FIDO2ChunkMerger, FIDO2ChunkSpliter},
+    fido2_commands::{FIDO2ErrorCode, FIDO2PacketCommandErrorResponse, FIDO2PacketCommandResponse},
+    fido2_internal_error::FIDO2InternalError,
+    fido2_parser::{FIDO2PacketBuilder, FIDO2PacketCommand},
+    global_buffer::GlobalBuffer,
+};
+
+// what the application has to do after a packet was received
+#[derive(Debug)]
+pub(crate) enum FIDO2TransportEvent {
+    // answer CTAPHID_INIT on channel_id, the allocated channel is new_channel_id
+    Init {
+        channel_id: u32,
+        new_channel_id: u32,
+        nonce: [u8; 8],
+    },
+    // a complete request is in the request buffer
+    Request {
+        channel_id: u32,
+        command: FIDO2PacketCommand,
+        length: u16,
+    },
+    Cancel {
+        channel_id: u32,
+    },
+    Error {
+        channel_id: u32,
+        code: FIDO2ErrorCode,
+    },
+}
+
+#[derive(Debug)]
+struct FIDO2PendingRequest {
+    channel_id: u32,
+    command: FIDO2PacketCommand,
+    merger: FIDO2ChunkMerger,
+    next_seq: u8,
+}
+
+#[derive(Debug)]
+pub(crate) struct FIDO2Transport {
+    channels: [bool; FIDO2_CHANNEL_COUNT],
+    // round robin, so a client that never releases its channel can't starve the others
+    next_channel: usize,
+    pending: Option<FIDO2PendingRequest>,
+}
+impl FIDO2Transport {
+    pub fn new() -> FIDO2Transport {
+        FIDO2Transport {
+            channels: [false; FIDO2_CHANNEL_COUNT],
+            next_channel: 0,
+            pending: None,
+        }
+    }
+    fn channel_index(channel_id: u32) -> Option<usize> {
+        if channel_id < FIDO2_CHANNEL_ID_FIRST
+            || channel_id >= FIDO2_CHANNEL_ID_FIRST + FIDO2_CHANNEL_COUNT as u32
+        {
+            return None;
+        }
+        Some((channel_id - FIDO2_CHANNEL_ID_FIRST) as usize)
+    }
+    pub fn channel_create(&mut self) -> u32 {
+        // reuse the oldest one if all ids are taken
+        let index = (0..FIDO2_CHANNEL_COUNT)
+            .map(|k| (self.next_channel + k) % FIDO2_CHANNEL_COUNT)
+            .find(|k| !self.channels[*k])
+            .unwrap_or(self.next_channel);
+        self.channels[index] = true;
+        self.next_channel = (index + 1) % FIDO2_CHANNEL_COUNT;
+        FIDO2_CHANNEL_ID_FIRST + index as u32
+    }
+    pub fn channel_delete(&mut self, channel_id: u32) {
+        if let Some(k) = Self::channel_index(channel_id) {
+            self.channels[k] = false;
+        }
+    }
+    pub fn channel_exists(&self, channel_id: u32) -> bool {
+        Self::channel_index(channel_id).is_some_and(|k| self.channels[k])
+    }
+    pub fn is_busy(&self) -> bool {
+        self.pending.is_some()
+    }
+    pub fn channel_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..FIDO2_CHANNEL_COUNT)
+            .filter(|k| self.channels[*k])
+            .map(|k| FIDO2_CHANNEL_ID_FIRST + k as u32)
+    }
+    // channel and command of a request that is still being received
+    pub fn pending_request(&self) -> Option<(u32, FIDO2PacketCommand)> {
+        self.pending.as_ref().map(|p| (p.channel_id, p.command))
+    }
+    fn error(channel_id: u32, code: FIDO2ErrorCode) -> Option<FIDO2TransportEvent> {
+        Some(FIDO2TransportEvent::Error { channel_id, code })
+    }
+    pub fn receive(
+        &mut self,
+        packet: [u8; 64],
+        buffer: &mut GlobalBuffer,
+    ) -> Option<FIDO2TransportEvent> {
+        let parsed = match FIDO2PacketBuilder::new_from_raw_packet(packet) {
+            Ok(p) => p,
+            Err(FIDO2InternalError::ReversedChannelError) => {
+                return Self::error(0, FIDO2ErrorCode::ErrInvalidChannel)
+            }
+            Err(FIDO2InternalError::DataLengthError) => {
+                let channel_id = crate::utils::channel_id_to_u32(&packet[0..4]);
+                return Self::error(channel_id, FIDO2ErrorCode::ErrInvalidLen);
+            }
+            Err(_) => {
+                let channel_id = crate::utils::channel_id_to_u32(&packet[0..4]);
+                return Self::error(channel_id, FIDO2ErrorCode::ErrInvalidCmd);
+            }
+        };
+        let channel_id = parsed.channel_id;
+        // continuation packet
+        if parsed.is_seq {
+            let pending = match &mut self.pending {
+                Some(p) if p.channel_id == channel_id => p,
+                // spurious continuation packets are ignored
+                _ => return None,
+            };
+            if parsed.seq_id != pending.next_seq {
+                self.pending = None;
+                return Self::error(channel_id, FIDO2ErrorCode::ErrInvalidSeq);
+            }
+            pending.next_seq += 1;
+            pending.merger.apply(
+                &mut buffer.request_buffer,
+                &parsed.data[..FIDO2_MAX_CHUNK_PACKET_DATA_SIZE],
+                parsed.seq_id,
+            );
+            if !pending.merger.is_done() {
+                return None;
+            }
+            let pending = self.pending.take().unwrap();
+            buffer.set_request_done(pending.merger.data_length);
+            return Some(FIDO2TransportEvent::Request {
+                channel_id,
+                command: pending.command,
+                length: pending.merger.data_length,
+            });
+        }
+        // initialization packet
+        let command = parsed.packet_type.unwrap();
+        if command == FIDO2PacketCommand::CtapHIDInit {
+            if parsed.data_length != 8 {
+                return Self::error(channel_id, FIDO2ErrorCode::ErrInvalidLen);
+            }
+            let nonce: [u8; 8] = parsed.data[..8].try_into().unwrap();
+            if channel_id == FIDO2_BROADCAST_CHANNEL_ID {
+                return Some(FIDO2TransportEvent::Init {
+                    channel_id,
+                    new_channel_id: self.channel_create(),
+                    nonce,
+                });
+            }
+            if !self.channel_exists(channel_id) {
+                return Self::error(channel_id, FIDO2ErrorCode::ErrInvalidChannel);
+            }
+            // resync, abort the transaction of this channel
+            if self.pending.as_ref().is_some_and(|p| p.channel_id == channel_id) {
+                self.pending = None;
+            }
+            return Some(FIDO2TransportEvent::Init {
+                channel_id,
+                new_channel_id: channel_id,
+                nonce,
+            });
+        }
+        if !self.channel_exists(channel_id) {
+            return Self::error(channel_id, FIDO2ErrorCode::ErrInvalidChannel);
+        }
+        if command == FIDO2PacketCommand::CtapHIDCancel {
+            if self.pending.as_ref().is_some_and(|p| p.channel_id == channel_id) {
+                self.pending = None;
+            }
+            return Some(FIDO2TransportEvent::Cancel { channel_id });
+        }
+        if let Some(p) = &self.pending {
+            if p.channel_id == channel_id {
+                self.pending = None;
+                return Self::error(channel_id, FIDO2ErrorCode::ErrInvalidSeq);
+            }
+            return Self::error(channel_id, FIDO2ErrorCode::ErrChannelBusy);
+        }
+        let length = parsed.data_length;
+        if length as usize > buffer.request_buffer.len() {
+            return Self::error(channel_id, FIDO2ErrorCode::ErrInvalidLen);
+        }
+        buffer.clear_request();
+        let first_len = core::cmp::min(length as usize, FIDO2_MAX_NORMAL_PACKET_DATA_SIZE);
+        let mut merger = FIDO2ChunkMerger::new(length, first_len as u8);
+        merger.apply(&mut buffer.request_buffer, &parsed.data[..first_len], 0xff);
+        if merger.is_done() {
+            buffer.set_request_done(length);
+            return Some(FIDO2TransportEvent::Request {
+                channel_id,
+                command,
+                length,
+            });
+        }
+        self.pending = Some(FIDO2PendingRequest {
+            channel_id,
+            command,
+            merger,
+            next_seq: 0,
+        });
+        None
+    }
+    // while a request of busy_channel_id is being processed the request buffer
+    // is in use, only CTAPHID_INIT and CTAPHID_CANCEL get through
+    pub fn receive_busy(
+        &mut self,
+        packet: [u8; 64],
+        busy_channel_id: u32,
+    ) -> Option<FIDO2TransportEvent> {
+        let parsed = match FIDO2PacketBuilder::new_from_raw_packet(packet) {
+            Ok(p) => p,
+            Err(_) => return None,
+        };
+        let channel_id = parsed.channel_id;
+        if parsed.is_seq {
+            return None;
+        }
+        match parsed.packet_type.unwrap() {
+            FIDO2PacketCommand::CtapHIDInit if parsed.data_length == 8 => {
+                let nonce: [u8; 8] = parsed.data[..8].try_into().unwrap();
+                if channel_id == FIDO2_BROADCAST_CHANNEL_ID {
+                    return Some(FIDO2TransportEvent::Init {
+                        channel_id,
+                        new_channel_id: self.channel_create(),
+                        nonce,
+                    });
+                }
+                if !self.channel_exists(channel_id) {
+                    return Self::error(channel_id, FIDO2ErrorCode::ErrInvalidChannel);
+                }
+                Some(FIDO2TransportEvent::Init {
+                    channel_id,
+                    new_channel_id: channel_id,
+                    nonce,
+                })
+            }
+            FIDO2PacketCommand::CtapHIDCancel if channel_id == busy_channel_id => {
+                Some(FIDO2TransportEvent::Cancel { channel_id })
+            }
+            // a cancel of another channel has nothing to cancel
+            FIDO2PacketCommand::CtapHIDCancel => None,
+            _ => Self::error(channel_id, FIDO2ErrorCode::ErrChannelBusy),
+        }
+    }
+    // split a response into packets
+    pub fn send(
+        channel_id: u32,
+        command: FIDO2PacketCommand,
+        data: &[u8],
+        mut push: impl FnMut(&[u8; 64]),
+    ) {
+        let length = data.len() as u16;
+        let first_len = core::cmp::min(data.len(), FIDO2_MAX_NORMAL_PACKET_DATA_SIZE);
+        let spliter = FIDO2ChunkSpliter::new(length, first_len as u8);
+        let mut packet_data = [0u8; 64 - 5];
+        spliter.apply(data, &mut packet_data[..first_len], 0xff);
+        let pack = FIDO2PacketBuilder {
+            channel_id,
+            packet_type: Some(command),
+            seq_id: 0xff,
+            data_length: length,
+            is_seq: false,
+            data: packet_data,
+        };
+        push(&pack.pack().unwrap());
+        for seq_id in 0..spliter.chunks() {
+            let mut packet_data = [0u8; 64 - 5];
+            spliter.apply(data, &mut packet_data, seq_id);
+            let pack = FIDO2PacketBuilder {
+                channel_id,
+                packet_type: None,
+                seq_id,
+                data_length: FIDO2_MAX_CHUNK_PACKET_DATA_SIZE as u16,
+                is_seq: true,
+                data: packet_data,
+            };
+            push(&pack.pack().unwrap());
+        }
+    }
+    pub fn send_error(channel_id: u32, code: FIDO2ErrorCode, push: impl FnMut(&[u8; 64])) {
+        let mut data = [0u8; 1];
+        FIDO2PacketCommandErrorResponse::new(code).apply(&mut data);
+        Self::send(channel_id, FIDO2PacketCommand::CtapHIDError, &data, push);
+    }
+}
@@ -1,20 +1,20 @@
-use crate::consts::FIDO2_MAX_DATA_LENGTH;
-use crate::FIDO2Commands::FIDO2PacketCommandResponse;
+use crate::consts::FIDO2_MESSAGE_BUFFER_SIZE;
+use crate::fido2_commands::FIDO2PacketCommandResponse;
 
 #[derive(Debug)]
 pub(crate) struct GlobalBuffer {
-    pub request_buffer: [u8; FIDO2_MAX_DATA_LENGTH],
-    pub response_buffer: [u8; FIDO2_MAX_DATA_LENGTH],
+    pub request_buffer: [u8; FIDO2_MESSAGE_BUFFER_SIZE],
+    pub response_buffer: [u8; FIDO2_MESSAGE_BUFFER_SIZE],
     pub request_buffer_data_len: u16,
     pub response_buffer_data_len: u16,
     pub request_buffer_done: bool,
     pub response_buffer_done: bool,
 }
 impl GlobalBuffer {
-    pub fn new() -> GlobalBuffer {
+    pub const fn new() -> GlobalBuffer {
         GlobalBuffer {
-            request_buffer: [0u8; FIDO2_MAX_DATA_LENGTH],
-            response_buffer: [0u8; FIDO2_MAX_DATA_LENGTH],
+            request_buffer: [0u8; FIDO2_MESSAGE_BUFFER_SIZE],
+            response_buffer: [0u8; FIDO2_MESSAGE_BUFFER_SIZE],
             request_buffer_data_len: 0,
             response_buffer_data_len: 0,
             request_buffer_done: false,
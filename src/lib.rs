@@ -0,0 +1,64 @@
+/*
+    unsafe{key}: The most unsafe usb security key that support FIDO2 protocol
+    Copyright (C) 2022 sb-child
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+// the hardware independent modules of the firmware, built on the host for the
+// tests (cargo test-host), main.rs declares the same modules for the device
+#![cfg_attr(not(test), no_std)]
+// most items (and the log macros) are only used by the firmware, which is
+// where unused code is reported
+#![allow(dead_code, unused_imports, unused_macros)]
+
+mod consts;
+mod fido2_attestation;
+mod fido2_auth_data;
+mod fido2_authenticator;
+mod fido2_button;
+mod fido2_cbor;
+mod fido2_chunk;
+mod fido2_client_pin;
+mod fido2_clock;
+mod fido2_commands;
+mod fido2_config;
+mod fido2_cose;
+mod fido2_counter;
+mod fido2_credential_management;
+mod fido2_credential_store;
+mod fido2_crypto;
+mod fido2_crypto_self_test;
+mod fido2_debug_console;
+mod fido2_device_identity;
+mod fido2_device_state;
+mod fido2_entropy;
+mod fido2_extensions;
+mod fido2_get_assertion;
+mod fido2_get_info;
+mod fido2_internal_error;
+mod fido2_key_wrap;
+mod fido2_large_blobs;
+mod fido2_log;
+mod fido2_make_credential;
+mod fido2_packet_queue;
+mod fido2_parser;
+mod fido2_reset;
+mod fido2_status_code;
+mod fido2_storage;
+mod fido2_tasks;
+mod fido2_touch;
+mod fido2_transport;
+mod global_buffer;
+mod utils;
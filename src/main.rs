@@ -19,21 +19,20 @@
 #![no_main]
 #![no_std]
 #![allow(unused_imports)]
+// RTIC implements its pub Mutex trait for the shared resources, so their types
+// are pub while the modules keep what they use pub(crate)
+#![allow(private_interfaces, private_bounds)]
 
 extern crate alloc;
 
-use alloc::string::String;
 use embedded_alloc::Heap;
 use num_enum::TryFromPrimitive;
 // use panic_halt as _;
 use build_time;
 use byteorder;
-use core::cell::RefCell;
 use core::fmt::Write;
 use core::ops::DerefMut;
 use core::str::from_utf8;
-use cortex_m::interrupt::Mutex;
-use cortex_m_rt::entry;
 use embedded_hal::digital::v2::{InputPin, IoPin, OutputPin};
 use fugit::MicrosDuration;
 use nb::block;
@@ -41,227 +40,799 @@ use num_enum::IntoPrimitive;
 use panic_reset as _;
 use stm32f1xx_hal::device::TIM1;
 use stm32f1xx_hal::device::TIM2;
-use stm32f1xx_hal::gpio::PinState;
+use stm32f1xx_hal::adc::Adc;
+use stm32f1xx_hal::flash::{FlashSize, SectorSize};
 use stm32f1xx_hal::i2c;
-use stm32f1xx_hal::stm32::{self, interrupt, Interrupt};
+use stm32f1xx_hal::stm32::{self, Interrupt};
 use stm32f1xx_hal::timer::Event;
-use stm32f1xx_hal::{afio::AfioExt, pac, usb};
-use stm32f1xx_hal::{prelude::*, serial};
-use usb_device::{self, prelude::*};
-use usbd_hid::{self, descriptor::generator_prelude::*};
+use stm32f1xx_hal::{pac, serial, usb};
+use stm32f1xx_hal::prelude::*;
+use usb_device::{self, bus::UsbBusAllocator, prelude::*};
+use usbd_hid::{self, descriptor::generator_prelude::*, hid_class::HIDClass};
 
+mod board;
+#[cfg(feature = "board-bluepill")]
+mod board_bluepill;
+#[cfg(feature = "board-unsafekey-v1")]
+mod board_unsafekey_v1;
 mod consts;
+mod fido2_attestation;
+mod fido2_auth_data;
+#[cfg(feature = "debug-console")]
+mod fido2_bootloader;
+mod fido2_button;
+mod fido2_authenticator;
+mod fido2_cbor;
 mod fido2_chunk;
+mod fido2_client_pin;
+mod fido2_clock;
 mod fido2_commands;
+mod fido2_config;
+mod fido2_cose;
+mod fido2_counter;
+mod fido2_credential_management;
+mod fido2_credential_store;
+mod fido2_crypto;
+mod fido2_crypto_self_test;
+mod fido2_debug_console;
+mod fido2_device_identity;
+mod fido2_device_state;
+mod fido2_entropy;
+mod fido2_extensions;
+mod fido2_flash;
+mod fido2_get_assertion;
+mod fido2_get_info;
 mod fido2_hid_desc;
 mod fido2_internal_error;
+mod fido2_key_wrap;
+mod fido2_large_blobs;
+mod fido2_log;
+mod fido2_make_credential;
+mod fido2_monotonic;
+mod fido2_packet_queue;
 mod fido2_parser;
+mod fido2_reset;
+mod fido2_status_code;
+mod fido2_storage;
+mod fido2_tasks;
+mod fido2_touch;
+mod fido2_transport;
 mod global_buffer;
 mod utils;
 
+use board as Board;
 use consts as ProjectConsts;
+use fido2_authenticator as FIDO2Authenticator;
+#[cfg(feature = "debug-console")]
+use fido2_bootloader as FIDO2Bootloader;
+use fido2_button as FIDO2Button;
 use fido2_chunk as FIDO2Chunk;
+use fido2_clock as FIDO2Clock;
 use fido2_commands as FIDO2Commands;
+use fido2_crypto as FIDO2Crypto;
+use fido2_crypto_self_test as FIDO2CryptoSelfTest;
+use fido2_debug_console as FIDO2DebugConsole;
+use fido2_device_identity as FIDO2DeviceIdentity;
+use fido2_entropy as FIDO2Entropy;
+use fido2_flash as FIDO2Flash;
 use fido2_hid_desc as FIDO2HID;
 use fido2_internal_error as FIDO2Errors;
+use fido2_log as FIDO2Log;
+use fido2_monotonic as FIDO2Monotonic;
+use fido2_packet_queue as FIDO2PacketQueue;
 use fido2_parser as FIDO2Parser;
+use fido2_status_code as FIDO2Status;
+use fido2_tasks as FIDO2Tasks;
+use fido2_transport as FIDO2Transport;
 use global_buffer as GlobalBuffer;
 use utils as Utils;
 
+use Board::FIDO2Board;
+use FIDO2Button::FIDO2PresenceInput;
 use FIDO2Commands::FIDO2PacketCommandResponse;
-
-static mut GLOBAL_TIMER: u128 = 0;
+use FIDO2Log::{log_debug, log_error, log_info, log_warn};
 
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
 
-// static G_TIM: Mutex<RefCell<Option<stm32f1xx_hal::timer::CounterUs<TIM1>>>> =
-//     Mutex::new(RefCell::new(None));
-
-// #[interrupt]
-// fn TIM1_UP() {
-//     static mut STIM: Option<stm32f1xx_hal::timer::CounterUs<TIM1>> = None;
-//     let tim = STIM.get_or_insert_with(|| {
-//         cortex_m::interrupt::free(|cs| G_TIM.borrow(cs).replace(None).unwrap())
-//     });
-//     unsafe { GLOBAL_TIMER += 1 };
-//     let _ = tim.wait();
-// }
-fn get_timer() -> u128 {
-    unsafe { GLOBAL_TIMER }
+type Crypto = FIDO2Crypto::FIDO2SoftwareCrypto<
+    FIDO2Entropy::FIDO2EntropyRng<FIDO2AdcNoise, FIDO2Crypto::FIDO2SoftwareHash>,
+>;
+type Authenticator =
+    FIDO2Authenticator::FIDO2Authenticator<FIDO2Flash::FIDO2FlashStorage<'static>, Crypto>;
+type Presence = <Board::CurrentBoard as Board::FIDO2Board>::Presence;
+type LedPin = <Board::CurrentBoard as Board::FIDO2Board>::LedPin;
+// where the log goes, the debug console answers there too
+#[cfg(not(feature = "log-rtt"))]
+type LogSink = serial::Tx<pac::USART1>;
+#[cfg(feature = "log-rtt")]
+type LogSink = FIDO2Log::FIDO2RttSink;
+
+// the USB device, serviced in USB_LP_CAN_RX0
+pub struct FIDO2Usb {
+    dev: UsbDevice<'static, usb::UsbBusType>,
+    ctrl: HIDClass<'static, usb::UsbBusType>,
+    tx: FIDO2PacketQueue::FIDO2PacketQueue<{ ProjectConsts::FIDO2_USB_TX_QUEUE_LENGTH }>,
 }
-fn add_timer() {
-    unsafe {
-        GLOBAL_TIMER += 1;
+
+// queue a report for the host, waiting while the queue is full
+fn usb_push(usb: &mut impl rtic::Mutex<T = FIDO2Usb>, packet: &[u8; 64]) {
+    while !usb.lock(|usb| usb.tx.push(packet)) {
+        rtic::pend(Interrupt::USB_LP_CAN_RX0);
+        cortex_m::asm::wfi();
     }
+    rtic::pend(Interrupt::USB_LP_CAN_RX0);
 }
 
-#[entry]
-fn main() -> ! {
-    {
-        // alloc init
-        use core::mem::MaybeUninit;
-        const HEAP_SIZE: usize = 1024;
-        static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
-        unsafe { HEAP.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
+// correct transfer interrupts of the USB peripheral, usb-device has no API for it
+fn usb_ctr_interrupt(enable: bool) {
+    let usb = unsafe { &*pac::USB::ptr() };
+    usb.cntr.modify(|_, w| w.ctrm().bit(enable));
+}
+
+// log records are written with the tasks up to priority 2 masked (a BASEPRI
+// lock like the RTIC resources), the debug console and the other tasks that
+// log never find the sink busy. only usb_interrupt could, it does not log
+fn log_critical(f: &mut dyn FnMut()) {
+    const CEILING: u8 = ((1 << pac::NVIC_PRIO_BITS) - 2) << (8 - pac::NVIC_PRIO_BITS);
+    let basepri = cortex_m::register::basepri::read();
+    cortex_m::register::basepri_max::write(CEILING);
+    f();
+    unsafe { cortex_m::register::basepri::write(basepri) };
+}
+
+// Vrefint and temperature sensor conversions, the ADC runs from its own
+// prescaled clock so the conversion time also jitters against the core cycle
+// counter (see the health tests in fido2_entropy for the entropy estimate)
+pub struct FIDO2AdcNoise {
+    adc: Adc<pac::ADC1>,
+}
+impl FIDO2Entropy::FIDO2NoiseSource for FIDO2AdcNoise {
+    fn sample(&mut self) -> u8 {
+        let start = cortex_m::peripheral::DWT::cycle_count();
+        let vref = self.adc.read_vref();
+        // two more conversions (sensor and Vrefint) at the long sample time
+        let temp = self.adc.read_temp();
+        let jitter = cortex_m::peripheral::DWT::cycle_count().wrapping_sub(start);
+        vref as u8 ^ (temp as u8).rotate_left(4) ^ jitter as u8
     }
-    // hardware init
-    let dp = pac::Peripherals::take().unwrap();
-    let cp = cortex_m::Peripherals::take().unwrap();
-    let rcc = dp.RCC.constrain();
-    let mut flash = dp.FLASH.constrain();
-    let clocks = rcc
-        .cfgr
-        .use_hse(8.MHz())
-        .sysclk(72.MHz())
-        .freeze(&mut flash.acr);
-    let mut gpioa = dp.GPIOA.split();
-    let mut gpiob = dp.GPIOB.split();
-    let mut gpioc = dp.GPIOC.split();
-    let mut gpiod = dp.GPIOD.split();
-    let mut afio = dp.AFIO.constrain();
-    let (pa15, pb3, pb4) = afio.mapr.disable_jtag(gpioa.pa15, gpiob.pb3, gpiob.pb4);
-    // debug serial port
-    let tx = gpioa.pa9.into_alternate_push_pull(&mut gpioa.crh);
-    let rx = gpioa.pa10;
-    let serial = serial::Serial::new(
-        dp.USART1,
-        (tx, rx),
-        &mut afio.mapr,
-        serial::Config::default().baudrate(115200.bps()),
-        &clocks,
+}
+
+fn send_init(channel_id: u32, new_channel_id: u32, nonce: [u8; 8], push: impl FnMut(&[u8; 64])) {
+    let command_resp = FIDO2Commands::FIDO2PacketCommandInitResponse::new(
+        nonce,
+        Utils::channel_id_to_array(new_channel_id),
     );
-    let (mut tx, rx) = serial.split();
-    // build timestamp
-    let mut _usb_serial_number = String::from("Firmware v000 ");
-    _usb_serial_number.push_str(build_time::build_time_local!("%Y%m%d-%H%M%S%z"));
-    let mut _usb_serial_number = _usb_serial_number.into_bytes();
-    Utils::insert_number_string(&mut _usb_serial_number, ProjectConsts::BUILD_VERSION, 12);
-    let _usb_serial_number = from_utf8(&_usb_serial_number).unwrap();
-    // === function ===
-    // channel ID
-    // 0x00000000 is reversed, 0xffffffff is reserved for broadcast
-    // id range available: 0x00001000(4096) ~ 0x0000100a(4106)
-    let mut _fido2_channel_id_used = [false; 4106 - 4096];
-    let _fido2_channel_id_offset = |cid: u32| -> usize { (0x00001000 + cid) as usize };
-    let fido2_channel_create = |cid: u32| -> Option<u32> {
-        if fido2_channel_reversed(cid) {
-            return None;
-        }
-        // try to find a available id
-        for (k, v) in _fido2_channel_id_used.iter().enumerate() {
-            if !v {
-                _fido2_channel_id_used[k] = true;
-                return Some((k + 0x00001000) as u32);
-            }
-        }
-        // id was full
-        return None;
-    };
-    let fido2_channel_delete = |cid: u32| {
-        if !(cid < 0x00001000 || cid > 0x0000100a) {
-            _fido2_channel_id_used[_fido2_channel_id_offset(cid)] = false
-        }
+    let mut resp_data = [0u8; 17];
+    let data_len = command_resp.apply(&mut resp_data).unwrap();
+    FIDO2Transport::FIDO2Transport::send(
+        channel_id,
+        FIDO2Parser::FIDO2PacketCommand::CtapHIDInit,
+        &resp_data[..data_len as usize],
+        push,
+    );
+}
+
+// 96-bit unique device id
+fn device_uid() -> [u8; 12] {
+    let mut uid = [0u8; 12];
+    for (k, v) in uid.iter_mut().enumerate() {
+        *v = unsafe { core::ptr::read_volatile((0x1FFF_F7E8 + k) as *const u8) };
+    }
+    uid
+}
+
+// tasks, from high to low priority:
+// 3: usb_interrupt, moves reports between the endpoints and the other tasks
+// 2: transport, button, keepalive and led, all short, the button task only
+//    runs while a request waits for user presence
+// 1: ctap, runs the authenticator and may take seconds (ECDSA, user presence)
+// the debug console (debug-console feature) reads USART1 at priority 2 and
+// asks the task that owns the data for a dump, its output goes through the log
+// sink, which masks priority 2 while a record is written (log_critical)
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true, dispatchers = [TAMPER, PVD])]
+mod app {
+    use super::*;
+    use fugit::ExtU64;
+    use FIDO2Clock::{FIDO2Clock as _, FIDO2Duration, FIDO2Instant};
+    use FIDO2Tasks::{
+        FIDO2BusyState, FIDO2CtapJob, FIDO2LedPattern, FIDO2LedState, FIDO2TransportMessage,
     };
-    let fido2_channel_exists = |cid: u32| -> bool {
-        if cid < 0x00001000 || cid > 0x0000100a {
-            false
-        } else if _fido2_channel_id_used[_fido2_channel_id_offset(cid)] {
-            true
-        } else {
-            false
+
+    #[monotonic(binds = SysTick, default = true)]
+    type MonoTimer = FIDO2Monotonic::FIDO2SysTick;
+
+    #[shared]
+    struct Shared {
+        usb: FIDO2Usb,
+        busy: Option<FIDO2BusyState>,
+        // ctap and console_dump have the same priority, locking costs nothing
+        authenticator: Authenticator,
+    }
+
+    #[local]
+    struct Local {
+        // a report the transport task had no room for
+        pending: Option<[u8; 64]>,
+        ctaphid: FIDO2Transport::FIDO2Transport,
+        // None while the CTAP task has it
+        buffer: Option<&'static mut GlobalBuffer::GlobalBuffer>,
+        device_id: [u8; 16],
+        #[cfg(feature = "debug-console")]
+        debug_rx: serial::Rx<pac::USART1>,
+        status_led: Board::FIDO2Led<LedPin>,
+        led_state: FIDO2LedState,
+        presence: Presence,
+    }
+
+    // the RTIC monotonic seen through FIDO2Clock
+    struct FIDO2MonotonicClock;
+    impl FIDO2Clock::FIDO2Clock for FIDO2MonotonicClock {
+        fn now(&mut self) -> FIDO2Instant {
+            monotonics::now()
         }
-    };
-    fn fido2_channel_reversed(cid: u32) -> bool {
-        cid == 0x00000000 || cid == 0xffffffff
     }
-    // usb
-    let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.crh);
-    usb_dp.set_low();
-    let hid_usb_port = usb::Peripheral {
-        usb: dp.USB,
-        pin_dm: gpioa.pa11,
-        pin_dp: usb_dp.into_floating_input(&mut gpioa.crh),
-    };
-    let hid_usb_bus = usb::UsbBus::new(hid_usb_port);
-    let mut hid_usb_ctrl =
-        usbd_hid::hid_class::HIDClass::new(&hid_usb_bus, FIDO2HID::FIDO2Report::desc(), 60);
-    let mut hid_usb_dev = UsbDeviceBuilder::new(&hid_usb_bus, UsbVidPid(0x7777, 0x0001))
-        .manufacturer("GitHub @sb-child")
-        .product("unsafe{key} Board v1.0")
-        .serial_number(_usb_serial_number)
-        .build();
-    // global buffer
-    let mut global_buffer = GlobalBuffer::GlobalBuffer::new();
-    // error generator
-    fn _fido2_err_command_not_found() -> impl FIDO2Commands::FIDO2PacketCommandResponse {
-        FIDO2Commands::FIDO2PacketCommandErrorResponse::new(
-            FIDO2Commands::FIDO2ErrorCode::ErrInvalidCmd,
+
+    #[init(local = [
+        usb_bus: Option<UsbBusAllocator<usb::UsbBusType>> = None,
+        usb_serial_number: [u8; 32] = [0; 32],
+        flash: Option<stm32f1xx_hal::flash::Parts> = None,
+        log_sink: Option<LogSink> = None,
+        global_buffer: GlobalBuffer::GlobalBuffer = GlobalBuffer::GlobalBuffer::new(),
+    ])]
+    fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
+        {
+            // alloc init
+            use core::mem::MaybeUninit;
+            const HEAP_SIZE: usize = 1024;
+            static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
+            unsafe { HEAP.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
+        }
+        // hardware init
+        let dp = cx.device;
+        let mut cp = cx.core;
+        cp.DCB.enable_trace();
+        cp.DWT.enable_cycle_counter();
+        let flash = cx.local.flash.insert(dp.FLASH.constrain());
+        // clocks, LED, presence input, debug serial port and usb pins
+        let board = Board::CurrentBoard::init(
+            Board::FIDO2BoardPeripherals {
+                rcc: dp.RCC,
+                afio: dp.AFIO,
+                gpioa: dp.GPIOA,
+                gpiob: dp.GPIOB,
+                gpioc: dp.GPIOC,
+                usart1: dp.USART1,
+                usb: dp.USB,
+                tim3: dp.TIM3,
+            },
+            &mut flash.acr,
+        );
+        let clocks = board.clocks;
+        #[cfg(not(feature = "log-rtt"))]
+        FIDO2Log::set_sink(cx.local.log_sink.insert(board.debug_tx), log_critical);
+        #[cfg(feature = "log-rtt")]
+        FIDO2Log::set_sink(
+            cx.local
+                .log_sink
+                .insert(FIDO2Log::FIDO2RttSink(rtt_target::rtt_init_default!().up.0)),
+            log_critical,
+        );
+        log_info!(
+            "{} {}.{}.{}",
+            <Board::CurrentBoard as Board::FIDO2Board>::NAME,
+            ProjectConsts::MAJOR_VERSION,
+            ProjectConsts::MINOR_VERSION,
+            ProjectConsts::BUILD_VERSION
+        );
+        let mono = FIDO2Monotonic::FIDO2SysTick::new(cp.SYST, clocks.sysclk().raw());
+        // per-device identity
+        let identity = FIDO2DeviceIdentity::FIDO2DeviceIdentity::new(device_uid());
+        *cx.local.usb_serial_number = identity.serial_number::<Crypto>();
+        let usb_serial_number = from_utf8(cx.local.usb_serial_number).unwrap();
+        // usb
+        let hid_usb_bus = cx.local.usb_bus.insert(usb::UsbBus::new(board.usb));
+        let hid_usb_ctrl =
+            usbd_hid::hid_class::HIDClass::new(hid_usb_bus, FIDO2HID::FIDO2Report::desc(), 60);
+        let hid_usb_dev = UsbDeviceBuilder::new(hid_usb_bus, UsbVidPid(0x7777, 0x0001))
+            .manufacturer("GitHub @sb-child")
+            .product(<Board::CurrentBoard as Board::FIDO2Board>::NAME)
+            .serial_number(usb_serial_number)
+            .build();
+        // ctap2
+        // refuse to run with a broken crypto provider
+        if !FIDO2CryptoSelfTest::self_test::<Crypto>() {
+            log_error!("crypto self test failed");
+            FIDO2Log::flush();
+            panic!("crypto self test failed");
+        }
+        let mut storage =
+            FIDO2Flash::FIDO2FlashStorage::new(flash.writer(SectorSize::Sz1K, FlashSize::Sz128K));
+        let noise = FIDO2AdcNoise {
+            adc: Adc::adc1(dp.ADC1, clocks),
+        };
+        let rng =
+            FIDO2Entropy::FIDO2EntropyRng::new(noise, &mut storage, &identity.seed_salt::<Crypto>())
+                .unwrap_or_else(|e| {
+                    log_error!("no RNG: {:?}", e);
+                    FIDO2Log::flush();
+                    panic!("no RNG");
+                });
+        let crypto = FIDO2Crypto::FIDO2SoftwareCrypto(rng);
+        let authenticator = FIDO2Authenticator::FIDO2Authenticator::new(storage, crypto).unwrap();
+        #[cfg(feature = "debug-console")]
+        let debug_rx = {
+            let mut rx = board.debug_rx;
+            rx.listen();
+            rx
+        };
+        // periodic tasks
+        led::spawn(None).unwrap();
+        keepalive::spawn().unwrap();
+        (
+            Shared {
+                usb: FIDO2Usb {
+                    dev: hid_usb_dev,
+                    ctrl: hid_usb_ctrl,
+                    tx: FIDO2PacketQueue::FIDO2PacketQueue::new(),
+                },
+                busy: None,
+                authenticator,
+            },
+            Local {
+                pending: None,
+                ctaphid: FIDO2Transport::FIDO2Transport::new(),
+                buffer: Some(cx.local.global_buffer),
+                device_id: identity.device_id::<Crypto>(),
+                #[cfg(feature = "debug-console")]
+                debug_rx,
+                status_led: board.led,
+                led_state: FIDO2LedState::new(),
+                presence: board.presence,
+            },
+            init::Monotonics(mono),
         )
     }
-    fn _fido2_err_data_length() -> impl FIDO2Commands::FIDO2PacketCommandResponse {
-        FIDO2Commands::FIDO2PacketCommandErrorResponse::new(
-            FIDO2Commands::FIDO2ErrorCode::ErrInvalidLen,
-        )
+
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::wfi();
+        }
     }
-    fn _fido2_err_reversed_channel() -> impl FIDO2Commands::FIDO2PacketCommandResponse {
-        FIDO2Commands::FIDO2PacketCommandErrorResponse::new(
-            FIDO2Commands::FIDO2ErrorCode::ErrInvalidChannel,
-        )
+
+    // a report the transport task has no room for stays in the endpoint, the
+    // host is NAKed until the transport task pends this interrupt again. CTR_RX
+    // stays set until the endpoint is read, so the interrupt is masked meanwhile,
+    // or it would fire again right away and starve the transport task
+    #[task(binds = USB_LP_CAN_RX0, priority = 3, shared = [usb], local = [pending])]
+    fn usb_interrupt(mut cx: usb_interrupt::Context) {
+        let pending = cx.local.pending;
+        cx.shared.usb.lock(|usb| {
+            usb.dev.poll(&mut [&mut usb.ctrl]);
+            // nobody would read the responses after a bus reset
+            if usb.dev.state() != UsbDeviceState::Configured {
+                usb.tx.clear();
+                return;
+            }
+            loop {
+                if pending.is_none() {
+                    let mut buff = [0u8; 64];
+                    if usb.ctrl.pull_raw_output(&mut buff).is_err() {
+                        break;
+                    }
+                    *pending = Some(buff);
+                }
+                if transport::spawn(FIDO2TransportMessage::Packet(pending.unwrap())).is_err() {
+                    usb_ctr_interrupt(false);
+                    break;
+                }
+                *pending = None;
+            }
+            while let Some(packet) = usb.tx.peek() {
+                if let Err(UsbError::WouldBlock) = usb.ctrl.push_raw_input(packet) {
+                    break;
+                }
+                usb.tx.pop();
+            }
+        });
     }
-    // event processing
-    let _fido2_req = |buff: [u8; 64]| {
-        let parser = FIDO2Parser::FIDO2PacketBuilder::new_from_raw_packet(buff);
-        match parser {
-            Ok(_) => {}
-            Err(FIDO2Errors::FIDO2InternalError::CommandNotFoundError) => {
-                global_buffer.apply_response_from(_fido2_err_command_not_found());
+
+    // CTAPHID: reassembles requests, answers the short commands itself and
+    // hands CBOR and provisioning requests to the CTAP task
+    #[task(
+        priority = 2,
+        capacity = 4,
+        shared = [usb, busy],
+        local = [ctaphid, buffer, device_id]
+    )]
+    fn transport(mut cx: transport::Context, message: FIDO2TransportMessage) {
+        let packet = match message {
+            FIDO2TransportMessage::Packet(packet) => {
+                // there is room in the queue again
+                cx.shared.usb.lock(|_| usb_ctr_interrupt(true));
+                packet
             }
-            Err(FIDO2Errors::FIDO2InternalError::DataLengthError) => {
-                global_buffer.apply_response_from(_fido2_err_data_length());
+            FIDO2TransportMessage::Release(buffer) => {
+                buffer.clear_request();
+                buffer.clear_response();
+                *cx.local.buffer = Some(buffer);
+                cx.shared.busy.lock(|busy| *busy = None);
+                return;
             }
-            Err(FIDO2Errors::FIDO2InternalError::ReversedChannelError) => {
-                global_buffer.apply_response_from(_fido2_err_reversed_channel());
+            #[cfg(feature = "debug-console")]
+            FIDO2TransportMessage::Console(command) => {
+                let ctaphid = &*cx.local.ctaphid;
+                let buffer = cx.local.buffer.as_deref();
+                let tx_queued = cx.shared.usb.lock(|usb| usb.tx.len());
+                FIDO2Log::with_sink(|mut tx| match command {
+                    FIDO2DebugConsole::FIDO2ConsoleCommand::Channels => {
+                        FIDO2DebugConsole::dump_channels(&mut tx, ctaphid)
+                    }
+                    FIDO2DebugConsole::FIDO2ConsoleCommand::Buffers => {
+                        FIDO2DebugConsole::dump_buffers(
+                            &mut tx,
+                            buffer,
+                            tx_queued,
+                            ProjectConsts::FIDO2_USB_TX_QUEUE_LENGTH,
+                        )
+                    }
+                    _ => Ok(()),
+                });
+                return;
             }
         };
-        let req = parser.unwrap();
-    };
-    // === loop ===
-    loop {
-        if !hid_usb_dev.poll(&mut [&mut hid_usb_ctrl]) {
-            continue;
+        let ctaphid = cx.local.ctaphid;
+        let event = match cx.local.buffer.as_deref_mut() {
+            Some(buffer) => ctaphid.receive(packet, buffer),
+            None => {
+                let busy_channel_id =
+                    cx.shared.busy.lock(|busy| busy.as_ref().map_or(0, |b| b.channel_id));
+                ctaphid.receive_busy(packet, busy_channel_id)
+            }
+        };
+        // there is room for a report left in the endpoint
+        rtic::pend(Interrupt::USB_LP_CAN_RX0);
+        let event = match event {
+            Some(e) => e,
+            None => return,
+        };
+        if FIDO2Tasks::packet_trace() {
+            log_debug!("{:?}", event);
         }
-        let mut buff = [0u8; 64];
-        let result = hid_usb_ctrl.pull_raw_output(&mut buff);
-        if result.is_ok() {
-            let r = result.unwrap();
-            let parser = FIDO2Parser::FIDO2PacketBuilder::new_from_raw_packet(buff);
-            writeln!(tx, "PC: {:?}", parser).unwrap();
-            let parsed = parser.unwrap();
-            // response init only
-            if parsed.packet_type.is_some()
-                && parsed.packet_type.unwrap() != FIDO2Parser::FIDO2PacketCommand::CtapHIDInit
-            {
-                continue;
+        let usb = &mut cx.shared.usb;
+        match event {
+            FIDO2Transport::FIDO2TransportEvent::Init {
+                channel_id,
+                new_channel_id,
+                nonce,
+            } => {
+                // CTAPHID_INIT on the busy channel aborts the request too
+                cx.shared.busy.lock(|busy| match busy {
+                    Some(b) if b.channel_id == channel_id => b.cancelled = true,
+                    _ => {}
+                });
+                send_init(channel_id, new_channel_id, nonce, |packet: &[u8; 64]| {
+                    usb_push(usb, packet)
+                });
             }
-            let command_req =
-                FIDO2Commands::FIDO2PacketCommandInitRequest::unpack(&parsed.data).unwrap();
-            let command_resp = FIDO2Commands::FIDO2PacketCommandInitResponse::new(
-                command_req.random,
-                [0x00, 0xc0, 0xff, 0xee],
+            FIDO2Transport::FIDO2TransportEvent::Request {
+                channel_id,
+                command,
+                length,
+            } => {
+                // a complete request means the buffer is here
+                let buffer = cx.local.buffer.take().unwrap();
+                if matches!(
+                    command,
+                    FIDO2Parser::FIDO2PacketCommand::CtapHIDCbor
+                        | FIDO2Parser::FIDO2PacketCommand::CtapHIDVendorProvision
+                ) {
+                    cx.shared.busy.lock(|busy| *busy = Some(FIDO2BusyState::new(channel_id)));
+                    // the buffer goes with the job, so there is never more than one
+                    let job = FIDO2CtapJob {
+                        channel_id,
+                        command,
+                        length,
+                        buffer,
+                    };
+                    if let Err(job) = ctap::spawn(job) {
+                        log_warn!("CTAP task not free, {:08x} gets ERR_CHANNEL_BUSY", channel_id);
+                        // the buffer stays here, the channel must not stay busy
+                        job.buffer.clear_request();
+                        *cx.local.buffer = Some(job.buffer);
+                        cx.shared.busy.lock(|busy| *busy = None);
+                        FIDO2Transport::FIDO2Transport::send_error(
+                            channel_id,
+                            FIDO2Commands::FIDO2ErrorCode::ErrChannelBusy,
+                            |packet: &[u8; 64]| usb_push(usb, packet),
+                        );
+                    }
+                    return;
+                }
+                let request = &buffer.request_buffer[..length as usize];
+                let response = &mut buffer.response_buffer;
+                let resp_len = match command {
+                    FIDO2Parser::FIDO2PacketCommand::CtapHIDPing => {
+                        FIDO2Commands::FIDO2PacketCommandPingResponse::new(request).apply(response)
+                    }
+                    FIDO2Parser::FIDO2PacketCommand::CtapHIDWink => {
+                        led::spawn(Some(FIDO2LedPattern::Wink)).ok();
+                        FIDO2Commands::FIDO2PacketCommandWinkResponse::new().apply(response)
+                    }
+                    FIDO2Parser::FIDO2PacketCommand::CtapHIDLock => {
+                        FIDO2Commands::FIDO2PacketCommandLockResponse::new().apply(response)
+                    }
+                    FIDO2Parser::FIDO2PacketCommand::CtapHIDVendorFirmwareInfo => {
+                        FIDO2Commands::FIDO2PacketCommandFirmwareInfoResponse::new(
+                            *cx.local.device_id,
+                            build_time::build_time_local!("%Y%m%d-%H%M%S%z"),
+                        )
+                        .apply(response)
+                    }
+                    _ => None,
+                };
+                let push = |packet: &[u8; 64]| usb_push(usb, packet);
+                match resp_len {
+                    Some(len) => FIDO2Transport::FIDO2Transport::send(
+                        channel_id,
+                        command,
+                        &buffer.response_buffer[..len as usize],
+                        push,
+                    ),
+                    None => FIDO2Transport::FIDO2Transport::send_error(
+                        channel_id,
+                        FIDO2Commands::FIDO2ErrorCode::ErrInvalidCmd,
+                        push,
+                    ),
+                }
+                buffer.clear_request();
+                buffer.clear_response();
+                *cx.local.buffer = Some(buffer);
+            }
+            FIDO2Transport::FIDO2TransportEvent::Cancel { channel_id } => {
+                cx.shared.busy.lock(|busy| match busy {
+                    Some(b) if b.channel_id == channel_id => b.cancelled = true,
+                    _ => {}
+                });
+            }
+            FIDO2Transport::FIDO2TransportEvent::Error { channel_id, code } => {
+                FIDO2Transport::FIDO2Transport::send_error(channel_id, code, |packet: &[u8; 64]| {
+                    usb_push(usb, packet)
+                });
+            }
+        }
+    }
+
+    // user presence through the button and LED tasks, keepalives are sent by
+    // the keepalive task meanwhile
+    struct FIDO2RticPlatform<'a, M: rtic::Mutex<T = Option<FIDO2BusyState>>> {
+        busy: &'a mut M,
+        clock: FIDO2MonotonicClock,
+        up_timeout: FIDO2Duration,
+    }
+    impl<'a, M: rtic::Mutex<T = Option<FIDO2BusyState>>> FIDO2Authenticator::FIDO2Platform
+        for FIDO2RticPlatform<'a, M>
+    {
+        fn user_presence(&mut self) -> Result<(), FIDO2Status::FIDO2StatusCode> {
+            // presses before the request do not count
+            self.busy.lock(|busy| {
+                if let Some(b) = busy {
+                    b.up_needed = true;
+                    b.confirmed = false;
+                }
+            });
+            led::spawn(Some(FIDO2LedPattern::UpNeeded)).ok();
+            // fails while the last poll is still scheduled, that one goes on
+            button::spawn().ok();
+            let busy = &mut *self.busy;
+            let result = FIDO2Tasks::wait_user_presence(
+                &mut self.clock,
+                self.up_timeout,
+                || {
+                    busy.lock(|busy| {
+                        busy.as_ref()
+                            .map_or((true, false), |b| (b.cancelled, b.confirmed))
+                    })
+                },
+                // the other tasks run in the meantime
+                |_| cortex_m::asm::wfi(),
             );
-            let mut resp_data = [0u8; 59];
-            let data_len = command_resp.apply(&mut resp_data).unwrap();
-            let pack = FIDO2Parser::FIDO2PacketBuilder {
-                channel_id: 0xffffffff,
-                is_seq: false,
-                seq_id: 0xff,
-                data_length: data_len,
-                data: resp_data,
-                packet_type: Some(FIDO2Parser::FIDO2PacketCommand::CtapHIDInit),
+            self.busy.lock(|busy| {
+                if let Some(b) = busy {
+                    b.up_needed = false;
+                }
+            });
+            led::spawn(Some(FIDO2LedPattern::Off)).ok();
+            result
+        }
+        fn uptime_ms(&mut self) -> u64 {
+            self.clock.now_ms()
+        }
+    }
+
+    #[task(priority = 1, capacity = 1, shared = [usb, busy, authenticator])]
+    fn ctap(mut cx: ctap::Context, job: FIDO2CtapJob) {
+        let FIDO2CtapJob {
+            channel_id,
+            command,
+            length,
+            buffer,
+        } = job;
+        let request = &buffer.request_buffer[..length as usize];
+        let response = &mut buffer.response_buffer;
+        let busy = &mut cx.shared.busy;
+        let resp_len = cx.shared.authenticator.lock(|authenticator| match command {
+            FIDO2Parser::FIDO2PacketCommand::CtapHIDVendorProvision => {
+                authenticator.provision(request, response)
+            }
+            _ => {
+                let mut platform = FIDO2RticPlatform {
+                    busy,
+                    clock: FIDO2MonotonicClock,
+                    up_timeout: ProjectConsts::FIDO2_USER_PRESENCE_TIMEOUT_MS.millis(),
+                };
+                authenticator.process(&mut platform, request, response)
+            }
+        });
+        cx.shared.busy.lock(|busy| {
+            if let Some(b) = busy {
+                b.done = true;
+            }
+        });
+        log_debug!("{:?} on {:08x}: {} bytes", command, channel_id, resp_len);
+        let usb = &mut cx.shared.usb;
+        FIDO2Transport::FIDO2Transport::send(
+            channel_id,
+            command,
+            &buffer.response_buffer[..resp_len as usize],
+            |packet: &[u8; 64]| usb_push(usb, packet),
+        );
+        // the transport task runs at a higher priority, its queue empties right away
+        let mut message = FIDO2TransportMessage::Release(buffer);
+        while let Err(m) = transport::spawn(message) {
+            message = m;
+        }
+    }
+
+    // line input on the debug serial port, short commands are answered here
+    #[cfg(feature = "debug-console")]
+    #[task(
+        binds = USART1,
+        priority = 2,
+        local = [
+            debug_rx,
+            console_line: FIDO2DebugConsole::FIDO2ConsoleLine =
+                FIDO2DebugConsole::FIDO2ConsoleLine::new(),
+        ]
+    )]
+    fn console(cx: console::Context) {
+        use FIDO2DebugConsole::FIDO2ConsoleCommand;
+        while let Ok(byte) = cx.local.debug_rx.read() {
+            // echo
+            FIDO2Log::with_sink(|tx| tx.write_char(byte as char).ok());
+            let command = match cx.local.console_line.push(byte) {
+                Some(c) => c,
+                None => continue,
+            };
+            let queued = match command {
+                FIDO2ConsoleCommand::Help | FIDO2ConsoleCommand::Unknown => {
+                    FIDO2Log::with_sink(|tx| {
+                        writeln!(tx, "\r\n{}", FIDO2DebugConsole::FIDO2_CONSOLE_HELP).ok()
+                    });
+                    true
+                }
+                FIDO2ConsoleCommand::Trace(enabled) => {
+                    FIDO2Tasks::set_packet_trace(enabled);
+                    FIDO2Log::with_sink(|tx| {
+                        writeln!(tx, "\r\ntrace {}", if enabled { "on" } else { "off" }).ok()
+                    });
+                    true
+                }
+                FIDO2ConsoleCommand::Bootloader => {
+                    FIDO2Log::with_sink(|tx| {
+                        writeln!(tx, "\r\nrebooting into the bootloader").ok()
+                    });
+                    // let the last byte leave the UART
+                    FIDO2Log::flush();
+                    FIDO2Bootloader::reboot_to_bootloader()
+                }
+                FIDO2ConsoleCommand::Channels | FIDO2ConsoleCommand::Buffers => {
+                    FIDO2Log::with_sink(|tx| writeln!(tx).ok());
+                    transport::spawn(FIDO2TransportMessage::Console(command)).is_ok()
+                }
+                // the CTAP task may be busy for a while, the dump waits for it
+                FIDO2ConsoleCommand::Flash | FIDO2ConsoleCommand::Counters => {
+                    FIDO2Log::with_sink(|tx| writeln!(tx).ok());
+                    console_dump::spawn(command).is_ok()
+                }
             };
-            let packed = pack.pack().unwrap();
-            hid_usb_ctrl.push_raw_input(&packed).unwrap();
+            if !queued {
+                FIDO2Log::with_sink(|tx| writeln!(tx, "busy, try again").ok());
+            }
+        }
+    }
+
+    // dumps of the authenticator state, between two CTAP requests. RTIC cannot
+    // leave a software task out, without the debug console nothing spawns it
+    #[task(priority = 1, capacity = 2, shared = [authenticator])]
+    fn console_dump(cx: console_dump::Context, command: FIDO2DebugConsole::FIDO2ConsoleCommand) {
+        #[cfg(feature = "debug-console")]
+        {
+            let mut authenticator = cx.shared.authenticator;
+            authenticator.lock(|authenticator| {
+                FIDO2Log::with_sink(|mut tx| match command {
+                    FIDO2DebugConsole::FIDO2ConsoleCommand::Flash => {
+                        FIDO2DebugConsole::dump_flash(&mut tx, authenticator)
+                    }
+                    FIDO2DebugConsole::FIDO2ConsoleCommand::Counters => {
+                        FIDO2DebugConsole::dump_counters(&mut tx, authenticator)
+                    }
+                    _ => Ok(()),
+                })
+            });
+        }
+        #[cfg(not(feature = "debug-console"))]
+        let _ = (cx, command);
+    }
+
+    // polls the presence input only while a request waits for user presence,
+    // so the core sleeps otherwise (the touch pad calibrates on the first wait)
+    #[task(priority = 2, shared = [busy], local = [presence])]
+    fn button(mut cx: button::Context) {
+        let now = monotonics::now();
+        let event = cx.local.presence.poll(now.duration_since_epoch().to_millis());
+        let confirm = matches!(
+            event.map(|e| e.kind),
+            Some(FIDO2Button::FIDO2ButtonEventKind::Press | FIDO2Button::FIDO2ButtonEventKind::LongPress)
+        );
+        let waiting = cx.shared.busy.lock(|busy| match busy {
+            Some(b) if b.up_needed => {
+                b.confirmed |= confirm;
+                true
+            }
+            _ => false,
+        });
+        if waiting {
+            button::spawn_at(now + ProjectConsts::FIDO2_BUTTON_DEBOUNCE_MS.millis()).ok();
+        }
+    }
+
+    #[task(priority = 2, shared = [usb, busy])]
+    fn keepalive(mut cx: keepalive::Context) {
+        let now = monotonics::now();
+        let status = cx.shared.busy.lock(|busy| {
+            busy.as_ref()
+                .and_then(|b| b.keepalive().map(|code| (b.channel_id, code)))
+        });
+        if let Some((channel_id, code)) = status {
+            let mut data = [0u8; 1];
+            FIDO2Commands::FIDO2PacketCommandKeepAliveResponse::new(code).apply(&mut data);
+            let usb = &mut cx.shared.usb;
+            FIDO2Transport::FIDO2Transport::send(
+                channel_id,
+                FIDO2Parser::FIDO2PacketCommand::CtapHIDKeepalive,
+                &data,
+                |packet: &[u8; 64]| usb_push(usb, packet),
+            );
+        }
+        keepalive::spawn_at(now + ProjectConsts::FIDO2_KEEPALIVE_INTERVAL_MS.millis()).ok();
+    }
+
+    // None is the periodic tick
+    #[task(priority = 2, capacity = 4, local = [status_led, led_state])]
+    fn led(cx: led::Context, pattern: Option<FIDO2LedPattern>) {
+        let now = FIDO2MonotonicClock.now_ms();
+        match pattern {
+            Some(p) => cx.local.led_state.set(p, now),
+            None => {
+                led::spawn_after(ProjectConsts::FIDO2_LED_BLINK_MS.millis(), None).ok();
+            }
+        }
+        if cx.local.led_state.level(now) {
+            cx.local.status_led.on();
+        } else {
+            cx.local.status_led.off();
         }
     }
 }
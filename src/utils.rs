@@ -74,3 +74,41 @@ pub(crate) fn insert_number_string(arr: &mut [u8], val: u8, last: usize) {
         num /= 10;
     }
 }
+
+// fixed capacity byte string, longer input is truncated
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FIDO2Bytes<const N: usize> {
+    pub data: [u8; N],
+    pub len: usize,
+}
+impl<const N: usize> FIDO2Bytes<N> {
+    pub fn new() -> FIDO2Bytes<N> {
+        FIDO2Bytes {
+            data: [0u8; N],
+            len: 0,
+        }
+    }
+    pub fn from_slice(v: &[u8]) -> FIDO2Bytes<N> {
+        let mut r = FIDO2Bytes::new();
+        r.len = core::cmp::min(v.len(), N);
+        r.data[..r.len].copy_from_slice(&v[..r.len]);
+        r
+    }
+    // truncate on a char boundary so the result is still valid UTF-8
+    pub fn from_str(v: &str) -> FIDO2Bytes<N> {
+        let mut end = core::cmp::min(v.len(), N);
+        while !v.is_char_boundary(end) {
+            end -= 1;
+        }
+        FIDO2Bytes::from_slice(&v.as_bytes()[..end])
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_slice()).unwrap_or("")
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}